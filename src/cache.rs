@@ -1,69 +1,959 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::proto::RequestCommand;
+use crate::cache::crdt::{wrong_type, Crdt};
+use crate::cache::eviction::{EvictionKind, EvictionPolicy};
+use crate::cache::multi::Stamp;
+use crate::cache::snapshot::SnapshotEntry;
+use crate::proto::{ErrorCode, RequestCommand, Response};
 
+pub mod cluster;
+pub mod crdt;
+pub mod eviction;
+pub mod gossip;
 pub mod middlewares;
+pub mod multi;
+pub mod raft;
+pub mod replication;
+pub mod snapshot;
+pub mod wal;
 
 pub trait CacheServer {
-    fn on_request(&self, f: &RequestCommand) -> Vec<u8>;
+    fn on_request(&self, f: &RequestCommand) -> Response;
 }
 
+/// Milliseconds since the unix epoch, the unit of every stored deadline.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The deadline `secs` seconds from now, refusing timeouts it cannot hold.
+pub fn deadline_after(secs: u64) -> Result<u64, CacheError> {
+    secs.checked_mul(1000)
+        .and_then(|x| x.checked_add(now_millis()))
+        .ok_or(CacheError::InvalidExpire)
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    /// The write would exceed `--max-memory` and nothing could be evicted.
+    OutOfMemory,
+    /// A timeout too long for its deadline to fit in unix milliseconds.
+    InvalidExpire,
+}
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::OutOfMemory => {
+                write!(f, "command not allowed when used memory > 'max-memory'")
+            }
+            CacheError::InvalidExpire => write!(f, "invalid expire time"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<CacheError> for Response {
+    fn from(value: CacheError) -> Self {
+        match value {
+            CacheError::OutOfMemory => Response::error(ErrorCode::OutOfMemory, value.to_string()),
+            CacheError::InvalidExpire => {
+                Response::error(ErrorCode::InvalidArgument, value.to_string())
+            }
+        }
+    }
+}
+
+/// Key and value bytes held by all shards together, against one limit.
+#[derive(Debug)]
+struct Memory {
+    used: AtomicUsize,
+    /// Limit for `used`, `0` meaning unbounded.
+    max: usize,
+    /// Shard to evict from next when a write finds no room in its own.
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Storage {
+    entries: HashMap<String, Entry>,
+    /// Deadlines ordered by time, so the sweeper only touches keys that are due.
+    expiries: BTreeSet<(u64, String)>,
+    /// Multi-primary mode: the stamp of the last write of each key. Those
+    /// of removed keys are tombstones, kept until every primary saw them.
+    stamps: HashMap<String, Stamp>,
+    /// CRDT values, kept apart from plain values and never evicted.
+    crdts: HashMap<String, Crdt>,
+    /// Unix millisecond of the last removal merged into each OR-Set, whose
+    /// tags of removed adds go once every primary saw it.
+    removals: HashMap<String, u64>,
+    /// Shared by every shard, which accounts for the key plus value bytes
+    /// of its entries in it.
+    memory: Arc<Memory>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+impl Storage {
+    fn new(memory: &Arc<Memory>, policy: Box<dyn EvictionPolicy>) -> Self {
+        Storage {
+            entries: HashMap::new(),
+            expiries: BTreeSet::new(),
+            stamps: HashMap::new(),
+            crdts: HashMap::new(),
+            removals: HashMap::new(),
+            memory: memory.clone(),
+            policy,
+        }
+    }
+
+    /// Evicts from this shard until `size` more bytes for `key` fit under
+    /// the limit.
+    fn reserve(&mut self, key: &str, size: usize) -> Result<(), CacheError> {
+        let max = self.memory.max;
+        if max == 0 {
+            return Ok(());
+        }
+        if size > max {
+            return Err(CacheError::OutOfMemory);
+        }
+        loop {
+            let old = self
+                .entries
+                .get(key)
+                .map_or(0, |x| key.len() + x.value.len());
+            if self.memory.used.load(Ordering::SeqCst) - old + size <= max {
+                return Ok(());
+            }
+            let victim = self.policy.victim().ok_or(CacheError::OutOfMemory)?;
+            self.remove(&victim);
+        }
+    }
+
+    fn insert(
+        &mut self,
+        key: &str,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<Option<Entry>, CacheError> {
+        let size = key.len() + value.len();
+        self.reserve(key, size)?;
+
+        if let Some(deadline) = expires_at {
+            self.expiries.insert((deadline, key.to_owned()));
+        }
+        let old = self
+            .entries
+            .insert(key.to_owned(), Entry { value, expires_at });
+        if let Some(old) = old.as_ref() {
+            self.memory
+                .used
+                .fetch_sub(key.len() + old.value.len(), Ordering::SeqCst);
+            if let Some(deadline) = old.expires_at.filter(|x| Some(*x) != expires_at) {
+                self.expiries.remove(&(deadline, key.to_owned()));
+            }
+        }
+        self.memory.used.fetch_add(size, Ordering::SeqCst);
+        self.policy.on_insert(key, expires_at);
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let old = self.entries.remove(key)?;
+        if let Some(deadline) = old.expires_at {
+            self.expiries.remove(&(deadline, key.to_owned()));
+        }
+        self.memory
+            .used
+            .fetch_sub(key.len() + old.value.len(), Ordering::SeqCst);
+        self.policy.on_remove(key);
+        Some(old)
+    }
+
+    /// Looks up a live entry, dropping it first if its deadline has passed.
+    fn live(&mut self, key: &str, now: u64) -> Option<&mut Entry> {
+        if self.entries.get(key)?.is_expired(now) {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// Whether a write stamped `stamp` orders after the last write of `key`.
+    fn is_newer(&self, key: &str, stamp: &Stamp) -> bool {
+        self.stamps.get(key).is_none_or(|x| stamp > x)
+    }
+
+    fn set_deadline(&mut self, key: &str, expires_at: Option<u64>) -> bool {
+        let now = now_millis();
+        let Some(entry) = self.live(key, now) else {
+            return false;
+        };
+        let old = std::mem::replace(&mut entry.expires_at, expires_at);
+        if let Some(deadline) = old {
+            self.expiries.remove(&(deadline, key.to_owned()));
+        }
+        if let Some(deadline) = expires_at {
+            self.expiries.insert((deadline, key.to_owned()));
+        }
+        self.policy.on_insert(key, expires_at);
+        true
+    }
+
+    fn remove_expired(&mut self, now: u64) -> usize {
+        let mut removed = 0;
+        while let Some((deadline, key)) = self.expiries.first().cloned() {
+            if deadline > now {
+                break;
+            }
+            self.remove(&key);
+            removed += 1;
+        }
+        removed
+    }
+}
+
+/// The keyspace split into independently locked shards, so requests for
+/// different keys rarely contend.
 #[derive(Debug, Clone)]
 pub struct Cache {
-    storage: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    shards: Arc<[Mutex<Storage>]>,
+    memory: Arc<Memory>,
+    hasher: RandomState,
 }
 
 impl CacheServer for &Cache {
-    fn on_request(&self, c: &RequestCommand) -> Vec<u8> {
+    fn on_request(&self, c: &RequestCommand) -> Response {
         match c {
-            RequestCommand::Get(key) => self.get(key).unwrap(),
-            RequestCommand::Set(key, val) => self.set(key, val.clone()).unwrap(),
-            RequestCommand::Delete(key) => self.delete(key).unwrap(),
-            RequestCommand::Keys(take, skip) => self.keys(take.clone(), skip.clone()).unwrap(),
-            _ => Vec::new(),
+            RequestCommand::Get(key) => match self.get(key) {
+                Some(x) => Response::Value(x),
+                None => Response::Nil,
+            },
+            RequestCommand::Set(key, val) => match self.set(key, val.clone()) {
+                Ok(_) => Response::Ok,
+                Err(err) => err.into(),
+            },
+            RequestCommand::SetEx(key, val, secs) => match self.set_ex(key, val.clone(), *secs) {
+                Ok(_) => Response::Ok,
+                Err(err) => err.into(),
+            },
+            RequestCommand::Delete(key) => Response::Integer(self.delete(key).is_some() as i64),
+            RequestCommand::Keys(take, skip) => Response::Array(
+                self.keys(*take, *skip)
+                    .into_iter()
+                    .map(|x| Response::Value(x.into_bytes()))
+                    .collect(),
+            ),
+            RequestCommand::KeysAfter(prefix, after, limit) => Response::Array(
+                self.keys_after(prefix, after.as_deref(), *limit)
+                    .into_iter()
+                    .map(|x| Response::Value(x.into_bytes()))
+                    .collect(),
+            ),
+            RequestCommand::SetExAt(key, val, deadline) => {
+                match self.set_ex_at(key, val.clone(), *deadline) {
+                    Ok(_) => Response::Ok,
+                    Err(err) => err.into(),
+                }
+            }
+            RequestCommand::Expire(key, secs) => match self.expire(key, *secs) {
+                Ok(x) => Response::Integer(x as i64),
+                Err(err) => err.into(),
+            },
+            RequestCommand::ExpireAt(key, deadline) => {
+                Response::Integer(self.expire_at(key, *deadline) as i64)
+            }
+            RequestCommand::Ttl(key) => Response::Integer(self.ttl(key)),
+            RequestCommand::Persist(key) => Response::Integer(self.persist(key) as i64),
+            RequestCommand::Info => Response::Value(self.info().into_bytes()),
+            RequestCommand::Flush => Response::Integer(self.flush() as i64),
+            RequestCommand::Load(entries) => {
+                self.load(entries.clone());
+                Response::Ok
+            }
+            RequestCommand::Stamped(stamp, x) => match &**x {
+                RequestCommand::Set(key, val) => {
+                    match self.set_stamped(key, val.clone(), None, stamp) {
+                        Ok(_) => Response::Ok,
+                        Err(err) => err.into(),
+                    }
+                }
+                RequestCommand::SetEx(key, val, secs) => {
+                    let stored = deadline_after(*secs)
+                        .and_then(|x| self.set_stamped(key, val.clone(), Some(x), stamp));
+                    match stored {
+                        Ok(_) => Response::Ok,
+                        Err(err) => err.into(),
+                    }
+                }
+                RequestCommand::SetExAt(key, val, deadline) => {
+                    match self.set_stamped(key, val.clone(), Some(*deadline), stamp) {
+                        Ok(_) => Response::Ok,
+                        Err(err) => err.into(),
+                    }
+                }
+                RequestCommand::Delete(key) => {
+                    Response::Integer(self.delete_stamped(key, stamp) as i64)
+                }
+                RequestCommand::Flush => Response::Integer(self.flush_stamped(stamp) as i64),
+                // A deadline only applies to the value it was set with, or
+                // an older one.
+                RequestCommand::Expire(key, _)
+                | RequestCommand::ExpireAt(key, _)
+                | RequestCommand::Persist(key)
+                    if self.stamp(key).is_some_and(|x| &x > stamp) =>
+                {
+                    Response::Integer(0)
+                }
+                RequestCommand::Merge(key, x) => {
+                    self.merge_crdt(key, x, Some(stamp));
+                    Response::Ok
+                }
+                x => self.on_request(x),
+            },
+            RequestCommand::Merge(key, x) => {
+                self.merge_crdt(key, x, None);
+                Response::Ok
+            }
+            RequestCommand::DeleteCrdt(key) => Response::Integer(self.delete_crdt(key) as i64),
+            RequestCommand::CounterGet(key) => self.read_crdt(key, |x| match x {
+                None => Response::Integer(0),
+                Some(Crdt::Counter(x)) => Response::Integer(x.value()),
+                Some(x) => wrong_type(x),
+            }),
+            RequestCommand::SetMembers(key) => self.read_crdt(key, |x| match x {
+                None => Response::Array(Vec::new()),
+                Some(Crdt::Set(x)) => {
+                    Response::Array(x.members().map(|x| Response::Value(x.clone())).collect())
+                }
+                Some(x) => wrong_type(x),
+            }),
+            RequestCommand::RegisterGet(key) => self.read_crdt(key, |x| match x {
+                None => Response::Nil,
+                Some(Crdt::Register(x)) => Response::Value(x.value.clone()),
+                Some(x) => wrong_type(x),
+            }),
+            x => Response::error(ErrorCode::Unsupported, format!("unsupported command {}", x)),
         }
     }
 }
 
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cache {
     pub fn new() -> Self {
+        Self::with_shards(1, 0, EvictionKind::None)
+    }
+
+    /// A cache of `shards` shards holding at most `max_memory` key and value
+    /// bytes (`0` for no limit), making room for new writes with `eviction`.
+    ///
+    /// The limit holds for all shards together. Every shard evicts by its
+    /// own policy, and a write that finds nothing left to evict in its
+    /// shard evicts from the others.
+    pub fn with_shards(shards: usize, max_memory: usize, eviction: EvictionKind) -> Self {
+        let memory = Arc::new(Memory {
+            used: AtomicUsize::new(0),
+            max: max_memory,
+            next: AtomicUsize::new(0),
+        });
         Self {
-            storage: Arc::new(Mutex::new(HashMap::new())),
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(Storage::new(&memory, eviction.build())))
+                .collect(),
+            memory,
+            hasher: RandomState::new(),
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
-        match self.storage.lock().unwrap().get(key) {
-            None => Some(Vec::new()),
-            Some(x) => Some(x.clone()),
-        }
+    fn index(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Storage> {
+        &self.shards[self.index(key)]
     }
 
-    pub fn set(&self, key: &str, val: Vec<u8>) -> Option<Vec<u8>> {
-        match self.storage.lock().unwrap().insert(key.to_owned(), val) {
-            None => Some(Vec::new()),
-            Some(x) => Some(x.clone()),
+    /// Locks the shard of `key` once `size` more bytes for it fit under the
+    /// limit, evicting from the other shards when its own has nothing left
+    /// to evict. Shards are never locked together.
+    fn room_for(&self, key: &str, size: usize) -> Result<MutexGuard<'_, Storage>, CacheError> {
+        loop {
+            let mut storage = self.shard(key).lock().unwrap();
+            match storage.reserve(key, size) {
+                Ok(_) => return Ok(storage),
+                Err(err) => {
+                    drop(storage);
+                    if size > self.memory.max || !self.evict_elsewhere(key) {
+                        return Err(err);
+                    }
+                }
+            }
         }
     }
 
+    /// Evicts a key from a shard other than that of `key`, taking turns
+    /// between them; returns whether any had one to evict.
+    fn evict_elsewhere(&self, key: &str) -> bool {
+        let own = self.index(key);
+        let n = self.shards.len();
+        let start = self.memory.next.fetch_add(1, Ordering::Relaxed);
+        (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|i| *i != own)
+            .any(|i| {
+                let mut storage = self.shards[i].lock().unwrap();
+                match storage.policy.victim() {
+                    Some(x) => {
+                        storage.remove(&x);
+                        true
+                    }
+                    None => false,
+                }
+            })
+    }
+
+    /// Key and value bytes currently held.
+    pub fn used_memory(&self) -> usize {
+        self.memory.used.load(Ordering::SeqCst)
+    }
+
+    /// The value of `key`, or `None` if it is missing or expired.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut storage = self.shard(key).lock().unwrap();
+        let value = storage.live(key, now_millis())?.value.clone();
+        storage.policy.on_access(key);
+        Some(value)
+    }
+
+    /// Stores `val` without a deadline and returns the previous value.
+    pub fn set(&self, key: &str, val: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        let size = key.len() + val.len();
+        let old = self.room_for(key, size)?.insert(key, val, None)?;
+        Ok(old.map(|x| x.value))
+    }
+
+    /// Stores `val` expiring after `secs` seconds and returns the previous value.
+    pub fn set_ex(
+        &self,
+        key: &str,
+        val: Vec<u8>,
+        secs: u64,
+    ) -> Result<Option<Vec<u8>>, CacheError> {
+        self.set_ex_at(key, val, deadline_after(secs)?)
+    }
+
+    /// Stores `val` expiring at unix millisecond `deadline` and returns the
+    /// previous value.
+    pub fn set_ex_at(
+        &self,
+        key: &str,
+        val: Vec<u8>,
+        deadline: u64,
+    ) -> Result<Option<Vec<u8>>, CacheError> {
+        let size = key.len() + val.len();
+        let old = self.room_for(key, size)?.insert(key, val, Some(deadline))?;
+        Ok(old.map(|x| x.value))
+    }
+
+    /// Removes `key` and returns its value, if it had one.
     pub fn delete(&self, key: &str) -> Option<Vec<u8>> {
-        match self.storage.lock().unwrap().remove(key) {
-            None => Some(Vec::new()),
-            Some(x) => Some(x.clone()),
+        self.shard(key).lock().unwrap().remove(key).map(|x| x.value)
+    }
+
+    /// Up to `take` live keys after the first `skip`, shard by shard, so
+    /// paging is only stable while no keys change.
+    pub fn keys(&self, take: usize, skip: usize) -> Vec<String> {
+        let now = now_millis();
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .entries
+                    .iter()
+                    .filter(|(_, x)| !x.is_expired(now))
+                    .map(|(k, _)| k.clone())
+                    .collect::<Vec<_>>()
+            })
+            .skip(skip)
+            .take(take)
+            .collect()
+    }
+
+    /// Up to `limit` live keys starting with `prefix` that sort after
+    /// `after`, in order. Paging with the last key of a page as `after` is
+    /// stable: a key that stays is listed once whatever else changes.
+    pub fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> Vec<String> {
+        let now = now_millis();
+        // The largest of the smallest keys seen so far is on top.
+        let mut page: BinaryHeap<String> = BinaryHeap::new();
+        for shard in self.shards.iter() {
+            let storage = shard.lock().unwrap();
+            for (key, x) in &storage.entries {
+                if !key.starts_with(prefix)
+                    || after.is_some_and(|after| key.as_str() <= after)
+                    || x.is_expired(now)
+                {
+                    continue;
+                }
+                if page.len() < limit {
+                    page.push(key.clone());
+                } else if page.peek().is_some_and(|top| key < top) {
+                    page.pop();
+                    page.push(key.clone());
+                }
+            }
+        }
+        page.into_sorted_vec()
+    }
+
+    /// Sets a relative timeout in seconds; returns whether the key exists.
+    pub fn expire(&self, key: &str, secs: u64) -> Result<bool, CacheError> {
+        Ok(self.expire_at(key, deadline_after(secs)?))
+    }
+
+    /// Sets an absolute deadline in unix milliseconds; returns whether the key exists.
+    pub fn expire_at(&self, key: &str, deadline: u64) -> bool {
+        let mut storage = self.shard(key).lock().unwrap();
+        if deadline <= now_millis() {
+            return storage.remove(key).is_some();
         }
+        storage.set_deadline(key, Some(deadline))
     }
 
-    pub fn keys(&self, take: usize, skip: usize) -> Option<Vec<u8>> {
-        let res: Vec<String> = self
-            .storage
+    /// Remaining time to live in seconds, `-1` without a deadline and `-2` for a missing key.
+    pub fn ttl(&self, key: &str) -> i64 {
+        let now = now_millis();
+        let deadline = self
+            .shard(key)
             .lock()
             .unwrap()
-            .keys()
-            .map(|x| x.clone())
-            .take(take)
-            .skip(skip)
-            .collect();
-        Some(res.join("\r\n").into_bytes())
+            .live(key, now)
+            .map(|x| x.expires_at);
+        match deadline {
+            None => -2,
+            Some(None) => -1,
+            Some(Some(deadline)) => (deadline - now).div_ceil(1000) as i64,
+        }
+    }
+
+    /// Removes the deadline of a key; returns whether there was one.
+    pub fn persist(&self, key: &str) -> bool {
+        let mut storage = self.shard(key).lock().unwrap();
+        let had_deadline = storage
+            .live(key, now_millis())
+            .is_some_and(|x| x.expires_at.is_some());
+        if had_deadline {
+            storage.set_deadline(key, None);
+        }
+        had_deadline
+    }
+
+    /// Memory and keyspace statistics in the `INFO` format.
+    pub fn info(&self) -> String {
+        let (mut keys, mut expires, mut crdts) = (0, 0, 0);
+        let mut policy = "";
+        for shard in self.shards.iter() {
+            let storage = shard.lock().unwrap();
+            keys += storage.entries.len();
+            expires += storage.expiries.len();
+            crdts += storage.crdts.len();
+            policy = storage.policy.name();
+        }
+        format!(
+            "# Memory\r\nused_memory:{}\r\nmax_memory:{}\r\npolicy:{}\r\n\r\n# Keyspace\r\nshards:{}\r\nkeys:{}\r\nexpires:{}\r\ncrdts:{}\r\n",
+            self.used_memory(),
+            self.memory.max,
+            policy,
+            self.shards.len(),
+            keys,
+            expires,
+            crdts
+        )
+    }
+
+    /// Copies every live key, one shard at a time.
+    pub fn dump(&self) -> Vec<SnapshotEntry> {
+        let now = now_millis();
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .entries
+                    .iter()
+                    .filter(|(_, x)| !x.is_expired(now))
+                    .map(|(k, x)| SnapshotEntry {
+                        key: k.clone(),
+                        value: x.value.clone(),
+                        expires_at: x.expires_at,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Live keys accepted by `f`, one shard at a time.
+    pub fn keys_where(&self, f: impl Fn(&str) -> bool) -> Vec<String> {
+        let now = now_millis();
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .entries
+                    .iter()
+                    .filter(|(k, x)| !x.is_expired(now) && f(k))
+                    .map(|(k, _)| k.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Copies of those of `keys` that hold a live value.
+    pub fn dump_keys(&self, keys: &[String]) -> Vec<SnapshotEntry> {
+        let now = now_millis();
+        keys.iter()
+            .filter_map(|key| {
+                let mut storage = self.shard(key).lock().unwrap();
+                let x = storage.live(key, now)?;
+                Some(SnapshotEntry {
+                    key: key.clone(),
+                    value: x.value.clone(),
+                    expires_at: x.expires_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether `key` holds a live value or a CRDT value.
+    pub fn contains(&self, key: &str) -> bool {
+        let mut storage = self.shard(key).lock().unwrap();
+        storage.crdts.contains_key(key) || storage.live(key, now_millis()).is_some()
+    }
+
+    /// Stores `entries` as they are, skipping those that expired meanwhile.
+    pub fn load(&self, entries: Vec<SnapshotEntry>) {
+        let now = now_millis();
+        for x in entries
+            .into_iter()
+            .filter(|x| x.expires_at.is_none_or(|x| x > now))
+        {
+            let size = x.key.len() + x.value.len();
+            if let Ok(mut storage) = self.room_for(&x.key, size) {
+                let _ = storage.insert(&x.key, x.value, x.expires_at);
+            }
+        }
+    }
+
+    /// Removes every key, CRDT values included, and returns how many there
+    /// were.
+    pub fn flush(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut storage = shard.lock().unwrap();
+                let keys = storage.entries.keys().cloned().collect::<Vec<_>>();
+                for key in &keys {
+                    storage.remove(key);
+                }
+                storage.stamps.clear();
+                storage.removals.clear();
+                let crdts = storage.crdts.drain().count();
+                keys.len() + crdts
+            })
+            .sum()
+    }
+
+    /// Calls `f` with the CRDT value of `key` while no merge can change it.
+    pub fn read_crdt<R>(&self, key: &str, f: impl FnOnce(Option<&Crdt>) -> R) -> R {
+        f(self.shard(key).lock().unwrap().crdts.get(key))
+    }
+
+    /// Joins `x` into the CRDT value of `key`, written at `stamp` in
+    /// multi-primary mode.
+    pub fn merge_crdt(&self, key: &str, x: &Crdt, stamp: Option<&Stamp>) {
+        let mut storage = self.shard(key).lock().unwrap();
+        if matches!(x, Crdt::Set(x) if x.removes()) {
+            // Removals copied without the stamp they were written at were
+            // written before now.
+            let time = stamp
+                .map(|x| x.time)
+                .filter(|x| *x > 0)
+                .unwrap_or_else(now_millis);
+            let at = storage.removals.entry(key.to_owned()).or_default();
+            *at = (*at).max(time);
+        }
+        match storage.crdts.get_mut(key) {
+            Some(value) => value.merge(x),
+            None => {
+                storage.crdts.insert(key.to_owned(), x.clone());
+            }
+        }
+    }
+
+    /// Keys of the CRDT values for which `f` holds.
+    pub fn crdt_keys_where(&self, f: impl Fn(&str) -> bool) -> Vec<String> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .crdts
+                    .keys()
+                    .filter(|x| f(x))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Copies of the CRDT values of those of `keys` that hold one.
+    pub fn dump_crdts(&self, keys: &[String]) -> Vec<(String, Crdt)> {
+        keys.iter()
+            .filter_map(|key| {
+                let storage = self.shard(key).lock().unwrap();
+                Some((key.clone(), storage.crdts.get(key)?.clone()))
+            })
+            .collect()
+    }
+
+    /// Removes the CRDT value of `key`; returns whether there was one.
+    pub fn delete_crdt(&self, key: &str) -> bool {
+        let mut storage = self.shard(key).lock().unwrap();
+        storage.removals.remove(key);
+        storage.crdts.remove(key).is_some()
+    }
+
+    /// Copies every CRDT value, one shard at a time.
+    pub fn crdts(&self) -> Vec<(String, Crdt)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .crdts
+                    .iter()
+                    .map(|(k, x)| (k.clone(), x.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Merges CRDT values copied with [`Cache::crdts`].
+    pub fn load_crdts(&self, crdts: Vec<(String, Crdt)>) {
+        for (key, x) in crdts {
+            self.merge_crdt(&key, &x, None);
+        }
+    }
+
+    /// Stores `val` unless a later write of `key` was applied already;
+    /// returns whether it was stored.
+    pub fn set_stamped(
+        &self,
+        key: &str,
+        val: Vec<u8>,
+        expires_at: Option<u64>,
+        stamp: &Stamp,
+    ) -> Result<bool, CacheError> {
+        let mut storage = self.room_for(key, key.len() + val.len())?;
+        if !storage.is_newer(key, stamp) {
+            return Ok(false);
+        }
+        storage.insert(key, val, expires_at)?;
+        storage.stamps.insert(key.to_owned(), stamp.clone());
+        Ok(true)
+    }
+
+    /// Removes `key` unless a later write of it was applied already, and
+    /// leaves a tombstone so an older write arriving after cannot bring it
+    /// back. Returns whether a value was removed.
+    pub fn delete_stamped(&self, key: &str, stamp: &Stamp) -> bool {
+        let mut storage = self.shard(key).lock().unwrap();
+        if !storage.is_newer(key, stamp) {
+            return false;
+        }
+        storage.stamps.insert(key.to_owned(), stamp.clone());
+        storage.remove(key).is_some()
+    }
+
+    /// Removes every key last written before `stamp`, leaving tombstones,
+    /// and returns how many there were. CRDT values stay, as there is no
+    /// removing them that converges.
+    pub fn flush_stamped(&self, stamp: &Stamp) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut storage = shard.lock().unwrap();
+                let keys = storage
+                    .entries
+                    .keys()
+                    .filter(|x| storage.is_newer(x, stamp))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for key in &keys {
+                    storage.stamps.insert(key.clone(), stamp.clone());
+                    storage.remove(key);
+                }
+                keys.len()
+            })
+            .sum()
+    }
+
+    /// The stamp of the last write of `key`, until every primary saw it.
+    pub fn stamp(&self, key: &str) -> Option<Stamp> {
+        self.shard(key).lock().unwrap().stamps.get(key).cloned()
+    }
+
+    /// Copies every stamp, tombstones included, one shard at a time.
+    pub fn stamps(&self) -> Vec<(String, Stamp)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .stamps
+                    .iter()
+                    .map(|(k, x)| (k.clone(), x.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Restores stamps copied with [`Cache::stamps`].
+    pub fn load_stamps(&self, stamps: Vec<(String, Stamp)>) {
+        for (key, stamp) in stamps {
+            self.shard(&key).lock().unwrap().stamps.insert(key, stamp);
+        }
+    }
+
+    /// Gives `stamp` to every live key without one and returns how many
+    /// there were.
+    pub fn adopt(&self, stamp: &Stamp) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let storage = &mut *shard.lock().unwrap();
+                let keys = storage
+                    .entries
+                    .keys()
+                    .filter(|x| !storage.stamps.contains_key(*x))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for key in &keys {
+                    storage.stamps.insert(key.clone(), stamp.clone());
+                }
+                keys.len()
+            })
+            .sum()
+    }
+
+    /// Stamps of keys that were removed.
+    pub fn tombstones(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let storage = shard.lock().unwrap();
+                storage
+                    .stamps
+                    .keys()
+                    .filter(|x| !storage.entries.contains_key(*x))
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Drops every stamp from before unix millisecond `time`, as no write
+    /// older than that can still arrive, and returns how many tombstones
+    /// went with them.
+    pub fn forget_stamps(&self, time: u64) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let storage = &mut *shard.lock().unwrap();
+                let mut tombstones = 0;
+                storage.stamps.retain(|k, x| {
+                    let keep = x.time >= time;
+                    tombstones += (!keep && !storage.entries.contains_key(k)) as usize;
+                    keep
+                });
+                tombstones
+            })
+            .sum()
+    }
+
+    /// Forgets the tags of removed adds of every OR-Set last removed from
+    /// before unix millisecond `time`, as no copy holding them can still
+    /// arrive, and returns how many tags went.
+    pub fn forget_removed(&self, time: u64) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                let storage = &mut *shard.lock().unwrap();
+                let mut forgotten = 0;
+                storage.removals.retain(|k, at| {
+                    if *at >= time {
+                        return true;
+                    }
+                    if let Some(Crdt::Set(x)) = storage.crdts.get_mut(k) {
+                        forgotten += x.forget_removed();
+                    }
+                    false
+                });
+                forgotten
+            })
+            .sum()
+    }
+
+    /// Removes every key whose deadline has passed and returns how many were dropped.
+    pub fn remove_expired(&self) -> usize {
+        let now = now_millis();
+        self.shards
+            .iter()
+            .map(|x| x.lock().unwrap().remove_expired(now))
+            .sum()
+    }
+
+    /// Spawns the active expiry sweep, so keys that are never read again are
+    /// still reclaimed.
+    pub fn start_expiry_sweep(&self, interval: Duration) {
+        let cache = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            cache.remove_expired();
+        });
     }
 }
@@ -0,0 +1,829 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use crate::cache::gossip::{Member, MemberState};
+use crate::cache::middlewares::{is_write, Middleware, MiddlewareNext, WriteLog};
+use crate::cache::wal::manifest::Segment;
+use crate::cache::wal::WalError;
+use crate::cache::{wal, Cache, CacheServer};
+use crate::client::Client;
+use crate::proto::{ErrorCode, RequestCommand, Response};
+
+/// Hash slots the keyspace is divided into.
+pub const SLOTS: u16 = 16384;
+
+/// Slot assignments and migrations in the WAL directory, in the WAL record
+/// format. Records are `Migrate`, `Import`, `SetSlots`, `Stale` and
+/// `Synced` commands.
+const LOG: &str = "cluster.log";
+
+/// Keys moved at a time while requests for them wait.
+const BATCH: usize = 128;
+
+/// CRC16/XMODEM, as used for Redis cluster slots.
+fn crc16(buf: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for x in buf {
+        crc ^= (*x as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+/// Slot of `key`. Only the part between the first `{` and the next `}` is
+/// hashed if it is not empty, so related keys can be kept on one node.
+pub fn slot(key: &str) -> u16 {
+    let tag = key
+        .split_once('{')
+        .and_then(|(_, x)| x.split_once('}'))
+        .map(|(x, _)| x)
+        .filter(|x| !x.is_empty());
+    crc16(tag.unwrap_or(key).as_bytes()) % SLOTS
+}
+
+/// Which node owns which slots, as inclusive ranges.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SlotMap {
+    pub ranges: Vec<(u16, u16, String)>,
+}
+
+impl SlotMap {
+    /// Splits the slots evenly between `nodes`, in order.
+    pub fn even(nodes: &[String]) -> Self {
+        let n = nodes.len() as u32;
+        let ranges = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let start = i as u32 * SLOTS as u32 / n;
+                let end = (i as u32 + 1) * SLOTS as u32 / n - 1;
+                (start as u16, end as u16, x.clone())
+            })
+            .collect();
+        SlotMap { ranges }
+    }
+
+    /// Gives the inclusive range `start..=end` to `owner`.
+    pub fn assign(&mut self, start: u16, end: u16, owner: &str) {
+        let mut ranges = Vec::new();
+        for (s, e, x) in self.ranges.drain(..) {
+            if s < start {
+                ranges.push((s, e.min(start - 1), x.clone()));
+            }
+            if e > end {
+                ranges.push((s.max(end + 1), e, x));
+            }
+        }
+        ranges.push((start, end, owner.to_owned()));
+        ranges.sort_by_key(|x| x.0);
+        for x in ranges {
+            match self.ranges.last_mut() {
+                Some(last) if last.2 == x.2 && last.1 + 1 == x.0 => last.1 = x.1,
+                _ => self.ranges.push(x),
+            }
+        }
+    }
+
+    pub fn owner(&self, slot: u16) -> Option<&str> {
+        self.ranges
+            .iter()
+            .find(|(start, end, _)| (*start..=*end).contains(&slot))
+            .map(|(_, _, x)| x.as_str())
+    }
+
+    /// The map as a `Slots` response: `[start, end, address]` per range.
+    pub fn to_response(&self) -> Response {
+        Response::Array(
+            self.ranges
+                .iter()
+                .map(|(start, end, addr)| {
+                    Response::Array(vec![
+                        Response::Integer(*start as i64),
+                        Response::Integer(*end as i64),
+                        Response::Value(addr.clone().into_bytes()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// Reads a map back from a `Slots` response.
+    pub fn from_response(res: &Response) -> Option<Self> {
+        let Response::Array(items) = res else {
+            return None;
+        };
+        let ranges = items
+            .iter()
+            .map(|x| match x {
+                Response::Array(x) => match x.as_slice() {
+                    [Response::Integer(start), Response::Integer(end), Response::Value(addr)] => {
+                        Some((
+                            *start as u16,
+                            *end as u16,
+                            String::from_utf8_lossy(addr).to_string(),
+                        ))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        Some(SlotMap { ranges })
+    }
+}
+
+/// A slot range whose keys are moving, with the node on the other end.
+type Move = (u16, u16, String);
+
+fn overlaps(x: &Move, start: u16, end: u16) -> bool {
+    x.0 <= end && start <= x.1
+}
+
+fn find(moves: &[Move], slot: u16) -> Option<&str> {
+    moves
+        .iter()
+        .find(|x| (x.0..=x.1).contains(&slot))
+        .map(|x| x.2.as_str())
+}
+
+/// Slot ownership as rebuilt from the cluster log.
+#[derive(Debug, Default)]
+struct Slots {
+    map: SlotMap,
+    /// Ranges this node owns whose keys are being moved away. Keys still
+    /// here are served here, the others on the receiving node.
+    migrating: Vec<Move>,
+    /// Ranges whose keys are arriving from their owner. Only requests the
+    /// owner redirected with `ASK` are served here.
+    importing: Vec<Move>,
+    /// Keys of migrating ranges written here since they may have been
+    /// copied, whatever became of them: the receiver's copy may be out of
+    /// date, or be all that is left of a key deleted or expired here. They
+    /// are served here until the receiver's copy is replaced or dropped.
+    stale: HashSet<String>,
+}
+
+impl Slots {
+    fn apply(&mut self, f: &RequestCommand) {
+        match f {
+            RequestCommand::Migrate(start, end, to) => {
+                self.clear(*start, *end);
+                self.migrating.push((*start, *end, to.clone()));
+            }
+            RequestCommand::Import(start, end, from) => {
+                self.clear(*start, *end);
+                self.importing.push((*start, *end, from.clone()));
+            }
+            RequestCommand::SetSlots(start, end, owner) => {
+                self.clear(*start, *end);
+                self.map.assign(*start, *end, owner);
+            }
+            RequestCommand::Stale(key) => {
+                self.stale.insert(key.clone());
+            }
+            RequestCommand::Synced(keys) => {
+                for x in keys {
+                    self.stale.remove(x);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clear(&mut self, start: u16, end: u16) {
+        self.migrating.retain(|x| !overlaps(x, start, end));
+        self.importing.retain(|x| !overlaps(x, start, end));
+        self.stale.retain(|x| !(start..=end).contains(&slot(x)));
+    }
+
+    fn owns(&self, start: u16, end: u16, me: &str) -> bool {
+        (start..=end).all(|x| self.map.owner(x) == Some(me))
+    }
+}
+
+/// The cluster log file and the sequence number of its last record.
+struct Journal {
+    file: File,
+    seq: u64,
+}
+
+struct Shared {
+    slots: RwLock<Slots>,
+    /// Held shared by requests for keys of migrating slots and exclusively
+    /// while the keys of a batch that arrived are dropped, so none is dropped
+    /// along with a write the receiver missed.
+    moving: RwLock<()>,
+    log: Mutex<Journal>,
+    /// Stop flags of the migrations running on this node, by slot range.
+    running: Mutex<HashMap<(u16, u16), Arc<AtomicBool>>>,
+    /// Address of this node as it appears in the map.
+    me: String,
+    cache: Cache,
+    wal: WriteLog,
+}
+
+impl Shared {
+    /// Logs `f` to the cluster log, forces it to disk and applies it.
+    fn record(&self, f: RequestCommand) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        (&log.file).write_all(&wal::encode(log.seq + 1, &f))?;
+        log.file.sync_data()?;
+        log.seq += 1;
+        self.slots.write().unwrap().apply(&f);
+        Ok(())
+    }
+
+    /// Logs and applies a write to this node's keys, bypassing the cluster.
+    fn write(&self, f: &RequestCommand) -> Response {
+        let mut none = std::iter::empty();
+        (&self.wal).on_request(
+            f,
+            MiddlewareNext::new(&mut none, Box::new(|x| (&self.cache).on_request(x))),
+        )
+    }
+
+    /// Starts moving the keys of `start..=end` to `to`, unless that is
+    /// already under way.
+    fn start(self: &Arc<Self>, start: u16, end: u16, to: String) {
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let mut running = self.running.lock().unwrap();
+            if running.contains_key(&(start, end)) {
+                return;
+            }
+            running.insert((start, end), stop.clone());
+        }
+        let shared = self.clone();
+        thread::spawn(move || {
+            match shared.transfer(start, end, &to, &stop) {
+                Ok(true) => {}
+                Ok(false) => println!("[Cluster] Stopped moving slots {}-{} to {}", start, end, to),
+                Err(err) => eprintln!(
+                    "[Cluster] Moving slots {}-{} to {} failed, resume it with another MIGRATE: {}",
+                    start, end, to, err
+                ),
+            }
+            shared.running.lock().unwrap().remove(&(start, end));
+        });
+    }
+
+    /// Stops the migrations of ranges overlapping `start..=end` after their
+    /// current batch.
+    fn stop(&self, start: u16, end: u16) {
+        for (range, stop) in self.running.lock().unwrap().iter() {
+            if range.0 <= end && start <= range.1 {
+                stop.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Records that the receiver holds the keys of `keys` as they are here,
+    /// before they are dropped here, so a crash in between does not leave
+    /// them stale and have the receiver's copies dropped too.
+    fn synced(&self, keys: &[String]) -> Result<(), String> {
+        let stale: Vec<String> = {
+            let slots = self.slots.read().unwrap();
+            keys.iter()
+                .filter(|x| slots.stale.contains(*x))
+                .cloned()
+                .collect()
+        };
+        if stale.is_empty() {
+            return Ok(());
+        }
+        self.record(RequestCommand::Synced(stale))
+            .map_err(|x| x.to_string())
+    }
+
+    /// Moves the keys of `start..=end` to `to` batch by batch, then hands
+    /// the slots over. Returns `false` if stopped first.
+    ///
+    /// Every step can be repeated, so a migration interrupted anywhere is
+    /// resumed by starting it over.
+    fn transfer(&self, start: u16, end: u16, to: &str, stop: &AtomicBool) -> Result<bool, String> {
+        let mut client = Client::connect(to).map_err(|x| x.to_string())?;
+        let mut call = |requests| match client.pipeline(requests) {
+            Ok(res) => match res.into_iter().find(|x| x.is_error()) {
+                Some(Response::Error(code, message)) => Err(format!("{} {}", code, message)),
+                _ => Ok(()),
+            },
+            Err(err) => Err(err.to_string()),
+        };
+        call(vec![RequestCommand::Import(start, end, self.me.clone())])?;
+
+        let in_range = |x: &str| (start..=end).contains(&slot(x));
+        let mut moved = 0;
+        // The range is listed once per pass rather than once per batch; a
+        // pass picks up what was written to it while the last one ran.
+        loop {
+            // Listed with requests held, so once nothing is left every
+            // request for the range is redirected to the receiver.
+            let (keys, crdts, stale) = {
+                let _moving = self.moving.write().unwrap();
+                let stale: Vec<String> = (self.slots.read().unwrap().stale.iter())
+                    .filter(|x| in_range(x))
+                    .cloned()
+                    .collect();
+                (
+                    self.cache.keys_where(in_range),
+                    self.cache.crdt_keys_where(in_range),
+                    stale,
+                )
+            };
+            if keys.is_empty() && crdts.is_empty() && stale.is_empty() {
+                break;
+            }
+            // Stale keys no longer here were deleted or expired after the
+            // receiver may have got them. They stay stale, and served here,
+            // until the receiver dropped them too, so no write it took for
+            // them meanwhile is dropped along.
+            let gone: Vec<String> = stale
+                .into_iter()
+                .filter(|x| !self.cache.contains(x))
+                .collect();
+            for keys in gone.chunks(BATCH) {
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(false);
+                }
+                let mut requests: Vec<RequestCommand> = keys
+                    .iter()
+                    .map(|x| RequestCommand::Asking(Box::new(RequestCommand::Delete(x.clone()))))
+                    .collect();
+                requests.push(RequestCommand::Load(Vec::new()));
+                call(requests)?;
+
+                let _moving = self.moving.write().unwrap();
+                let synced = keys
+                    .iter()
+                    .filter(|x| !self.cache.contains(x))
+                    .cloned()
+                    .collect();
+                self.record(RequestCommand::Synced(synced))
+                    .map_err(|x| x.to_string())?;
+            }
+            for keys in keys.chunks(BATCH) {
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(false);
+                }
+                let batch = self.cache.dump_keys(keys);
+                if batch.is_empty() {
+                    continue;
+                }
+                // Requests for these keys are still served here while the
+                // copy is on its way.
+                call(vec![RequestCommand::Load(batch.clone())])?;
+
+                // Only keys unchanged since they were copied are dropped,
+                // the others go again with the next pass.
+                let _moving = self.moving.write().unwrap();
+                let unchanged: Vec<String> = batch
+                    .into_iter()
+                    .filter(|x| {
+                        self.cache.dump_keys(std::slice::from_ref(&x.key)).first() == Some(x)
+                    })
+                    .map(|x| x.key)
+                    .collect();
+                self.synced(&unchanged)?;
+                for key in unchanged {
+                    self.write(&RequestCommand::Delete(key));
+                    moved += 1;
+                }
+                // A crash must not bring back keys the receiver may have
+                // changed since.
+                self.wal.sync()?;
+            }
+            // CRDT values are merged into whatever the receiver holds, so
+            // sending one again after it changed here is harmless.
+            for keys in crdts.chunks(BATCH) {
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(false);
+                }
+                let batch = self.cache.dump_crdts(keys);
+                if batch.is_empty() {
+                    continue;
+                }
+                let mut requests: Vec<RequestCommand> = batch
+                    .iter()
+                    .map(|(key, x)| {
+                        RequestCommand::Asking(Box::new(RequestCommand::Merge(
+                            key.clone(),
+                            x.clone(),
+                        )))
+                    })
+                    .collect();
+                // Syncs the merges on the receiver, as a load of keys does.
+                requests.push(RequestCommand::Load(Vec::new()));
+                call(requests)?;
+
+                let _moving = self.moving.write().unwrap();
+                let unchanged: Vec<String> = batch
+                    .into_iter()
+                    .filter(|(key, x)| {
+                        let current = self.cache.dump_crdts(std::slice::from_ref(key));
+                        current.first().map(|x| &x.1) == Some(x)
+                    })
+                    .map(|(key, _)| key)
+                    .collect();
+                self.synced(&unchanged)?;
+                for key in unchanged {
+                    self.write(&RequestCommand::DeleteCrdt(key));
+                    moved += 1;
+                }
+                self.wal.sync()?;
+            }
+        }
+
+        call(vec![RequestCommand::SetSlots(start, end, to.to_owned())])?;
+        self.record(RequestCommand::SetSlots(start, end, to.to_owned()))
+            .map_err(|x| x.to_string())?;
+        println!(
+            "[Cluster] Moved {} keys of slots {}-{} to {}",
+            moved, start, end, to
+        );
+
+        // The others learn it from a MOVED until told; telling them is best effort.
+        let mut nodes: Vec<String> = self
+            .slots
+            .read()
+            .unwrap()
+            .map
+            .ranges
+            .iter()
+            .map(|x| x.2.clone())
+            .filter(|x| *x != self.me && x != to)
+            .collect();
+        nodes.sort();
+        nodes.dedup();
+        for node in nodes {
+            let res = Client::connect(&node)
+                .and_then(|mut x| x.execute(RequestCommand::SetSlots(start, end, to.to_owned())));
+            if let Err(err) = res {
+                eprintln!("[Cluster] {} did not learn the new owner: {}", node, err);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Cluster mode: this node serves the keys of its own slots and redirects
+/// clients elsewhere for the others.
+pub struct Cluster {
+    shared: Arc<Shared>,
+}
+
+impl Middleware for &Cluster {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        match f {
+            RequestCommand::Slots => self.shared.slots.read().unwrap().map.to_response(),
+            // Replicas hold whatever their primary owns.
+            RequestCommand::Replicate(_, _, _) => next.on_request(f),
+            RequestCommand::Migrate(start, end, to) => self.migrate(*start, *end, to),
+            RequestCommand::Import(start, end, from) => self.import(*start, *end, from),
+            RequestCommand::SetSlots(start, end, owner) => {
+                if let Err(res) = check_range(*start, *end) {
+                    return res;
+                }
+                self.shared.stop(*start, *end);
+                let f = RequestCommand::SetSlots(*start, *end, owner.clone());
+                match self.shared.record(f) {
+                    Ok(_) => Response::Ok,
+                    Err(err) => Response::error(ErrorCode::Internal, err.to_string()),
+                }
+            }
+            RequestCommand::Info => self.info(next.on_request(f)),
+            // Keys arriving from a migration are on disk before their
+            // sender drops its copies.
+            RequestCommand::Load(_) => {
+                let res = next.on_request(f);
+                match res.is_error() {
+                    true => res,
+                    false => match self.shared.wal.sync() {
+                        Ok(_) => res,
+                        Err(err) => Response::error(ErrorCode::Internal, err),
+                    },
+                }
+            }
+            x => {
+                let (x, asking) = match x {
+                    RequestCommand::Asking(x) => (x.as_ref(), true),
+                    x => (x, false),
+                };
+                let Some(key) = x.key() else {
+                    return next.on_request(x);
+                };
+                let slot = slot(key);
+                let slots = self.shared.slots.read().unwrap();
+                if let Some(from) = find(&slots.importing, slot) {
+                    if !asking {
+                        return Response::error(ErrorCode::Moved, format!("{} {}", slot, from));
+                    }
+                    drop(slots);
+                    return next.on_request(x);
+                }
+                if let Some(to) = find(&slots.migrating, slot) {
+                    let to = to.to_owned();
+                    drop(slots);
+                    let _moving = self.shared.moving.read().unwrap();
+                    let stale = self.shared.slots.read().unwrap().stale.contains(key);
+                    if !stale && !self.shared.cache.contains(key) {
+                        return Response::error(ErrorCode::Ask, format!("{} {}", slot, to));
+                    }
+                    let write = match x {
+                        RequestCommand::WithConcern(_, x) => is_write(x),
+                        x => is_write(x),
+                    };
+                    if write && !stale {
+                        let f = RequestCommand::Stale(key.to_owned());
+                        if let Err(err) = self.shared.record(f) {
+                            return Response::error(ErrorCode::Internal, err.to_string());
+                        }
+                    }
+                    return next.on_request(x);
+                }
+                match slots.map.owner(slot) {
+                    Some(owner) if owner != self.shared.me => {
+                        Response::error(ErrorCode::Moved, format!("{} {}", slot, owner))
+                    }
+                    _ => {
+                        drop(slots);
+                        next.on_request(x)
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_range(start: u16, end: u16) -> Result<(), Response> {
+    match start <= end && end < SLOTS {
+        true => Ok(()),
+        false => Err(Response::error(
+            ErrorCode::Internal,
+            format!("invalid slot range {}-{}", start, end),
+        )),
+    }
+}
+
+impl Cluster {
+    /// Splits the slots evenly between `nodes`, of which `me` is one, then
+    /// replays the cluster log in `dir` on top and resumes the migrations
+    /// it left unfinished.
+    pub fn open(
+        dir: &str,
+        nodes: &[String],
+        me: &str,
+        cache: &Cache,
+        wal: &WriteLog,
+    ) -> Result<Self, WalError> {
+        if !nodes.iter().any(|x| x == me) {
+            eprintln!(
+                "[Cluster] {} is not among the cluster nodes and owns no slots",
+                me
+            );
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(Path::new(dir).join(LOG))?;
+        let mut slots = Slots {
+            map: SlotMap::even(nodes),
+            ..Default::default()
+        };
+        let segment = Segment {
+            first_seq: 1,
+            last_seq: None,
+            archived: false,
+        };
+        let recovery = wal::recover(&file, &segment, 0, false, |x| slots.apply(&x.command))?;
+        if let Some((bytes, reason)) = recovery.truncated {
+            eprintln!("[Cluster] Dropped {} bytes from the log: {}", bytes, reason);
+        }
+        wal::write_header(&file)?;
+
+        let migrating = slots.migrating.clone();
+        let shared = Arc::new(Shared {
+            slots: RwLock::new(slots),
+            moving: RwLock::new(()),
+            log: Mutex::new(Journal {
+                file,
+                seq: recovery.last_seq,
+            }),
+            running: Mutex::new(HashMap::new()),
+            me: me.to_owned(),
+            cache: cache.clone(),
+            wal: wal.clone(),
+        });
+        for (start, end, to) in migrating {
+            println!(
+                "[Cluster] Resuming migration of slots {}-{} to {}",
+                start, end, to
+            );
+            shared.start(start, end, to);
+        }
+        Ok(Cluster { shared })
+    }
+
+    /// Starts, resumes or rolls back moving `start..=end` to `to`.
+    ///
+    /// On the owner this starts the move or resumes an interrupted one.
+    /// Naming this node as `to` pulls the keys here from the other end of
+    /// the move, which on the owner rolls it back: the keys that already
+    /// left are sent back and the slots stay where they were.
+    fn migrate(&self, start: u16, end: u16, to: &str) -> Response {
+        if let Err(res) = check_range(start, end) {
+            return res;
+        }
+        let shared = &self.shared;
+        let slots = shared.slots.read().unwrap();
+        let exact = |moves: &[Move]| {
+            moves
+                .iter()
+                .find(|x| x.0 == start && x.1 == end)
+                .map(|x| x.2.clone())
+        };
+        let migrating = exact(&slots.migrating);
+        let importing = exact(&slots.importing);
+
+        if to == shared.me {
+            let Some(other) = migrating.or(importing) else {
+                return Response::error(
+                    ErrorCode::Internal,
+                    format!("slots {}-{} are not being migrated", start, end),
+                );
+            };
+            drop(slots);
+            // Receive the keys back, then ask the other node to send them.
+            shared.stop(start, end);
+            if let Err(err) = shared.record(RequestCommand::Import(start, end, other.clone())) {
+                return Response::error(ErrorCode::Internal, err.to_string());
+            }
+            let me = shared.me.clone();
+            thread::spawn(move || {
+                let res = Client::connect(&other)
+                    .and_then(|mut x| x.execute(RequestCommand::Migrate(start, end, me)));
+                match res {
+                    Ok(Response::Error(code, message)) => eprintln!(
+                        "[Cluster] {} refused to send back slots {}-{}: {} {}",
+                        other, start, end, code, message
+                    ),
+                    Ok(_) => {}
+                    Err(err) => eprintln!(
+                        "[Cluster] Asking {} to send back slots {}-{} failed, retry the MIGRATE: {}",
+                        other, start, end, err
+                    ),
+                }
+            });
+            return Response::Ok;
+        }
+
+        match (migrating, importing) {
+            (Some(x), _) if x == to => {
+                drop(slots);
+                shared.start(start, end, x);
+                return Response::Ok;
+            }
+            (Some(x), _) => {
+                return Response::error(
+                    ErrorCode::Internal,
+                    format!("slots {}-{} are already moving to {}", start, end, x),
+                )
+            }
+            // The owner rolls back: send back whatever arrived so far.
+            (None, Some(x)) if x == to => {}
+            (None, Some(x)) => {
+                return Response::error(
+                    ErrorCode::Internal,
+                    format!("slots {}-{} are being imported from {}", start, end, x),
+                )
+            }
+            (None, None) => {
+                let busy = slots
+                    .migrating
+                    .iter()
+                    .chain(&slots.importing)
+                    .any(|x| overlaps(x, start, end));
+                if busy || !slots.owns(start, end, &shared.me) {
+                    return Response::error(
+                        ErrorCode::Internal,
+                        format!(
+                            "slots {}-{} are not all owned by this node or already moving",
+                            start, end
+                        ),
+                    );
+                }
+            }
+        }
+        drop(slots);
+        if let Err(err) = shared.record(RequestCommand::Migrate(start, end, to.to_owned())) {
+            return Response::error(ErrorCode::Internal, err.to_string());
+        }
+        shared.start(start, end, to.to_owned());
+        Response::Ok
+    }
+
+    /// Prepares to receive the keys of `start..=end` from `from`.
+    fn import(&self, start: u16, end: u16, from: &str) -> Response {
+        if let Err(res) = check_range(start, end) {
+            return res;
+        }
+        let slots = self.shared.slots.read().unwrap();
+        let busy = slots
+            .migrating
+            .iter()
+            .chain(&slots.importing)
+            .find(|x| overlaps(x, start, end));
+        match busy {
+            // Already importing them, or sending them back to `from`.
+            Some(x) if x.2 == from => return Response::Ok,
+            Some(x) => {
+                return Response::error(
+                    ErrorCode::Internal,
+                    format!("slots {}-{} are being moved with {}", x.0, x.1, x.2),
+                )
+            }
+            // A resumed migration that already handed the slots over.
+            None if slots.owns(start, end, &self.shared.me) => return Response::Ok,
+            None => {}
+        }
+        drop(slots);
+        match self
+            .shared
+            .record(RequestCommand::Import(start, end, from.to_owned()))
+        {
+            Ok(_) => Response::Ok,
+            Err(err) => Response::error(ErrorCode::Internal, err.to_string()),
+        }
+    }
+
+    /// Hands the slots of dead nodes to the member that took their place.
+    pub fn on_members(&self, members: &[Member]) {
+        let ranges = self.shared.slots.read().unwrap().map.ranges.clone();
+        for (start, end, owner) in ranges {
+            let dead = members
+                .iter()
+                .any(|x| x.addr == owner && x.state == MemberState::Dead);
+            let successor = members.iter().find(|x| {
+                x.state == MemberState::Alive && x.succeeds.as_deref() == Some(owner.as_str())
+            });
+            let (true, Some(successor)) = (dead, successor) else {
+                continue;
+            };
+            println!(
+                "[Cluster] {} took over slots {}-{} from {}",
+                successor.addr, start, end, owner
+            );
+            self.shared.stop(start, end);
+            let f = RequestCommand::SetSlots(start, end, successor.addr.clone());
+            if let Err(err) = self.shared.record(f) {
+                eprintln!("[Cluster] Failed to record the new owner: {}", err);
+            }
+        }
+    }
+
+    /// Adds a cluster section to the `INFO` of the cache.
+    fn info(&self, res: Response) -> Response {
+        let Response::Value(mut info) = res else {
+            return res;
+        };
+        let list = |moves: &[Move]| match moves.is_empty() {
+            true => "-".to_owned(),
+            false => moves
+                .iter()
+                .map(|x| format!("{}-{} {}", x.0, x.1, x.2))
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        let slots = self.shared.slots.read().unwrap();
+        let owned = slots
+            .map
+            .ranges
+            .iter()
+            .filter(|x| x.2 == self.shared.me)
+            .map(|x| (x.1 - x.0) as usize + 1)
+            .sum::<usize>();
+        info.extend(
+            format!(
+                "\r\n# Cluster\r\ncluster_node:{}\r\ncluster_slots:{}\r\ncluster_migrating:{}\r\ncluster_importing:{}\r\ncluster_transfers:{}\r\n",
+                self.shared.me,
+                owned,
+                list(&slots.migrating),
+                list(&slots.importing),
+                self.shared.running.lock().unwrap().len()
+            )
+            .as_bytes(),
+        );
+        Response::Value(info)
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Cache;
+use crate::cache::middlewares::{Middleware, MiddlewareNext};
+use crate::cache::multi::{Hlc, Stamp};
+use crate::cache::raft::Raft;
+use crate::proto::{ErrorCode, RequestCommand, Response};
+
+/// Locks striping the keyspace so updates of one key on this server are
+/// turned into deltas one at a time.
+const STRIPES: usize = 64;
+
+/// Counter every node adds to and subtracts from on its own; its value is
+/// what all of them added less what they subtracted.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    /// Total added by each node.
+    incs: BTreeMap<String, u64>,
+    /// Total subtracted by each node.
+    decs: BTreeMap<String, u64>,
+}
+
+impl PnCounter {
+    pub fn value(&self) -> i64 {
+        let sum =
+            |x: &BTreeMap<String, u64>| x.values().fold(0i64, |a, x| a.wrapping_add(*x as i64));
+        sum(&self.incs).wrapping_sub(sum(&self.decs))
+    }
+
+    /// The delta adding `by`, or subtracting if negative, on `node`.
+    pub fn add(&self, node: &str, by: i64) -> PnCounter {
+        let mut delta = PnCounter::default();
+        let (totals, delta_totals) = match by < 0 {
+            true => (&self.decs, &mut delta.decs),
+            false => (&self.incs, &mut delta.incs),
+        };
+        let total = totals.get(node).map_or(0, |x| *x);
+        delta_totals.insert(node.to_owned(), total.saturating_add(by.unsigned_abs()));
+        delta
+    }
+
+    /// Keeps the larger total of every node, as totals only grow.
+    pub fn merge(&mut self, other: &PnCounter) {
+        for (totals, theirs) in [(&mut self.incs, &other.incs), (&mut self.decs, &other.decs)] {
+            for (node, x) in theirs {
+                let total = totals.entry(node.clone()).or_default();
+                *total = (*total).max(*x);
+            }
+        }
+    }
+}
+
+/// Observed-remove set: every add of an element gets a unique tag and a
+/// remove drops the tags it saw, so an add concurrent with a remove of the
+/// same element wins.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct OrSet {
+    /// Tags of the adds of each element that were not removed.
+    adds: BTreeMap<Vec<u8>, BTreeSet<Stamp>>,
+    /// Tags of removed adds, so merging an older copy does not bring them
+    /// back. Multi-primary mode forgets them once every primary merged the
+    /// removals, as no copy holding them can arrive any longer.
+    removed: BTreeSet<Stamp>,
+}
+
+impl OrSet {
+    pub fn members(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.adds.keys()
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool {
+        self.adds.contains_key(element)
+    }
+
+    /// The delta adding every element under its own tag.
+    pub fn add(elements: impl IntoIterator<Item = (Vec<u8>, Stamp)>) -> OrSet {
+        let mut delta = OrSet::default();
+        for (element, tag) in elements {
+            delta.adds.entry(element).or_default().insert(tag);
+        }
+        delta
+    }
+
+    /// The delta removing `elements` as this copy has seen them added.
+    pub fn remove(&self, elements: &[Vec<u8>]) -> OrSet {
+        OrSet {
+            adds: BTreeMap::new(),
+            removed: elements
+                .iter()
+                .filter_map(|x| self.adds.get(x))
+                .flatten()
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Whether this holds tags of removed adds.
+    pub fn removes(&self) -> bool {
+        !self.removed.is_empty()
+    }
+
+    /// Drops the tags of removed adds, once no copy holding them can be
+    /// merged any longer.
+    pub fn forget_removed(&mut self) -> usize {
+        std::mem::take(&mut self.removed).len()
+    }
+
+    pub fn merge(&mut self, other: &OrSet) {
+        self.removed.extend(other.removed.iter().cloned());
+        for (element, tags) in &other.adds {
+            self.adds
+                .entry(element.clone())
+                .or_default()
+                .extend(tags.iter().cloned());
+        }
+        let removed = &self.removed;
+        self.adds.retain(|_, tags| {
+            tags.retain(|x| !removed.contains(x));
+            !tags.is_empty()
+        });
+    }
+}
+
+/// A value whose write with the latest stamp wins.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LwwRegister {
+    pub value: Vec<u8>,
+    pub stamp: Stamp,
+}
+
+impl LwwRegister {
+    pub fn merge(&mut self, other: &LwwRegister) {
+        if other.stamp > self.stamp {
+            *self = other.clone();
+        }
+    }
+}
+
+/// A value that nodes update on their own and that converges once every
+/// node merged the updates of the others, in any order and any number of
+/// times.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Crdt {
+    Counter(PnCounter),
+    Set(OrSet),
+    Register(LwwRegister),
+}
+
+impl Crdt {
+    /// Joins `other` into this value. Nodes that concurrently used a key
+    /// for values of different types all keep the same one: a register
+    /// over a set, and a set over a counter.
+    pub fn merge(&mut self, other: &Crdt) {
+        match (self, other) {
+            (Crdt::Counter(x), Crdt::Counter(y)) => x.merge(y),
+            (Crdt::Set(x), Crdt::Set(y)) => x.merge(y),
+            (Crdt::Register(x), Crdt::Register(y)) => x.merge(y),
+            (x, y) if y.rank() > x.rank() => *x = y.clone(),
+            _ => {}
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Crdt::Counter(_) => 0,
+            Crdt::Set(_) => 1,
+            Crdt::Register(_) => 2,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Crdt::Counter(_) => "counter",
+            Crdt::Set(_) => "set",
+            Crdt::Register(_) => "register",
+        }
+    }
+}
+
+/// The error for an update or read of a key holding another type.
+pub fn wrong_type(found: &Crdt) -> Response {
+    Response::error(
+        ErrorCode::WrongType,
+        format!("operation against a key holding a {}", found.kind()),
+    )
+}
+
+/// Whether `f` updates a CRDT value and is turned into a [`RequestCommand::Merge`].
+pub fn is_update(f: &RequestCommand) -> bool {
+    matches!(
+        f,
+        RequestCommand::CounterAdd(_, _)
+            | RequestCommand::SetAdd(_, _)
+            | RequestCommand::SetRemove(_, _)
+            | RequestCommand::RegisterSet(_, _)
+    )
+}
+
+/// Turns updates of CRDT values into the deltas they make, which is what
+/// the cache merges and what is logged and replicated. Merging a delta
+/// twice changes nothing, so replaying the log or a replica receiving a
+/// record again is harmless.
+pub struct Crdts {
+    hlc: Arc<Hlc>,
+    cache: Cache,
+    stripes: Box<[Mutex<()>]>,
+    /// In consensus mode, whose cache is only current on a leader that
+    /// confirmed it leads and applied all that was committed.
+    raft: Option<Raft>,
+}
+
+impl Middleware for &Crdts {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        let (concern, x) = match f {
+            RequestCommand::WithConcern(concern, x) => (Some(*concern), &**x),
+            x => (None, x),
+        };
+        let Some(key) = x.key().filter(|_| is_update(x)) else {
+            return next.on_request(f);
+        };
+
+        // Deltas build on the value they read, which must not change under
+        // them but by merges from other nodes.
+        let _order = self.stripe(key).lock().unwrap();
+        if let Some(raft) = &self.raft {
+            if let Err(res) = raft.await_reads() {
+                return res;
+            }
+        }
+        let (delta, reply) = match self.cache.read_crdt(key, |value| self.delta(x, value)) {
+            Ok(x) => x,
+            Err(res) => return res,
+        };
+        let merge = RequestCommand::Merge(key.to_owned(), delta);
+        let merge = match concern {
+            Some(concern) => RequestCommand::WithConcern(concern, Box::new(merge)),
+            None => merge,
+        };
+        match next.on_request(&merge) {
+            res if res.is_error() => res,
+            _ => reply,
+        }
+    }
+}
+
+impl Crdts {
+    pub fn new(hlc: &Arc<Hlc>, cache: &Cache, raft: Option<Raft>) -> Self {
+        Crdts {
+            hlc: hlc.clone(),
+            cache: cache.clone(),
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+            raft,
+        }
+    }
+
+    fn stripe(&self, key: &str) -> &Mutex<()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.stripes[hasher.finish() as usize % STRIPES]
+    }
+
+    /// The delta `f` makes to `value` and the reply to it.
+    fn delta(
+        &self,
+        f: &RequestCommand,
+        value: Option<&Crdt>,
+    ) -> Result<(Crdt, Response), Response> {
+        match (f, value) {
+            (RequestCommand::CounterAdd(_, by), None | Some(Crdt::Counter(_))) => {
+                let counter = match value {
+                    Some(Crdt::Counter(x)) => x,
+                    _ => &PnCounter::default(),
+                };
+                let delta = counter.add(self.hlc.origin(), *by);
+                Ok((
+                    Crdt::Counter(delta),
+                    Response::Integer(counter.value().wrapping_add(*by)),
+                ))
+            }
+            (RequestCommand::SetAdd(_, elements), None | Some(Crdt::Set(_))) => {
+                let added = elements
+                    .iter()
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .filter(|x| !matches!(value, Some(Crdt::Set(set)) if set.contains(x)))
+                    .count();
+                let delta = OrSet::add(elements.iter().map(|x| (x.clone(), self.hlc.now())));
+                Ok((Crdt::Set(delta), Response::Integer(added as i64)))
+            }
+            (RequestCommand::SetRemove(_, elements), None | Some(Crdt::Set(_))) => {
+                let set = match value {
+                    Some(Crdt::Set(x)) => x,
+                    _ => &OrSet::default(),
+                };
+                let removed = elements
+                    .iter()
+                    .collect::<BTreeSet<_>>()
+                    .into_iter()
+                    .filter(|x| set.contains(x))
+                    .count();
+                Ok((
+                    Crdt::Set(set.remove(elements)),
+                    Response::Integer(removed as i64),
+                ))
+            }
+            (RequestCommand::RegisterSet(_, val), None | Some(Crdt::Register(_))) => {
+                // Replicas do not observe the stamps they apply, so one
+                // promoted with a clock behind must not lose to the value
+                // it replaces.
+                if let Some(Crdt::Register(x)) = value {
+                    self.hlc.observe(&x.stamp);
+                }
+                let delta = LwwRegister {
+                    value: val.clone(),
+                    stamp: self.hlc.now(),
+                };
+                Ok((Crdt::Register(delta), Response::Ok))
+            }
+            (_, Some(x)) => Err(wrong_type(x)),
+            (x, None) => Err(Response::error(
+                ErrorCode::Unsupported,
+                format!("unsupported command {}", x),
+            )),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+
+use clap::ValueEnum;
+use rand::Rng;
+
+/// Chooses which key to drop when the cache runs over its memory limit.
+///
+/// The cache reports every write, read and removal; the policy only keeps
+/// whatever bookkeeping it needs to name a victim.
+pub trait EvictionPolicy: Debug + Send {
+    /// Name shown by `INFO`.
+    fn name(&self) -> &'static str;
+    /// A key was written or its deadline changed.
+    fn on_insert(&mut self, key: &str, expires_at: Option<u64>);
+    /// A key was read.
+    fn on_access(&mut self, _key: &str) {}
+    /// A key left the cache, whether deleted, expired or evicted.
+    fn on_remove(&mut self, key: &str);
+    /// The next key to evict, or `None` if the policy has nothing to offer.
+    fn victim(&mut self) -> Option<String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EvictionKind {
+    /// Reject writes once the limit is reached
+    None,
+    /// Evict the least recently used key
+    Lru,
+    /// Evict the least frequently used key
+    Lfu,
+    /// Evict a random key
+    Random,
+    /// Evict the key closest to expiring, only among keys with a deadline
+    Ttl,
+}
+
+impl EvictionKind {
+    pub fn build(&self) -> Box<dyn EvictionPolicy> {
+        match self {
+            EvictionKind::None => Box::new(NoEviction),
+            EvictionKind::Lru => Box::<Lru>::default(),
+            EvictionKind::Lfu => Box::<Lfu>::default(),
+            EvictionKind::Random => Box::<Random>::default(),
+            EvictionKind::Ttl => Box::<TtlFirst>::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NoEviction;
+
+impl EvictionPolicy for NoEviction {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn on_insert(&mut self, _key: &str, _expires_at: Option<u64>) {}
+
+    fn on_remove(&mut self, _key: &str) {}
+
+    fn victim(&mut self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Lru {
+    tick: u64,
+    last_used: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(old) = self.last_used.insert(key.to_owned(), self.tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, key.to_owned());
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+
+    fn on_insert(&mut self, key: &str, _expires_at: Option<u64>) {
+        self.touch(key);
+    }
+
+    fn on_access(&mut self, key: &str) {
+        self.touch(key);
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some(old) = self.last_used.remove(key) {
+            self.order.remove(&old);
+        }
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.order.first_key_value().map(|(_, key)| key.clone())
+    }
+}
+
+/// Least frequently used, ties broken by least recent use.
+#[derive(Debug, Default)]
+pub struct Lfu {
+    tick: u64,
+    counters: HashMap<String, (u64, u64)>,
+    order: BTreeSet<(u64, u64, String)>,
+}
+
+impl Lfu {
+    fn bump(&mut self, key: &str) {
+        self.tick += 1;
+        let (hits, last) = self.counters.get(key).copied().unwrap_or_default();
+        self.order.remove(&(hits, last, key.to_owned()));
+        self.counters
+            .insert(key.to_owned(), (hits.saturating_add(1), self.tick));
+        self.order
+            .insert((hits.saturating_add(1), self.tick, key.to_owned()));
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn name(&self) -> &'static str {
+        "lfu"
+    }
+
+    fn on_insert(&mut self, key: &str, _expires_at: Option<u64>) {
+        self.bump(key);
+    }
+
+    fn on_access(&mut self, key: &str) {
+        self.bump(key);
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some((hits, last)) = self.counters.remove(key) {
+            self.order.remove(&(hits, last, key.to_owned()));
+        }
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.order.first().map(|(_, _, key)| key.clone())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Random {
+    keys: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl EvictionPolicy for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn on_insert(&mut self, key: &str, _expires_at: Option<u64>) {
+        if !self.index.contains_key(key) {
+            self.index.insert(key.to_owned(), self.keys.len());
+            self.keys.push(key.to_owned());
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some(i) = self.index.remove(key) {
+            self.keys.swap_remove(i);
+            if let Some(moved) = self.keys.get(i) {
+                self.index.insert(moved.clone(), i);
+            }
+        }
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let i = rand::thread_rng().gen_range(0..self.keys.len());
+        Some(self.keys[i].clone())
+    }
+}
+
+/// Evicts the key with the nearest deadline; keys without one are never chosen.
+#[derive(Debug, Default)]
+pub struct TtlFirst {
+    deadlines: HashMap<String, u64>,
+    order: BTreeSet<(u64, String)>,
+}
+
+impl EvictionPolicy for TtlFirst {
+    fn name(&self) -> &'static str {
+        "ttl"
+    }
+
+    fn on_insert(&mut self, key: &str, expires_at: Option<u64>) {
+        self.on_remove(key);
+        if let Some(deadline) = expires_at {
+            self.deadlines.insert(key.to_owned(), deadline);
+            self.order.insert((deadline, key.to_owned()));
+        }
+    }
+
+    fn on_remove(&mut self, key: &str) {
+        if let Some(deadline) = self.deadlines.remove(key) {
+            self.order.remove(&(deadline, key.to_owned()));
+        }
+    }
+
+    fn victim(&mut self) -> Option<String> {
+        self.order.first().map(|(_, key)| key.clone())
+    }
+}
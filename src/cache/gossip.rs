@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::middlewares::{Middleware, MiddlewareNext};
+use crate::cache::now_millis;
+use crate::client::Peer;
+use crate::proto::{ErrorCode, RequestCommand, Response};
+
+/// Members asked to probe a member that did not answer directly.
+const INDIRECT_PROBES: usize = 3;
+
+/// Probe intervals between probes of a dead member, so members split by a
+/// partition find each other again once it heals.
+const DEAD_PROBE_EVERY: usize = 10;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemberState {
+    Alive,
+    /// Missed a probe; declared dead unless it refutes in time.
+    Suspect,
+    Dead,
+}
+
+impl Display for MemberState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            MemberState::Alive => "alive",
+            MemberState::Suspect => "suspect",
+            MemberState::Dead => "dead",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A node as seen through gossip.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub addr: String,
+    pub state: MemberState,
+    /// Raised only by the member itself, to refute a suspicion or announce
+    /// a change; starts at its start time so a restart supersedes old news.
+    pub incarnation: u64,
+    /// The primary this member replicates, if it is a replica.
+    pub primary: Option<String>,
+    /// A dead primary whose place this member took.
+    pub succeeds: Option<String>,
+    /// Members this member's own probes did not reach, as opposed to news
+    /// of them it heard; a failover needs a majority of these.
+    pub down: Vec<String>,
+}
+
+impl Member {
+    /// Whether this news supersedes `other`: a later incarnation, or the
+    /// same one in a worse state.
+    fn supersedes(&self, other: &Member) -> bool {
+        (self.incarnation, self.state) > (other.incarnation, other.state)
+    }
+}
+
+impl Display for Member {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "addr={} state={} incarnation={} primary={} succeeds={} down={}",
+            self.addr,
+            self.state,
+            self.incarnation,
+            self.primary.as_deref().unwrap_or("-"),
+            self.succeeds.as_deref().unwrap_or("-"),
+            match self.down.is_empty() {
+                true => "-".to_owned(),
+                false => self.down.join(","),
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GossipOptions {
+    /// Nodes to join through; they are probed like any member.
+    pub seeds: Vec<String>,
+    /// Time between probes, each of a different member.
+    pub interval: Duration,
+    /// How long a suspect has to refute before it is declared dead.
+    pub suspect_timeout: Duration,
+    /// Address of this node as other members reach it.
+    pub announce: String,
+}
+
+struct Shared {
+    /// Every member this node heard of, itself included, with when it
+    /// became a suspect.
+    members: Mutex<HashMap<String, (Member, Option<Instant>)>>,
+    peers: Mutex<HashMap<String, Arc<Peer>>>,
+    /// Streams fed by [`Gossip::subscribe`].
+    subscribers: Mutex<Vec<Sender<Member>>>,
+    options: GossipOptions,
+}
+
+impl Shared {
+    fn view(&self) -> Vec<Member> {
+        self.members
+            .lock()
+            .unwrap()
+            .values()
+            .map(|x| x.0.clone())
+            .collect()
+    }
+
+    /// Takes in what another member knows, refuting news of this node's
+    /// own failure with a later incarnation.
+    fn merge(&self, news: Vec<Member>) {
+        let mut changed = Vec::new();
+        let mut members = self.members.lock().unwrap();
+        for x in news {
+            if x.addr == self.options.announce {
+                let me = &mut members.get_mut(&x.addr).unwrap().0;
+                if x.state != MemberState::Alive && x.incarnation >= me.incarnation {
+                    me.incarnation = x.incarnation + 1;
+                    eprintln!("[Gossip] Refuted being {} by {}", x.state, x.addr);
+                }
+                continue;
+            }
+            let known = members.get(&x.addr).map(|x| &x.0);
+            if known.is_some_and(|known| !x.supersedes(known)) {
+                continue;
+            }
+            if known.is_none_or(|known| known.state != x.state) {
+                changed.push(x.clone());
+            }
+            let since = (x.state == MemberState::Suspect).then(Instant::now);
+            members.insert(x.addr.clone(), (x, since));
+        }
+        drop(members);
+        self.notify(changed);
+    }
+
+    /// Declares `addr` a suspect unless newer news about it arrived.
+    fn suspect(&self, addr: &str) {
+        let mut members = self.members.lock().unwrap();
+        let Some((member, since)) = members.get_mut(addr) else {
+            return;
+        };
+        if member.state != MemberState::Alive {
+            return;
+        }
+        eprintln!("[Gossip] {} missed its probe, suspecting it", addr);
+        member.state = MemberState::Suspect;
+        *since = Some(Instant::now());
+        let x = member.clone();
+        drop(members);
+        self.notify(vec![x]);
+    }
+
+    /// Declares dead the suspects that did not refute in time.
+    fn expire_suspects(&self) {
+        let mut changed = Vec::new();
+        for (member, since) in self.members.lock().unwrap().values_mut() {
+            if since.is_some_and(|x| x.elapsed() >= self.options.suspect_timeout) {
+                eprintln!("[Gossip] {} is dead", member.addr);
+                member.state = MemberState::Dead;
+                *since = None;
+                changed.push(member.clone());
+            }
+        }
+        self.notify(changed);
+    }
+
+    /// Records whether this node's own probe reached `addr`, announcing a
+    /// change with a new incarnation so that it spreads.
+    fn reached(&self, addr: &str, reached: bool) {
+        let mut members = self.members.lock().unwrap();
+        let me = &mut members.get_mut(&self.options.announce).unwrap().0;
+        if me.down.iter().any(|x| x == addr) != reached {
+            return;
+        }
+        match reached {
+            true => me.down.retain(|x| x != addr),
+            false => {
+                me.down.push(addr.to_owned());
+                me.down.sort();
+            }
+        }
+        me.incarnation += 1;
+    }
+
+    fn notify(&self, changed: Vec<Member>) {
+        if changed.is_empty() {
+            return;
+        }
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| changed.iter().all(|x| tx.send(x.clone()).is_ok()));
+    }
+
+    fn peer(&self, addr: &str) -> Arc<Peer> {
+        let timeout = self.options.interval / 2;
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(addr.to_owned())
+            .or_insert_with(|| Arc::new(Peer::new(addr, timeout)))
+            .clone()
+    }
+
+    /// Probes `addr` directly and merges its view; `false` if it did not
+    /// answer.
+    fn ping(&self, addr: &str) -> bool {
+        let ping = RequestCommand::Ping(self.options.announce.clone(), self.view());
+        match self.peer(addr).call(ping) {
+            Ok(Response::Members(x)) => {
+                self.merge(x);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Probes `target`, then through up to [`INDIRECT_PROBES`] other
+    /// members if it does not answer, and suspects it if none get through.
+    fn probe(&self, target: &str) {
+        if self.ping(target) {
+            self.reached(target, true);
+            return;
+        }
+        let mut helpers: Vec<String> = self
+            .view()
+            .into_iter()
+            .filter(|x| x.state == MemberState::Alive)
+            .map(|x| x.addr)
+            .filter(|x| x != target && *x != self.options.announce)
+            .collect();
+        helpers.shuffle(&mut rand::thread_rng());
+        helpers.truncate(INDIRECT_PROBES);
+
+        let reached = thread::scope(|s| {
+            let calls: Vec<_> = helpers
+                .iter()
+                .map(|x| {
+                    s.spawn(move || {
+                        let req = RequestCommand::PingReq(target.to_owned(), self.view());
+                        match self.peer(x).call(req) {
+                            Ok(Response::Members(x)) => {
+                                self.merge(x);
+                                true
+                            }
+                            _ => false,
+                        }
+                    })
+                })
+                .collect();
+            // The scope waits for the calls still running either way.
+            calls.into_iter().any(|x| x.join().unwrap_or(false))
+        });
+        self.reached(target, reached);
+        if !reached {
+            self.suspect(target);
+        }
+    }
+
+    /// Probes one member per interval, going round all live ones in a
+    /// random order that is reshuffled every round. Every
+    /// [`DEAD_PROBE_EVERY`] intervals a dead member is pinged as well; if it
+    /// answers it learns it was declared dead and refutes it.
+    fn run(&self) {
+        let mut round: Vec<String> = Vec::new();
+        for tick in 1.. {
+            thread::sleep(self.options.interval);
+            self.expire_suspects();
+            if tick % DEAD_PROBE_EVERY == 0 {
+                let dead: Vec<String> = self
+                    .view()
+                    .into_iter()
+                    .filter(|x| x.state == MemberState::Dead)
+                    .map(|x| x.addr)
+                    .collect();
+                if let Some(target) = dead.choose(&mut rand::thread_rng()) {
+                    let reached = self.ping(target);
+                    self.reached(target, reached);
+                }
+            }
+            if round.is_empty() {
+                round = self
+                    .view()
+                    .into_iter()
+                    .filter(|x| x.state != MemberState::Dead && x.addr != self.options.announce)
+                    .map(|x| x.addr)
+                    .collect();
+                round.shuffle(&mut rand::thread_rng());
+            }
+            if let Some(target) = round.pop() {
+                self.probe(&target);
+            }
+        }
+    }
+}
+
+/// SWIM-style membership: every interval one member is probed, directly
+/// and then through others, and the views of both sides are exchanged.
+pub struct Gossip {
+    shared: Arc<Shared>,
+}
+
+impl Middleware for &Gossip {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        match f {
+            RequestCommand::Ping(_, members) => {
+                self.shared.merge(members.clone());
+                Response::Members(self.shared.view())
+            }
+            RequestCommand::PingReq(target, members) => {
+                self.shared.merge(members.clone());
+                match self.shared.ping(target) {
+                    true => Response::Members(self.shared.view()),
+                    false => {
+                        Response::error(ErrorCode::Timeout, format!("{} did not answer", target))
+                    }
+                }
+            }
+            RequestCommand::Members => {
+                let mut members = self.members();
+                members.sort_by(|a, b| a.addr.cmp(&b.addr));
+                Response::Array(
+                    members
+                        .iter()
+                        .map(|x| Response::Value(x.to_string().into_bytes()))
+                        .collect(),
+                )
+            }
+            RequestCommand::Info => self.info(next.on_request(f)),
+            x => next.on_request(x),
+        }
+    }
+}
+
+impl Gossip {
+    /// Joins through the seeds and starts probing.
+    pub fn start(options: GossipOptions) -> Self {
+        let me = Member {
+            addr: options.announce.clone(),
+            state: MemberState::Alive,
+            incarnation: now_millis(),
+            primary: None,
+            succeeds: None,
+            down: Vec::new(),
+        };
+        let mut members = HashMap::from([(me.addr.clone(), (me, None))]);
+        // Known by address only until they answer with their own news.
+        for x in options.seeds.iter().filter(|x| **x != options.announce) {
+            let seed = Member {
+                addr: x.clone(),
+                state: MemberState::Alive,
+                incarnation: 0,
+                primary: None,
+                succeeds: None,
+                down: Vec::new(),
+            };
+            members.insert(x.clone(), (seed, None));
+        }
+        println!("[Gossip] Joining through {} seeds", members.len() - 1);
+
+        let shared = Arc::new(Shared {
+            members: Mutex::new(members),
+            peers: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(Vec::new()),
+            options,
+        });
+        let x = shared.clone();
+        thread::spawn(move || x.run());
+        Gossip { shared }
+    }
+
+    /// Every member this node knows of, itself included.
+    pub fn members(&self) -> Vec<Member> {
+        self.shared.view()
+    }
+
+    /// Streams every member whose state changed from now on.
+    pub fn subscribe(&self) -> Receiver<Member> {
+        let (tx, rx) = channel();
+        self.shared.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Announces the role of this node, if it changed.
+    pub fn set_role(&self, primary: Option<String>, succeeds: Option<String>) {
+        let mut members = self.shared.members.lock().unwrap();
+        let me = &mut members.get_mut(&self.shared.options.announce).unwrap().0;
+        if me.primary != primary || me.succeeds != succeeds {
+            me.primary = primary;
+            me.succeeds = succeeds;
+            me.incarnation += 1;
+        }
+    }
+
+    /// Adds a membership section to the `INFO` of the cache.
+    fn info(&self, res: Response) -> Response {
+        let Response::Value(mut info) = res else {
+            return res;
+        };
+        let members = self.members();
+        let count = |state| members.iter().filter(|x| x.state == state).count();
+        info.extend(
+            format!(
+                "\r\n# Membership\r\nmembers_alive:{}\r\nmembers_suspect:{}\r\nmembers_dead:{}\r\n",
+                count(MemberState::Alive),
+                count(MemberState::Suspect),
+                count(MemberState::Dead)
+            )
+            .as_bytes(),
+        );
+        Response::Value(info)
+    }
+}
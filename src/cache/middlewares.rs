@@ -1,43 +1,63 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::Debug;
-use std::fs::OpenOptions;
-use std::io::{BufWriter, Read, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::{channel, Sender};
+use std::hash::{Hash, Hasher};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::SystemTime;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::cache::CacheServer;
-use crate::proto;
-use crate::proto::{Frame, RequestCommand};
+use crate::cache::snapshot::Snapshot;
+use crate::cache::wal::manifest::{Manifest, Segment};
+use crate::cache::wal::{Append, Record, WalError, WalMessage, WalOptions, WalSync};
+use crate::cache::{deadline_after, snapshot, wal, Cache, CacheServer};
+use crate::proto::{ErrorCode, RequestCommand, Response};
 
+/// Locks striping the keyspace so writes to one key are logged in the order
+/// worker threads applied them.
+const WRITE_STRIPES: usize = 64;
+
+#[derive(Clone)]
 pub struct WriteLog {
-    tx: Sender<RequestCommand>,
+    tx: Sender<WalMessage>,
+    options: WalOptions,
+    manifest: Arc<Mutex<Manifest>>,
+    /// Sequence number of the next record, continued from the log on preload.
+    next_seq: Arc<AtomicU64>,
+    stripes: Arc<[Mutex<()>]>,
+    /// Streams fed by [`WriteLog::subscribe`]; its lock orders sequencing.
+    followers: Arc<Mutex<Vec<SyncSender<Record>>>>,
+    _lock: Arc<File>,
+    /// Directory holding the manifest, the segments and the snapshot.
     pub path: String,
 }
 
 pub trait Middleware {
-    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Vec<u8> {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
         next.on_request(f)
     }
 }
 
+pub type MiddlewareIter<'a> = dyn Iterator<Item = &'a dyn Middleware> + 'a;
+pub type RequestFn<'a> = Box<dyn FnOnce(&RequestCommand) -> Response + 'a>;
+
 pub struct MiddlewareNext<'a> {
-    middlewares: &'a mut (dyn Iterator<Item = &'a dyn Middleware>),
-    request_fn: Box<dyn FnOnce(&RequestCommand) -> Vec<u8> + 'a>,
+    middlewares: &'a mut MiddlewareIter<'a>,
+    request_fn: RequestFn<'a>,
 }
 
 impl<'a> MiddlewareNext<'a> {
-    pub fn new(
-        mw: &'a mut (dyn Iterator<Item = &'a dyn Middleware>),
-        req: Box<dyn FnOnce(&RequestCommand) -> Vec<u8> + 'a>,
-    ) -> Self {
+    pub fn new(mw: &'a mut MiddlewareIter<'a>, req: RequestFn<'a>) -> Self {
         MiddlewareNext {
             middlewares: mw,
             request_fn: req,
         }
     }
-    pub fn on_request(self, request: &RequestCommand) -> Vec<u8> {
+    pub fn on_request(self, request: &RequestCommand) -> Response {
         if let Some(step) = self.middlewares.next() {
             step.on_request(request, self)
         } else {
@@ -46,120 +66,497 @@ impl<'a> MiddlewareNext<'a> {
     }
 }
 
+/// Whether `f` changes the cache and so is logged and replicated.
+pub fn is_write(f: &RequestCommand) -> bool {
+    matches!(
+        f,
+        RequestCommand::Set(_, _)
+            | RequestCommand::SetEx(_, _, _)
+            | RequestCommand::SetExAt(_, _, _)
+            | RequestCommand::Delete(_)
+            | RequestCommand::Expire(_, _)
+            | RequestCommand::ExpireAt(_, _)
+            | RequestCommand::Persist(_)
+            | RequestCommand::Flush
+            | RequestCommand::Load(_)
+            | RequestCommand::Merge(_, _)
+            | RequestCommand::DeleteCrdt(_)
+    ) || matches!(f, RequestCommand::Stamped(_, x) if is_write(x))
+}
+
+/// Translates a write into the record that is logged, replicated and
+/// applied, `None` for anything else.
+///
+/// Relative timeouts become absolute deadlines, computed once here, so the
+/// cache, the log and every replay of it agree on when the key expires.
+/// Timeouts that do not fit in a deadline are refused.
+pub fn durable_record(f: &RequestCommand) -> Result<Option<RequestCommand>, Response> {
+    let record = match f {
+        RequestCommand::Set(_, _)
+        | RequestCommand::SetExAt(_, _, _)
+        | RequestCommand::Delete(_)
+        | RequestCommand::ExpireAt(_, _)
+        | RequestCommand::Persist(_)
+        | RequestCommand::Flush
+        | RequestCommand::Load(_)
+        | RequestCommand::Merge(_, _)
+        | RequestCommand::DeleteCrdt(_) => f.clone(),
+        RequestCommand::SetEx(key, val, secs) => {
+            RequestCommand::SetExAt(key.clone(), val.clone(), deadline_after(*secs)?)
+        }
+        RequestCommand::Expire(key, secs) => {
+            RequestCommand::ExpireAt(key.clone(), deadline_after(*secs)?)
+        }
+        // The record of a stamped write carries its stamp.
+        RequestCommand::Stamped(stamp, x) => {
+            return Ok(
+                durable_record(x)?.map(|x| RequestCommand::Stamped(stamp.clone(), Box::new(x)))
+            )
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(record))
+}
+
 impl Middleware for &WriteLog {
-    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Vec<u8> {
-        match f {
-            RequestCommand::Set(_, _) => {
-                self.tx
-                    .send(f.clone())
-                    .expect("[WAL] Failed to send message for sink");
-            }
-            RequestCommand::Delete(_) => {
-                self.tx
-                    .send(f.clone())
-                    .expect("[WAL] Failed to send message for sink");
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        let record = match durable_record(f) {
+            Ok(Some(x)) => x,
+            Ok(None) => return next.on_request(f),
+            Err(res) => return res,
+        };
+
+        // Writes without a key touch any number of keys and are ordered
+        // against all others.
+        let _order = match f.key() {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                vec![self.stripes[hasher.finish() as usize % WRITE_STRIPES]
+                    .lock()
+                    .unwrap()]
             }
-            _ => {}
+            None => self.stripes.iter().map(|x| x.lock().unwrap()).collect(),
+        };
+
+        let res = next.on_request(&record);
+        if res.is_error() {
+            return res;
         }
 
-        next.on_request(f)
+        let (synced, durable) = match self.options.sync {
+            WalSync::Always => {
+                let (tx, rx) = channel();
+                (Some(tx), Some(rx))
+            }
+            _ => (None, None),
+        };
+        self.append(vec![record], synced);
+        match durable.map(|x| x.recv().expect("[WAL] Writer stopped")) {
+            Some(Err(err)) => Response::error(
+                ErrorCode::Internal,
+                format!("write applied but not durable: {}", err),
+            ),
+            _ => res,
+        }
     }
 }
 
 impl WriteLog {
-    pub fn preload(&self, cache: &impl CacheServer) {
-        let f = OpenOptions::new()
-            .read(true)
-            .open(self.path.clone())
-            .unwrap();
+    /// Numbers `records` and queues them for the writer and every follower,
+    /// under one lock so all of them receive them in sequence.
+    fn append(&self, records: Vec<RequestCommand>, synced: Option<Sender<Result<(), String>>>) {
+        let mut followers = self.followers.lock().unwrap();
+        let first = self
+            .next_seq
+            .fetch_add(records.len() as u64, Ordering::SeqCst);
+        let records: Vec<Record> = (first..)
+            .zip(records)
+            .map(|(seq, command)| Record { seq, command })
+            .collect();
+        // A follower that falls behind misses records and has to catch up
+        // from the log.
+        followers.retain(|x| {
+            records
+                .iter()
+                .all(|r| !matches!(x.try_send(r.clone()), Err(TrySendError::Disconnected(_))))
+        });
+        self.tx
+            .send(WalMessage::Append(Append { records, synced }))
+            .expect("[WAL] Failed to send message for sink");
+    }
+
+    /// Streams every record logged from now on, up to `backlog` of them
+    /// waiting at a time.
+    pub fn subscribe(&self, backlog: usize) -> Receiver<Record> {
+        let (tx, rx) = sync_channel(backlog);
+        self.followers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Forces every record logged so far to disk, whatever the sync policy.
+    pub fn sync(&self) -> Result<(), String> {
+        let (tx, rx) = channel();
+        self.tx
+            .send(WalMessage::Sync(tx))
+            .expect("[WAL] Failed to send message for sink");
+        rx.recv().expect("[WAL] Writer stopped")
+    }
+
+    /// Sequence number of the last record logged.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq.load(Ordering::SeqCst) - 1
+    }
 
+    /// Restores the latest snapshot into `cache`, replays the segments
+    /// written since and continues their sequence numbers.
+    ///
+    /// A torn tail of the active segment is truncated; corruption anywhere
+    /// else stops startup unless `repair` allows truncating the log there,
+    /// dropping every later segment.
+    pub fn preload(&self, cache: &Cache, repair: bool) -> Result<(), WalError> {
         let t = SystemTime::now();
         println!("Preloading previous state...");
-        let mut i = 0;
-        while let Some(x) = proto::deserialize(&f).unwrap() {
+
+        let mut manifest = self.manifest.lock().unwrap();
+        *manifest = Manifest::load(&self.path)?;
+
+        let mut last_seq = 0;
+        if let Some(x) = snapshot::read(&snapshot::path(&self.path))? {
+            println!(
+                "Loaded snapshot of {} keys at sequence {}",
+                x.entries.len(),
+                x.seq
+            );
+            last_seq = x.seq;
+            cache.load(x.entries);
+            cache.load_stamps(x.stamps);
+            cache.load_crdts(x.crdts);
+        }
+        let base = last_seq;
+
+        let mut records = 0;
+        let mut after = None;
+        let count = manifest.segments.len();
+        for i in 0..count {
+            let segment = &manifest.segments[i];
+            let path = Path::new(&self.path).join(segment.file_name());
+            let f = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(f) => f,
+                // Listed right before the crash, never created.
+                Err(err) if err.kind() == io::ErrorKind::NotFound && !segment.is_sealed() => break,
+                Err(err) => return Err(err.into()),
+            };
+            let after = after.get_or_insert(segment.first_seq - 1);
+            let recovery = wal::recover(&f, segment, *after, repair, |x| {
+                if x.seq > base {
+                    cache.on_request(&x.command);
+                    records += 1;
+                }
+            })
+            .map_err(|err| match err {
+                WalError::Corrupt { offset, reason } => WalError::Corrupt {
+                    offset,
+                    reason: format!("{}: {}", path.display(), reason),
+                },
+                WalError::Format(reason) => {
+                    WalError::Format(format!("{}: {}", path.display(), reason))
+                }
+                err => err,
+            })?;
+            *after = recovery.last_seq;
+            last_seq = last_seq.max(recovery.last_seq);
+
+            if let Some((bytes, reason)) = &recovery.truncated {
+                eprintln!(
+                    "[WAL] Truncated {} bytes of {}: {}",
+                    bytes,
+                    path.display(),
+                    reason
+                );
+                // Only the repair truncates a sealed segment, and whatever
+                // follows it no longer continues the log.
+                if segment.is_sealed() {
+                    let empty = recovery.records == 0;
+                    manifest.segments[i].last_seq = Some(recovery.last_seq);
+                    for x in manifest.segments.drain(i + !empty as usize..) {
+                        eprintln!("[WAL] Dropped segment {}", x.file_name());
+                        let _ = fs::remove_file(Path::new(&self.path).join(x.file_name()));
+                    }
+                    manifest.save(&self.path)?;
+                    break;
+                }
+            }
+        }
+        self.next_seq.store(last_seq + 1, Ordering::SeqCst);
+
+        // Earlier releases kept the whole log in one file next to where the
+        // directory now is.
+        let legacy = Path::new(&self.path).with_extension("log");
+        if legacy.is_file() {
+            if last_seq > 0 || !manifest.segments.is_empty() {
+                return Err(WalError::Format(format!(
+                    "{} is left from an earlier version but {} holds a log already, move one of them away",
+                    legacy.display(),
+                    self.path
+                )));
+            }
+            drop(manifest);
+            records += self.import(cache, &legacy)?;
+        }
+        println!("Preloaded {} items in {:?}", records, t.elapsed().unwrap());
+        Ok(())
+    }
+
+    /// Applies the version 1 log at `legacy` to `cache` and logs its
+    /// commands anew, then renames it out of the way.
+    fn import(&self, cache: &Cache, legacy: &Path) -> Result<usize, WalError> {
+        let mut commands = Vec::new();
+        let torn = wal::read_v1(&File::open(legacy)?, |x| {
             cache.on_request(&x);
-            i += 1;
+            commands.push(x);
+        })
+        .map_err(|err| match err {
+            WalError::Format(reason) => WalError::Format(format!(
+                "{} is taken for a log of an earlier version, move it away if it is not: {}",
+                legacy.display(),
+                reason
+            )),
+            err => err,
+        })?;
+        if torn > 0 {
+            eprintln!(
+                "[WAL] Dropped {} bytes of an incomplete record at the end of {}",
+                torn,
+                legacy.display()
+            );
         }
-        println!("Preloaded {} items in {:?}", i, t.elapsed().unwrap());
+        let count = commands.len();
+        self.append(commands, None);
+        self.sync()
+            .map_err(|err| WalError::Io(io::Error::other(err)))?;
+
+        let imported = legacy.with_extension("log.imported");
+        fs::rename(legacy, &imported)?;
+        wal::sync_dir(&imported)?;
+        println!(
+            "[WAL] Imported {} records from {}, kept as {}",
+            count,
+            legacy.display(),
+            imported.display()
+        );
+        Ok(count)
     }
 
-    pub fn new(path: &str) -> Self {
-        let (tx, rx) = channel::<RequestCommand>();
+    /// Prepares and locks the WAL directory at `path`, then starts the
+    /// writer thread. Nothing is read until [`WriteLog::preload`].
+    pub fn open(path: &str, options: WalOptions) -> Result<Self, WalError> {
+        let file = Path::new(path);
+        if file.is_file() {
+            let dir = match file.with_extension("") {
+                x if x == file => file.with_extension("d"),
+                x => x,
+            };
+            return Err(WalError::Format(format!(
+                "{} is a log file of an earlier version, start with --wal {} to import it from {}.log",
+                file.display(),
+                dir.display(),
+                dir.display()
+            )));
+        }
+        let lock = wal::init_dir(path)?;
+        if let Some(archive) = &options.archive_dir {
+            wal::init_archive(archive)?;
+        }
+        let (tx, rx) = channel::<WalMessage>();
+        let next_seq = Arc::new(AtomicU64::new(1));
+        let manifest = Arc::new(Mutex::new(Manifest::default()));
 
-        let tpath = path.to_owned();
-        thread::spawn(move || {
-            let f = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(tpath)
-                .unwrap();
-            let mut w = BufWriter::new(f);
-            for x in rx.iter() {
-                let buf: Vec<u8> = x.into();
-
-                w.write(&*buf).unwrap();
-            }
-        });
+        let writer = wal::Writer::new(path, manifest.clone(), options.clone());
+        thread::spawn(move || writer.run(rx));
 
-        WriteLog {
+        Ok(WriteLog {
             tx,
+            options,
+            _lock: Arc::new(lock),
+            manifest,
+            next_seq,
+            stripes: (0..WRITE_STRIPES).map(|_| Mutex::new(())).collect(),
+            followers: Arc::new(Mutex::new(Vec::new())),
             path: path.to_string(),
+        })
+    }
+
+    /// Writes a snapshot of `cache` and compacts the log behind it. Returns
+    /// the sequence number it was taken at, or `None` if nothing was logged
+    /// since the last one.
+    ///
+    /// Shards are copied one at a time while writes continue. Whatever lands
+    /// after the active segment was sealed is replayed on top on startup,
+    /// which is safe as every logged record is an idempotent, absolute write.
+    pub fn snapshot(&self, cache: &Cache) -> Result<Option<u64>, WalError> {
+        let last = self.manifest.lock().unwrap().snapshot_seq;
+        if self.next_seq.load(Ordering::SeqCst) - 1 == last {
+            return Ok(None);
         }
+        // Every record numbered so far was sent before the seal, which the
+        // followers lock keeps appends from numbering more meanwhile.
+        let (tx, rx) = channel();
+        let seq = {
+            let _order = self.followers.lock().unwrap();
+            self.tx
+                .send(WalMessage::Seal(tx))
+                .expect("[WAL] Failed to send message for sink");
+            self.last_seq()
+        };
+        rx.recv().expect("[WAL] Writer stopped")?;
+
+        let snapshot = Snapshot {
+            seq,
+            entries: cache.dump(),
+            stamps: cache.stamps(),
+            crdts: cache.crdts(),
+            term: 0,
+        };
+        snapshot::write(&snapshot::path(&self.path), &snapshot)?;
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.snapshot_seq = seq;
+        manifest.save(&self.path)?;
+        Ok(Some(seq))
     }
-}
 
-pub struct Replicator {
-    tx: Sender<RequestCommand>,
-}
+    /// Copies sealed segments to the archive directory, then deletes those
+    /// covered by the snapshot beyond the newest `retain_segments`.
+    pub fn compact(&self) -> Result<(), WalError> {
+        let dir = Path::new(&self.path);
+        if let Some(archive) = &self.options.archive_dir {
+            let pending: Vec<Segment> = self
+                .manifest
+                .lock()
+                .unwrap()
+                .segments
+                .iter()
+                .filter(|x| x.is_sealed() && !x.archived)
+                .cloned()
+                .collect();
+            // Sealed segments never change, so they are copied unlocked.
+            for segment in pending {
+                let dst = Path::new(archive).join(segment.file_name());
+                let tmp = dst.with_extension("tmp");
+                fs::copy(dir.join(segment.file_name()), &tmp)?;
+                File::open(&tmp)?.sync_all()?;
+                fs::rename(&tmp, &dst)?;
+                wal::sync_dir(&dst)?;
+
+                let mut manifest = self.manifest.lock().unwrap();
+                if let Some(x) = manifest.segments.iter_mut().find(|x| *x == &segment) {
+                    x.archived = true;
+                }
+                manifest.save(&self.path)?;
+            }
+        }
 
-impl Middleware for &Replicator {
-    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Vec<u8> {
-        match f {
-            RequestCommand::Set(_, _) => {
-                self.tx
-                    .send(f.clone())
-                    .expect("[Replicator] Failed to send message for sink");
+        let removed: Vec<Segment> = {
+            let mut manifest = self.manifest.lock().unwrap();
+            let snapshot_seq = manifest.snapshot_seq;
+            let covered = manifest
+                .segments
+                .iter()
+                .take_while(|x| x.last_seq.is_some_and(|x| x <= snapshot_seq))
+                .take_while(|x| x.archived || self.options.archive_dir.is_none())
+                .count();
+            let n = covered.saturating_sub(self.options.retain_segments);
+            if n == 0 {
+                return Ok(());
             }
-            RequestCommand::Delete(_) => {
-                self.tx
-                    .send(f.clone())
-                    .expect("[Replicator] Failed to send message for sink");
+            let removed = manifest.segments.drain(..n).collect();
+            manifest.save(&self.path)?;
+            removed
+        };
+        for x in removed {
+            match fs::remove_file(dir.join(x.file_name())) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
             }
-            _ => {}
         }
+        Ok(())
+    }
 
-        next.on_request(f)
+    /// Passes every logged record after `seq` to `f`, oldest first, so a
+    /// replica can catch up from where it left off.
+    ///
+    /// Returns `false` if some of those records were already compacted away,
+    /// in which case the caller has to start over from a snapshot.
+    pub fn records_since(&self, seq: u64, mut f: impl FnMut(Record)) -> Result<bool, WalError> {
+        let segments = self.manifest.lock().unwrap().segments.clone();
+        if !self.retains(seq) {
+            return Ok(false);
+        }
+        for segment in segments
+            .iter()
+            .filter(|x| x.last_seq.is_none_or(|x| x > seq))
+        {
+            let file = match File::open(Path::new(&self.path).join(segment.file_name())) {
+                Ok(x) => x,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    // Compacted meanwhile, or listed but not written yet.
+                    match segment.is_sealed() {
+                        true => return Ok(false),
+                        false => continue,
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            };
+            wal::scan(&file, |x| {
+                if x.seq > seq {
+                    f(x)
+                }
+            })?;
+        }
+        Ok(true)
     }
-}
 
-impl Replicator {
-    pub fn new(addrs: Vec<String>) -> Self {
-        let addrs = addrs.clone();
-        let (tx, rx) = channel::<RequestCommand>();
+    /// Whether the log still holds every record after `seq`, so a replica
+    /// that applied up to `seq` can be caught up from it.
+    pub fn retains(&self, seq: u64) -> bool {
+        let next_seq = self.next_seq.load(Ordering::SeqCst);
+        let oldest = self
+            .manifest
+            .lock()
+            .unwrap()
+            .segments
+            .first()
+            .map_or(next_seq, |x| x.first_seq);
+        oldest <= seq + 1 && seq < next_seq
+    }
 
+    /// Spawns a thread that snapshots `cache` every `snapshot_interval`, if
+    /// set, and compacts and archives the log in between.
+    pub fn start_maintenance(&self, cache: &Cache, snapshot_interval: Option<Duration>) {
+        let wal = self.clone();
+        let cache = cache.clone();
         thread::spawn(move || {
-            let mut replicas = HashMap::new();
-
-            for addr in addrs {
-                let s = TcpStream::connect(&addr).expect("[Replicator] connection failed");
-                s.set_nodelay(true).unwrap();
-                s.set_nonblocking(true).unwrap();
-
-                replicas.insert(addr, s);
-            }
-
-            for x in rx.iter() {
-                replicas.iter().clone().for_each(|(_, mut replica)| {
-                    let buf: Vec<u8> = Frame::new(x.clone()).into();
-                    replica
-                        .write(&*buf)
-                        .expect("[Replicator] error while replicating");
-                });
+            let mut last_snapshot = Instant::now();
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                if snapshot_interval.is_some_and(|x| last_snapshot.elapsed() >= x) {
+                    last_snapshot = Instant::now();
+                    let t = SystemTime::now();
+                    match wal.snapshot(&cache) {
+                        Ok(Some(seq)) => println!(
+                            "[WAL] Snapshot at sequence {} in {:?}",
+                            seq,
+                            t.elapsed().unwrap()
+                        ),
+                        Ok(None) => {}
+                        Err(err) => eprintln!("[WAL] Snapshot failed: {}", err),
+                    }
+                }
+                if let Err(err) = wal.compact() {
+                    eprintln!("[WAL] Compaction failed: {}", err);
+                }
             }
         });
-
-        Replicator { tx }
     }
 }
 
@@ -175,7 +572,7 @@ impl Logger {
 }
 
 impl Middleware for &Logger {
-    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Vec<u8> {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
         let t = SystemTime::now();
         let res = next.on_request(f);
         if self.verbose {
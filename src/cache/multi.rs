@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{now_millis, Cache};
+use crate::proto::RequestCommand;
+
+/// How much later than its stamp a write may still be logged. Writes are
+/// stamped before they are numbered, so the stream of a peer is only nearly
+/// in stamp order and horizons are kept this far behind.
+const GRACE: Duration = Duration::from_secs(5);
+
+/// Time between heartbeats on a stream that is caught up.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Hybrid logical clock timestamp of a write and the primary it was made
+/// on. Later stamps win; the origin breaks ties, so every primary picks the
+/// same winner.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Stamp {
+    /// Unix milliseconds, or later if another primary's clock is ahead.
+    pub time: u64,
+    /// Orders the stamps issued within one millisecond.
+    pub counter: u32,
+    pub origin: String,
+}
+
+impl Stamp {
+    /// Older than any stamp issued by a clock, for keys written before
+    /// multi-primary mode.
+    pub fn zero(origin: &str) -> Self {
+        Stamp {
+            time: 0,
+            counter: 0,
+            origin: origin.to_owned(),
+        }
+    }
+}
+
+impl Display for Stamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}@{}", self.time, self.counter, self.origin)
+    }
+}
+
+/// Issues stamps that order after every stamp it issued or observed, even
+/// if the wall clock steps back.
+#[derive(Debug)]
+pub struct Hlc {
+    origin: String,
+    /// Time and counter of the last stamp issued or observed.
+    last: Mutex<(u64, u32)>,
+}
+
+impl Hlc {
+    pub fn new(origin: &str) -> Self {
+        Hlc {
+            origin: origin.to_owned(),
+            last: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Address of this server, the origin of its stamps.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// A stamp later than every stamp issued or observed so far.
+    pub fn now(&self) -> Stamp {
+        let mut last = self.last.lock().unwrap();
+        let time = now_millis();
+        *last = match time > last.0 {
+            true => (time, 0),
+            false => (last.0, last.1 + 1),
+        };
+        Stamp {
+            time: last.0,
+            counter: last.1,
+            origin: self.origin.clone(),
+        }
+    }
+
+    /// Moves the clock past a stamp issued elsewhere.
+    pub fn observe(&self, stamp: &Stamp) {
+        let mut last = self.last.lock().unwrap();
+        *last = (*last).max((stamp.time, stamp.counter));
+    }
+}
+
+/// What a primary heard from one of its peers.
+#[derive(Debug, Default)]
+struct Heard {
+    /// Time of the newest stamp the peer streamed here.
+    latest: Option<u64>,
+    /// Every write stamped before this time reached the peer.
+    horizon: Option<u64>,
+}
+
+/// Stamps the writes of this primary and follows how far the others got,
+/// to tell when a tombstone was seen everywhere.
+#[derive(Debug)]
+pub struct Clock {
+    hlc: Arc<Hlc>,
+    peers: Mutex<HashMap<String, Heard>>,
+}
+
+impl Clock {
+    /// Starts the clock past every stamp in `cache`, and stamps the keys
+    /// written before multi-primary mode with [`Stamp::zero`] so that
+    /// primaries that start out with different values agree on one.
+    ///
+    /// Peers are named by their `--addr`, as that is the origin of their
+    /// stamps.
+    pub fn open(hlc: &Arc<Hlc>, peers: &[String], cache: &Cache) -> Self {
+        let clock = Clock {
+            hlc: hlc.clone(),
+            peers: Mutex::new(
+                peers
+                    .iter()
+                    .map(|x| (x.clone(), Heard::default()))
+                    .collect(),
+            ),
+        };
+        let adopted = cache.adopt(&Stamp::zero(hlc.origin()));
+        if adopted > 0 {
+            println!("[Multi] Stamped {} keys that had no stamp", adopted);
+        }
+        if let Some(x) = cache.stamps().into_iter().map(|x| x.1).max() {
+            clock.observe(&x);
+        }
+        clock
+    }
+
+    /// Address of this primary, the origin of its stamps.
+    pub fn origin(&self) -> &str {
+        self.hlc.origin()
+    }
+
+    /// The other primaries, by their `--addr`.
+    pub fn peers(&self) -> Vec<String> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    pub fn now(&self) -> Stamp {
+        self.hlc.now()
+    }
+
+    pub fn observe(&self, stamp: &Stamp) {
+        self.hlc.observe(stamp)
+    }
+
+    /// Records a write its origin streamed here, in order.
+    pub fn heard(&self, stamp: &Stamp) {
+        if let Some(x) = self.peers.lock().unwrap().get_mut(&stamp.origin) {
+            x.latest = x.latest.max(Some(stamp.time));
+        }
+    }
+
+    /// Records a heartbeat: the origin streamed everything it stamped
+    /// before, and everything stamped before `horizon` reached it.
+    pub fn seen(&self, stamp: &Stamp, horizon: u64) {
+        if let Some(x) = self.peers.lock().unwrap().get_mut(&stamp.origin) {
+            x.latest = x.latest.max(Some(stamp.time));
+            x.horizon = x.horizon.max(Some(horizon));
+        }
+    }
+
+    /// Every write stamped before this time reached this primary; `None`
+    /// until every peer streamed to it.
+    pub fn horizon(&self) -> Option<u64> {
+        let peers = self.peers.lock().unwrap();
+        let horizon = peers
+            .values()
+            .try_fold(now_millis(), |horizon, x| Some(horizon.min(x.latest?)))?;
+        Some(horizon.saturating_sub(GRACE.as_millis() as u64))
+    }
+
+    /// Every write stamped before this time reached every primary, so
+    /// nothing older can arrive anywhere and its stamps can go.
+    pub fn stable(&self) -> Option<u64> {
+        let horizon = self.horizon()?;
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .try_fold(horizon, |stable, x| Some(stable.min(x.horizon?)))
+    }
+
+    /// Sent on a stream that is caught up, to move the horizons along when
+    /// there are no writes.
+    pub fn heartbeat(&self) -> RequestCommand {
+        let horizon = self.horizon().unwrap_or(0);
+        RequestCommand::Stamped(self.now(), Box::new(RequestCommand::Seen(horizon)))
+    }
+
+    /// Spawns a thread that drops the stamps and tombstones of `cache`, and
+    /// the tags its OR-Sets keep of removed members, once every primary has
+    /// seen them.
+    pub fn start_collector(self: &Arc<Self>, cache: &Cache) {
+        let clock = self.clone();
+        let cache = cache.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            if let Some(x) = clock.stable() {
+                cache.forget_stamps(x);
+                cache.forget_removed(x);
+            }
+        });
+    }
+
+    /// Multi-primary fields for the replication section of `INFO`.
+    pub fn info(&self, cache: &Cache) -> String {
+        let field = |x: Option<u64>| x.map_or(-1, |x| x as i64);
+        let peers = self.peers.lock().unwrap().len();
+        format!(
+            "multi_primary_peers:{}\r\nhorizon:{}\r\nstable:{}\r\ntombstones:{}\r\n",
+            peers,
+            field(self.horizon()),
+            field(self.stable()),
+            cache.tombstones()
+        )
+    }
+}
@@ -0,0 +1,966 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::middlewares::{durable_record, is_write, Middleware, MiddlewareNext};
+use crate::cache::snapshot::Snapshot;
+use crate::cache::wal::manifest::Segment;
+use crate::cache::wal::{sync_dir, WalError};
+use crate::cache::{snapshot, wal, Cache, CacheServer};
+use crate::client::Peer;
+use crate::proto::{ErrorCode, RequestCommand, Response};
+
+/// Consensus log in the WAL directory, in the WAL record format with the
+/// entry index as sequence number.
+const LOG: &str = "raft.log";
+
+/// Current term and vote in the WAL directory.
+const STATE: &str = "raft.state";
+
+/// Snapshot of the cache the log was compacted behind, in the WAL directory.
+const SNAPSHOT: &str = "raft.snapshot";
+
+/// How often a leader asserts itself when there is nothing to replicate.
+const HEARTBEAT: Duration = Duration::from_millis(50);
+
+/// How long a peer may take to answer a vote or an append.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Entries sent per append.
+const MAX_BATCH: usize = 512;
+
+/// How long a write waits to be committed.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes of a snapshot sent per install.
+const SNAPSHOT_CHUNK: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl Display for RaftRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            RaftRole::Follower => "follower",
+            RaftRole::Candidate => "candidate",
+            RaftRole::Leader => "leader",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Term and vote, saved before any reply depends on them.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Vote {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+impl Vote {
+    fn load(dir: &str) -> Result<Self, WalError> {
+        let path = Path::new(dir).join(STATE);
+        match fs::read(&path) {
+            Ok(buf) => serde_json::from_slice(&buf).map_err(|err| WalError::Corrupt {
+                offset: err.column() as u64,
+                reason: format!("{}: {}", path.display(), err),
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, dir: &str) -> io::Result<()> {
+        let path = Path::new(dir).join(STATE);
+        let tmp = path.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec_pretty(self)?)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&path)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    /// Addresses of the other members of the cluster.
+    pub peers: Vec<String>,
+    /// Shortest wait for a leader before standing for election; each wait
+    /// is drawn between this and twice this.
+    pub election_timeout: Duration,
+    /// Address of this node, as its peers know it.
+    pub announce: String,
+    /// Applied entries after which the cache is snapshotted and the log
+    /// compacted behind it.
+    pub compact_after: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    vote: Vote,
+    role: RaftRole,
+    leader: Option<String>,
+    /// Index and term of the last entry the snapshot covers, `0` without one.
+    base: u64,
+    base_term: u64,
+    /// Entries after `base`, with the term they were appended in.
+    log: Vec<(u64, RequestCommand)>,
+    /// Where every entry starts in the log file.
+    offsets: Vec<u64>,
+    file: File,
+    commit: u64,
+    applied: u64,
+    /// Results of applied entries that a client waits for, by index.
+    results: HashMap<u64, Option<Response>>,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    /// Heartbeat rounds reads asked for to confirm this node still leads,
+    /// and the last round each peer answered in the current term.
+    round: u64,
+    confirmed: HashMap<String, u64>,
+    /// When to stand for election unless a leader is heard from first.
+    election_at: Instant,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.base + self.log.len() as u64
+    }
+
+    /// Term of the entry at `index`, which must not be covered by the
+    /// snapshot but may be its last one.
+    fn term_at(&self, index: u64) -> u64 {
+        match index.checked_sub(self.base) {
+            Some(0) | None => self.base_term,
+            Some(x) => self.log.get(x as usize - 1).map_or(0, |x| x.0),
+        }
+    }
+
+    /// Appends `entries` to the log and forces them to disk.
+    fn append(&mut self, entries: Vec<(u64, RequestCommand)>) -> io::Result<()> {
+        let mut offset = self.file.metadata()?.len();
+        let mut buf = Vec::new();
+        let mut offsets = Vec::new();
+        for (i, (term, command)) in entries.iter().enumerate() {
+            let index = self.last_index() + 1 + i as u64;
+            let record = wal::encode(
+                index,
+                &RequestCommand::Entry(*term, Box::new(command.clone())),
+            );
+            buf.extend(&record);
+            offsets.push(offset);
+            offset += record.len() as u64;
+        }
+        (&self.file).write_all(&buf)?;
+        self.file.sync_data()?;
+        self.offsets.extend(offsets);
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    /// Drops the entries from `index` on.
+    fn truncate(&mut self, index: u64) -> io::Result<()> {
+        let i = (index - self.base) as usize - 1;
+        let Some(offset) = self.offsets.get(i) else {
+            return Ok(());
+        };
+        self.file.set_len(*offset)?;
+        self.file.sync_data()?;
+        self.log.truncate(i);
+        self.offsets.truncate(i);
+        Ok(())
+    }
+
+    /// Drops the entries up to `index`, which the snapshot now covers.
+    fn compact(&mut self, index: u64, term: u64, dir: &str) -> io::Result<()> {
+        let n = (index.min(self.last_index()) - self.base) as usize;
+        self.log.drain(..n);
+        self.offsets.drain(..n);
+        self.base = index;
+        self.base_term = term;
+        self.rewrite(dir)
+    }
+
+    /// Replaces the log file with one holding only the entries after `base`.
+    /// Until then the old file still holds them where `offsets` says.
+    fn rewrite(&mut self, dir: &str) -> io::Result<()> {
+        let path = Path::new(dir).join(LOG);
+        let tmp = path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(wal::MAGIC)?;
+        let mut offset = wal::MAGIC.len() as u64;
+        let mut offsets = Vec::with_capacity(self.log.len());
+        for (i, (term, command)) in self.log.iter().enumerate() {
+            let record = wal::encode(
+                self.base + 1 + i as u64,
+                &RequestCommand::Entry(*term, Box::new(command.clone())),
+            );
+            w.write_all(&record)?;
+            offsets.push(offset);
+            offset += record.len() as u64;
+        }
+        w.into_inner().map_err(|x| x.into_error())?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&path)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&path)?;
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    fn reset_election(&mut self, timeout: Duration) {
+        let jitter = rand::thread_rng().gen_range(0..=timeout.as_millis() as u64);
+        self.election_at = Instant::now() + timeout + Duration::from_millis(jitter);
+    }
+
+    /// Applies committed entries to `cache`, keeping the results clients wait for.
+    fn apply(&mut self, cache: &Cache) {
+        while self.applied < self.commit {
+            self.applied += 1;
+            let res = match &self.log[(self.applied - self.base) as usize - 1].1 {
+                // Appended by a new leader to commit what came before it.
+                RequestCommand::Empty => Response::Ok,
+                x => cache.on_request(x),
+            };
+            if let Some(slot) = self.results.get_mut(&self.applied) {
+                *slot = Some(res);
+            }
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled whenever the log, the commit index or the role changes.
+    changed: Condvar,
+    peers: Vec<Peer>,
+    cache: Cache,
+    dir: String,
+    options: RaftOptions,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn save_vote(&self, state: &State) {
+        if let Err(err) = state.vote.save(&self.dir) {
+            // Answering without the vote on disk could elect two leaders.
+            eprintln!("[Raft] Failed to save term and vote: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    /// Follows `term` if it is newer than ours.
+    fn observe(&self, state: &mut State, term: u64) {
+        if term > state.vote.term {
+            state.vote = Vote {
+                term,
+                voted_for: None,
+            };
+            self.save_vote(state);
+            if state.role != RaftRole::Follower {
+                eprintln!("[Raft] Stepping down in term {}", term);
+            }
+            state.role = RaftRole::Follower;
+            state.leader = None;
+            self.changed.notify_all();
+        }
+    }
+
+    /// Members, this node included, that make a majority.
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut State) {
+        state.vote = Vote {
+            term: state.vote.term + 1,
+            voted_for: Some(self.options.announce.clone()),
+        };
+        self.save_vote(state);
+        state.role = RaftRole::Candidate;
+        state.leader = None;
+        state.votes = HashSet::from([self.options.announce.clone()]);
+        state.reset_election(self.options.election_timeout);
+        if state.votes.len() >= self.quorum() {
+            return self.become_leader(state);
+        }
+
+        let request = RequestCommand::RequestVote(
+            state.vote.term,
+            self.options.announce.clone(),
+            state.last_index(),
+            state.term_at(state.last_index()),
+        );
+        for i in 0..self.peers.len() {
+            let shared = self.clone();
+            let request = request.clone();
+            thread::spawn(move || {
+                let peer = &shared.peers[i];
+                let Ok(Response::Vote(term, granted)) = peer.call(request.clone()) else {
+                    return;
+                };
+                let mut state = shared.lock();
+                shared.observe(&mut state, term);
+                let RequestCommand::RequestVote(asked, _, _, _) = request else {
+                    unreachable!()
+                };
+                if granted && state.role == RaftRole::Candidate && state.vote.term == asked {
+                    state.votes.insert(peer.addr.clone());
+                    if state.votes.len() >= shared.quorum() {
+                        shared.become_leader(&mut state);
+                    }
+                }
+            });
+        }
+    }
+
+    fn become_leader(&self, state: &mut State) {
+        eprintln!("[Raft] Elected leader in term {}", state.vote.term);
+        state.role = RaftRole::Leader;
+        state.leader = Some(self.options.announce.clone());
+        for peer in &self.peers {
+            state
+                .next_index
+                .insert(peer.addr.clone(), state.last_index() + 1);
+            state.match_index.insert(peer.addr.clone(), 0);
+        }
+        state.confirmed.clear();
+        // Entries of earlier terms only commit behind one of our own.
+        let term = state.vote.term;
+        if let Err(err) = state.append(vec![(term, RequestCommand::Empty)]) {
+            eprintln!("[Raft] Failed to append to the log: {}", err);
+        }
+        self.advance_commit(state);
+        self.changed.notify_all();
+    }
+
+    /// Commits what a quorum holds, as long as it includes an entry of the
+    /// current term.
+    fn advance_commit(&self, state: &mut State) {
+        let mut matched = state.match_index.values().copied().collect::<Vec<_>>();
+        matched.push(state.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > state.commit && state.term_at(index) == state.vote.term {
+            state.commit = index;
+            state.apply(&self.cache);
+            self.compact(state);
+            self.changed.notify_all();
+        }
+    }
+
+    /// Snapshots the cache once enough entries were applied since the last
+    /// time and drops them from the log. Entries are only applied under the
+    /// state lock, so the cache is exactly as of the last applied one.
+    fn compact(&self, state: &mut State) {
+        if state.applied - state.base < self.options.compact_after {
+            return;
+        }
+        let snapshot = Snapshot {
+            seq: state.applied,
+            entries: self.cache.dump(),
+            stamps: self.cache.stamps(),
+            crdts: self.cache.crdts(),
+            term: state.term_at(state.applied),
+        };
+        let res = snapshot::write(&Path::new(&self.dir).join(SNAPSHOT), &snapshot)
+            .and_then(|_| state.compact(snapshot.seq, snapshot.term, &self.dir));
+        if let Err(err) = res {
+            eprintln!("[Raft] Failed to compact the log: {}", err);
+        }
+    }
+
+    /// Stands for election whenever no leader was heard from in time.
+    fn run_elections(self: Arc<Self>) {
+        loop {
+            thread::sleep(Duration::from_millis(10));
+            let mut state = self.lock();
+            if state.role != RaftRole::Leader && Instant::now() >= state.election_at {
+                self.start_election(&mut state);
+            }
+        }
+    }
+
+    /// Sends entries, or heartbeats, to peer `i` while this node leads.
+    fn replicate(self: Arc<Self>, i: usize) {
+        let peer = &self.peers[i];
+        // The commit index and heartbeat round the peer was last sent.
+        let (mut sent_commit, mut sent_round) = (0, 0);
+        loop {
+            // Anything new for the peer goes out at once, else a heartbeat
+            // does once the interval is up.
+            let state = self.lock();
+            let (state, _) = self
+                .changed
+                .wait_timeout_while(state, HEARTBEAT, |x| {
+                    x.role != RaftRole::Leader
+                        || (x.next_index[&peer.addr] > x.last_index()
+                            && x.commit <= sent_commit
+                            && x.round <= sent_round)
+                })
+                .unwrap();
+            if state.role != RaftRole::Leader {
+                continue;
+            }
+
+            let (term, round) = (state.vote.term, state.round);
+            sent_round = round;
+            let prev = state.next_index[&peer.addr] - 1;
+            // The entries the peer needs next were compacted away.
+            if prev < state.base {
+                let (index, last_term) = (state.base, state.base_term);
+                // Opened before the next compaction may replace it.
+                let file = File::open(Path::new(&self.dir).join(SNAPSHOT));
+                drop(state);
+                let res = file.and_then(|x| self.send_snapshot(peer, term, x, index, last_term));
+                let mut state = self.lock();
+                match res {
+                    Ok(Response::Appended(theirs, true, _)) => {
+                        self.observe(&mut state, theirs);
+                        if state.role == RaftRole::Leader && state.vote.term == term {
+                            state.confirmed.insert(peer.addr.clone(), round);
+                            self.changed.notify_all();
+                            let matched = state.match_index[&peer.addr].max(index);
+                            state.match_index.insert(peer.addr.clone(), matched);
+                            state.next_index.insert(peer.addr.clone(), matched + 1);
+                            self.advance_commit(&mut state);
+                        }
+                        continue;
+                    }
+                    Ok(Response::Appended(theirs, false, _)) => self.observe(&mut state, theirs),
+                    Ok(x) => eprintln!("[Raft] {} answered {}", peer.addr, x),
+                    Err(_) => {}
+                }
+                // Sent again whole, not before a while.
+                drop(state);
+                thread::sleep(HEARTBEAT);
+                continue;
+            }
+            let end = state.last_index().min(prev + MAX_BATCH as u64);
+            let request = RequestCommand::AppendEntries(
+                term,
+                self.options.announce.clone(),
+                prev,
+                state.term_at(prev),
+                state.log[(prev - state.base) as usize..(end - state.base) as usize].to_vec(),
+                state.commit,
+            );
+            sent_commit = state.commit;
+            drop(state);
+
+            let res = peer.call(request);
+            let mut state = self.lock();
+            match res {
+                Ok(Response::Appended(theirs, success, index)) => {
+                    self.observe(&mut state, theirs);
+                    if state.role != RaftRole::Leader || state.vote.term != term {
+                        continue;
+                    }
+                    // Even a refusal shows the peer takes us for its leader.
+                    state.confirmed.insert(peer.addr.clone(), round);
+                    self.changed.notify_all();
+                    if success {
+                        let matched = state.match_index[&peer.addr].max(index);
+                        state.match_index.insert(peer.addr.clone(), matched);
+                        state.next_index.insert(peer.addr.clone(), matched + 1);
+                        self.advance_commit(&mut state);
+                    } else {
+                        let next = prev.min(index + 1).max(1);
+                        state.next_index.insert(peer.addr.clone(), next);
+                    }
+                }
+                Ok(x) => eprintln!("[Raft] {} answered {}", peer.addr, x),
+                Err(_) => {
+                    drop(state);
+                    thread::sleep(HEARTBEAT);
+                }
+            }
+        }
+    }
+
+    /// Sends the snapshot in `file`, which covers the log up to `index`, to
+    /// `peer` chunk by chunk. Returns the answer to the last chunk, or to the
+    /// first one not taken.
+    fn send_snapshot(
+        &self,
+        peer: &Peer,
+        term: u64,
+        mut file: File,
+        index: u64,
+        last_term: u64,
+    ) -> io::Result<Response> {
+        let len = file.metadata()?.len();
+        let mut offset = 0;
+        loop {
+            let mut data = vec![0u8; SNAPSHOT_CHUNK.min((len - offset) as usize)];
+            file.read_exact(&mut data)?;
+            let size = data.len() as u64;
+            let done = offset + size == len;
+            let res = peer.call(RequestCommand::InstallSnapshot(
+                term,
+                self.options.announce.clone(),
+                index,
+                last_term,
+                offset,
+                data,
+                done,
+            ))?;
+            if done || res != Response::Ok {
+                return Ok(res);
+            }
+            offset += size;
+        }
+    }
+
+    /// Takes `leader` as the leader of `term`, unless that term is over.
+    fn heard_from(&self, state: &mut State, term: u64, leader: &str) -> bool {
+        self.observe(state, term);
+        if term < state.vote.term {
+            return false;
+        }
+        if state.role != RaftRole::Follower {
+            state.role = RaftRole::Follower;
+            self.changed.notify_all();
+        }
+        if state.leader.as_deref() != Some(leader) {
+            eprintln!("[Raft] Following {} in term {}", leader, term);
+            state.leader = Some(leader.to_owned());
+        }
+        state.reset_election(self.options.election_timeout);
+        true
+    }
+
+    fn request_vote(&self, term: u64, candidate: &str, index: u64, last_term: u64) -> Response {
+        let mut state = self.lock();
+        self.observe(&mut state, term);
+        let ours = (state.term_at(state.last_index()), state.last_index());
+        let granted = term == state.vote.term
+            && state
+                .vote
+                .voted_for
+                .as_deref()
+                .is_none_or(|x| x == candidate)
+            && (last_term, index) >= ours;
+        if granted {
+            state.vote.voted_for = Some(candidate.to_owned());
+            self.save_vote(&state);
+            state.reset_election(self.options.election_timeout);
+        }
+        Response::Vote(state.vote.term, granted)
+    }
+
+    fn append_entries(
+        &self,
+        term: u64,
+        leader: &str,
+        prev: u64,
+        prev_term: u64,
+        entries: &[(u64, RequestCommand)],
+        commit: u64,
+    ) -> Response {
+        let mut state = self.lock();
+        if !self.heard_from(&mut state, term, leader) {
+            return Response::Appended(state.vote.term, false, state.last_index());
+        }
+
+        // Entries the snapshot covers are committed, so they are the
+        // leader's already.
+        let skip = state.base.saturating_sub(prev).min(entries.len() as u64);
+        let (prev, prev_term, entries) = match skip {
+            0 => (prev, prev_term, entries),
+            n => (prev + n, entries[n as usize - 1].0, &entries[n as usize..]),
+        };
+        if prev < state.base {
+            return Response::Appended(term, true, prev);
+        }
+        if prev > state.last_index() {
+            return Response::Appended(term, false, state.last_index());
+        }
+        if state.term_at(prev) != prev_term {
+            return Response::Appended(term, false, prev - 1);
+        }
+
+        for (i, x) in entries.iter().enumerate() {
+            let index = prev + 1 + i as u64;
+            if index <= state.last_index() && state.term_at(index) == x.0 {
+                continue;
+            }
+            let res = state
+                .truncate(index)
+                .and_then(|_| state.append(entries[i..].to_vec()));
+            if let Err(err) = res {
+                eprintln!("[Raft] Failed to append to the log: {}", err);
+                return Response::Appended(term, false, state.last_index().min(prev));
+            }
+            break;
+        }
+
+        let last = prev + entries.len() as u64;
+        let commit = commit.min(last);
+        if commit > state.commit {
+            state.commit = commit;
+            state.apply(&self.cache);
+            self.compact(&mut state);
+        }
+        Response::Appended(term, true, last)
+    }
+
+    /// Stores a chunk of the leader's snapshot, which covers the log up to
+    /// the entry at `index` of `last_term`, and once it is complete replaces
+    /// the cache and the log it covers with it.
+    fn install_snapshot(
+        &self,
+        term: u64,
+        leader: &str,
+        (index, last_term): (u64, u64),
+        offset: u64,
+        data: &[u8],
+        done: bool,
+    ) -> Response {
+        let mut state = self.lock();
+        if !self.heard_from(&mut state, term, leader) {
+            return Response::Appended(state.vote.term, false, state.last_index());
+        }
+        // Applied entries are committed, the snapshot has nothing newer.
+        if index <= state.applied {
+            return Response::Appended(term, true, index);
+        }
+
+        let path = Path::new(&self.dir).join(SNAPSHOT);
+        let part = path.with_extension("part");
+        let res = (|| {
+            let mut f = OpenOptions::new().create(true).append(true).open(&part)?;
+            if offset == 0 {
+                f.set_len(0)?;
+            } else if f.metadata()?.len() != offset {
+                return Err(io::Error::other("snapshot chunk out of order"));
+            }
+            f.write_all(data)?;
+            if done {
+                f.sync_all()?;
+                fs::rename(&part, &path)?;
+                sync_dir(&path)?;
+            }
+            Ok(())
+        })();
+        if let Err(err) = res {
+            eprintln!("[Raft] Failed to store a snapshot chunk: {}", err);
+            return Response::Appended(term, false, state.last_index());
+        }
+        if !done {
+            return Response::Ok;
+        }
+
+        let snapshot = match snapshot::read(&path) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Response::Appended(term, false, state.last_index()),
+            Err(err) => {
+                eprintln!("[Raft] Received an unreadable snapshot: {}", err);
+                return Response::Appended(term, false, state.last_index());
+            }
+        };
+        self.cache.flush();
+        self.cache.load(snapshot.entries);
+        self.cache.load_stamps(snapshot.stamps);
+        self.cache.load_crdts(snapshot.crdts);
+
+        // Entries after the snapshot stay if they continue it.
+        if index >= state.last_index() || state.term_at(index) != last_term {
+            state.log.clear();
+            state.offsets.clear();
+        }
+        if let Err(err) = state.compact(index, last_term, &self.dir) {
+            // The log on disk no longer matches the one in memory.
+            eprintln!("[Raft] Failed to rewrite the log: {}", err);
+            std::process::exit(1);
+        }
+        state.commit = state.commit.max(index);
+        state.applied = index;
+        eprintln!("[Raft] Installed snapshot up to {} from {}", index, leader);
+        Response::Appended(term, true, index)
+    }
+
+    /// Appends a write as leader and waits until it is committed and applied.
+    fn propose(&self, f: &RequestCommand) -> Response {
+        let mut state = self.lock();
+        if state.role != RaftRole::Leader {
+            return redirect(&state);
+        }
+        let term = state.vote.term;
+        let first = state.last_index() + 1;
+        let entries = match durable_record(f) {
+            Ok(x) => x.into_iter().map(|x| (term, x)).collect(),
+            Err(res) => return res,
+        };
+        if let Err(err) = state.append(entries) {
+            return Response::error(
+                ErrorCode::Internal,
+                format!("cannot append to the consensus log: {}", err),
+            );
+        }
+        let last = state.last_index();
+        state.results.insert(first, None);
+        self.advance_commit(&mut state);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if state.applied >= last {
+                // What applying the entry returned answers the client.
+                return state
+                    .results
+                    .remove(&first)
+                    .flatten()
+                    .unwrap_or(Response::Ok);
+            }
+            let now = Instant::now();
+            let lost = state.vote.term != term;
+            if lost || now >= deadline {
+                state.results.remove(&first);
+                let message = match lost {
+                    true => "leadership changed before the write committed",
+                    false => "write was not committed in time",
+                };
+                return Response::error(
+                    ErrorCode::Timeout,
+                    format!("{}, it may still apply", message),
+                );
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Waits until reads are as fresh as every write acknowledged before
+    /// them: a majority answered a heartbeat sent after the read arrived, so
+    /// no newer leader has committed anything meanwhile, and this leader
+    /// applied what was committed then, including an entry of its own term.
+    /// Until it applied one it may not know all that earlier leaders
+    /// committed.
+    fn await_reads(&self) -> Result<(), Response> {
+        let mut state = self.lock();
+        if state.role != RaftRole::Leader {
+            return Err(redirect(&state));
+        }
+        let term = state.vote.term;
+        let index = state.commit;
+        state.round += 1;
+        let round = state.round;
+        // Heartbeats go out now rather than at their next interval.
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        loop {
+            if state.role != RaftRole::Leader || state.vote.term != term {
+                return Err(redirect(&state));
+            }
+            let confirmed = 1 + state.confirmed.values().filter(|x| **x >= round).count();
+            if confirmed >= self.quorum()
+                && state.applied >= index
+                && state.term_at(state.applied) == term
+            {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                let message = match confirmed >= self.quorum() {
+                    true => "leader did not commit an entry of its term in time",
+                    false => "leader could not reach a majority to confirm it still leads",
+                };
+                return Err(Response::error(ErrorCode::Timeout, message));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+fn redirect(state: &State) -> Response {
+    match &state.leader {
+        Some(leader) => Response::error(ErrorCode::NotLeader, leader.clone()),
+        None => Response::error(ErrorCode::NotLeader, "no leader elected yet"),
+    }
+}
+
+/// Consensus mode: a static cluster elects a leader, which commits every
+/// write to a quorum of logs before applying and acknowledging it.
+#[derive(Clone)]
+pub struct Raft {
+    shared: Arc<Shared>,
+}
+
+impl Middleware for &Raft {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        let shared = &self.shared;
+        match f {
+            RequestCommand::RequestVote(term, candidate, index, last_term) => {
+                shared.request_vote(*term, candidate, *index, *last_term)
+            }
+            RequestCommand::AppendEntries(term, leader, prev, prev_term, entries, commit) => {
+                shared.append_entries(*term, leader, *prev, *prev_term, entries, *commit)
+            }
+            RequestCommand::InstallSnapshot(term, leader, index, last_term, offset, data, done) => {
+                shared.install_snapshot(*term, leader, (*index, *last_term), *offset, data, *done)
+            }
+            RequestCommand::Info => self.info(next.on_request(f)),
+            RequestCommand::WithConcern(_, x) if is_write(x) => shared.propose(x),
+            x if is_write(x) => shared.propose(x),
+            x if x.key().is_some()
+                || matches!(
+                    x,
+                    RequestCommand::Keys(_, _) | RequestCommand::KeysAfter(_, _, _)
+                ) =>
+            {
+                match shared.await_reads() {
+                    Ok(_) => next.on_request(x),
+                    Err(res) => res,
+                }
+            }
+            x => next.on_request(x),
+        }
+    }
+}
+
+impl Raft {
+    /// Loads the consensus snapshot, log and term from `dir` and starts
+    /// taking part in elections. The cache is restored from the snapshot and
+    /// rebuilt on top of it as entries are learnt to be committed.
+    pub fn open(dir: &str, cache: &Cache, options: RaftOptions) -> Result<Self, WalError> {
+        let vote = Vote::load(dir)?;
+        let (mut base, mut base_term) = (0, 0);
+        if let Some(x) = snapshot::read(&Path::new(dir).join(SNAPSHOT))? {
+            (base, base_term) = (x.seq, x.term);
+            cache.load(x.entries);
+            cache.load_stamps(x.stamps);
+            cache.load_crdts(x.crdts);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(Path::new(dir).join(LOG))?;
+
+        let mut records = Vec::new();
+        let segment = Segment {
+            first_seq: 1,
+            last_seq: None,
+            archived: false,
+        };
+        let recovery = wal::recover(&file, &segment, 0, false, |x| records.push(x))?;
+        if let Some((bytes, reason)) = recovery.truncated {
+            eprintln!("[Raft] Dropped {} bytes from the log: {}", bytes, reason);
+        }
+        wal::write_header(&file)?;
+        let (mut log, mut offsets, mut offset) = (Vec::new(), Vec::new(), wal::MAGIC.len() as u64);
+        for x in records {
+            let size = wal::HEADER_SIZE as u64 + bincode::serialized_size(&x.command).unwrap();
+            let RequestCommand::Entry(term, command) = x.command else {
+                let reason = format!("record {} is not a consensus entry", x.seq);
+                return Err(WalError::Corrupt { offset, reason });
+            };
+            // Left from before a compaction the log was not rewritten after.
+            if x.seq <= base {
+                offset += size;
+                continue;
+            }
+            if x.seq != base + log.len() as u64 + 1 {
+                let reason = format!("record {} does not follow the snapshot at {}", x.seq, base);
+                return Err(WalError::Corrupt { offset, reason });
+            }
+            log.push((term, *command));
+            offsets.push(offset);
+            offset += size;
+        }
+        println!(
+            "[Raft] Term {}, snapshot at {}, {} entries after it, {} peers",
+            vote.term,
+            base,
+            log.len(),
+            options.peers.len()
+        );
+
+        let mut state = State {
+            vote,
+            role: RaftRole::Follower,
+            leader: None,
+            base,
+            base_term,
+            log,
+            offsets,
+            file,
+            commit: base,
+            applied: base,
+            results: HashMap::new(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            round: 0,
+            confirmed: HashMap::new(),
+            election_at: Instant::now(),
+        };
+        state.reset_election(options.election_timeout);
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            peers: options
+                .peers
+                .iter()
+                .map(|x| Peer::new(x, RPC_TIMEOUT))
+                .collect(),
+            cache: cache.clone(),
+            dir: dir.to_owned(),
+            options,
+        });
+        let x = shared.clone();
+        thread::spawn(move || x.run_elections());
+        for i in 0..shared.peers.len() {
+            let x = shared.clone();
+            thread::spawn(move || x.replicate(i));
+        }
+        Ok(Raft { shared })
+    }
+
+    /// Waits until this node is known to lead and applied every write
+    /// acknowledged so far, or answers why it cannot serve reads.
+    pub fn await_reads(&self) -> Result<(), Response> {
+        self.shared.await_reads()
+    }
+
+    /// Adds a consensus section to the `INFO` of the cache.
+    fn info(&self, res: Response) -> Response {
+        let Response::Value(mut info) = res else {
+            return res;
+        };
+        let state = self.shared.lock();
+        info.extend(
+            format!(
+                "\r\n# Consensus\r\nraft_role:{}\r\nraft_term:{}\r\nraft_leader:{}\r\nraft_snapshot_index:{}\r\nraft_last_index:{}\r\nraft_commit:{}\r\nraft_applied:{}\r\n",
+                state.role,
+                state.vote.term,
+                state.leader.as_deref().unwrap_or("-"),
+                state.base,
+                state.last_index(),
+                state.commit,
+                state.applied
+            )
+            .as_bytes(),
+        );
+        Response::Value(info)
+    }
+}
@@ -0,0 +1,1077 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::cache::Cache;
+use crate::cache::gossip::{Member, MemberState};
+use crate::cache::multi::{Clock, Stamp, HEARTBEAT_INTERVAL};
+use crate::client::Peer;
+use crate::cache::middlewares::{is_write, Middleware, MiddlewareNext, WriteLog};
+use crate::cache::wal::{sync_dir, Record};
+use crate::proto::{ErrorCode, Frame, FrameDecoder, RequestCommand, Response, WriteConcern};
+
+/// First and longest wait between reconnect attempts.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long a replica may take to accept a connection or a write.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for the writer to log records a replica missed.
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a replica reminds its primary to stream to it.
+const ATTACH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Key and value bytes sent per frame during a full sync.
+const SYNC_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Last record applied from each primary, in the WAL directory.
+const APPLIED: &str = "replica.applied";
+
+/// How often a replica saves where it stands in the logs of its primaries.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaState {
+    Connecting,
+    /// Receiving a copy of the whole cache.
+    Syncing,
+    /// Replaying records it missed from the WAL.
+    CatchingUp,
+    Online,
+    /// Waiting to reconnect after a failure.
+    Backoff,
+}
+
+impl Display for ReplicaState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ReplicaState::Connecting => "connecting",
+            ReplicaState::Syncing => "syncing",
+            ReplicaState::CatchingUp => "catching_up",
+            ReplicaState::Online => "online",
+            ReplicaState::Backoff => "backoff",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+struct Status {
+    state: ReplicaState,
+    /// Sequence number of the last record written to the replica.
+    sent: u64,
+    reconnects: u64,
+    last_error: Option<String>,
+}
+
+/// Wakes writes waiting for replicas whenever one of them acknowledges.
+#[derive(Debug, Default)]
+struct Acks {
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+/// One replica and the thread streaming the log to it.
+#[derive(Debug)]
+struct Replica {
+    addr: String,
+    /// Address of this server, so the replica can tell its primary apart.
+    from: String,
+    status: Mutex<Status>,
+    /// Sequence number of the last record the replica answered.
+    acked: AtomicU64,
+    acks: Arc<Acks>,
+    /// Set once the replica left; its thread stops at the next chance.
+    detached: AtomicBool,
+    /// Multi-primary mode: the replica is another primary.
+    clock: Option<Arc<Clock>>,
+    /// Token the replica attached with, which it expects on every record;
+    /// `0` until it attached.
+    token: AtomicU64,
+}
+
+impl Replica {
+    fn set_state(&self, state: ReplicaState) {
+        self.status.lock().unwrap().state = state;
+    }
+
+    fn fail(&self, err: impl Display) {
+        let mut status = self.status.lock().unwrap();
+        eprintln!("[Replicator] {}: {}", self.addr, err);
+        status.last_error = Some(err.to_string());
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr =
+            self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "address did not resolve")
+            })?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// Keeps the replica connected for good, backing off exponentially
+    /// between failed attempts.
+    fn run(self: &Arc<Self>, cache: &Cache, wal: &WriteLog, rx: &Receiver<Record>) {
+        let mut backoff = MIN_BACKOFF;
+        while !self.detached.load(Ordering::SeqCst) {
+            self.set_state(ReplicaState::Connecting);
+            match self.connect() {
+                Ok(stream) => {
+                    backoff = MIN_BACKOFF;
+                    if let Err(err) = self.stream(cache, wal, rx, stream) {
+                        self.fail(err);
+                    }
+                }
+                Err(err) => self.fail(err),
+            }
+
+            let mut status = self.status.lock().unwrap();
+            status.state = ReplicaState::Backoff;
+            status.reconnects += 1;
+            drop(status);
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Streams records over `stream` until it fails, resuming after the last
+    /// record the replica applied, or from a full copy of `cache` if the WAL
+    /// no longer holds the records after it.
+    fn stream(
+        self: &Arc<Self>,
+        cache: &Cache,
+        wal: &WriteLog,
+        rx: &Receiver<Record>,
+        stream: TcpStream,
+    ) -> io::Result<()> {
+        let offset = self.offset(&stream)?.filter(|x| wal.retains(*x));
+        self.acked.store(offset.unwrap_or(0), Ordering::SeqCst);
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader = self.read_acks(stream.try_clone()?, closed.clone());
+        let mut w = BufWriter::new(&stream);
+
+        let res = (|| {
+            let mut sent = match (offset, &self.clock) {
+                (Some(x), _) => x,
+                (None, Some(clock)) => self.merge_sync(clock, cache, wal, &mut w)?,
+                (None, None) => self.full_sync(cache, wal, &mut w)?,
+            };
+            self.catch_up(wal, &mut w, &mut sent, wal.last_seq())?;
+            self.set_state(ReplicaState::Online);
+            let mut beat = Instant::now();
+            loop {
+                let first = match rx.recv_timeout(Duration::from_secs(1)) {
+                    Ok(x) => Some(x),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                };
+                for x in first.into_iter().chain(rx.try_iter()) {
+                    if x.seq <= sent {
+                        continue;
+                    }
+                    // The backlog overflowed, the gap is in the log.
+                    if x.seq > sent + 1 {
+                        self.catch_up(wal, &mut w, &mut sent, x.seq - 1)?;
+                    }
+                    if self.streams(&x) {
+                        self.send(&mut w, &x)?;
+                    }
+                    sent = x.seq;
+                }
+                if let Some(clock) = &self.clock {
+                    if beat.elapsed() >= HEARTBEAT_INTERVAL && wal.last_seq() == sent {
+                        w.write_all(&self.frame(sent, clock.heartbeat()))?;
+                        beat = Instant::now();
+                    }
+                }
+                w.flush()?;
+                self.status.lock().unwrap().sent = sent;
+                if self.detached.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                if closed.load(Ordering::SeqCst) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "replica closed the connection",
+                    ));
+                }
+            }
+        })();
+
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = reader.join();
+        res
+    }
+
+    /// Asks the replica for the last record it applied, `None` if it needs a
+    /// full sync.
+    fn offset(&self, stream: &TcpStream) -> io::Result<Option<u64>> {
+        let token = self.token.load(Ordering::SeqCst);
+        let buf: Vec<u8> =
+            Frame::new(0, RequestCommand::ReplicaOffset(self.from.clone(), token)).into();
+        (&*stream).write_all(&buf)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        let frame = FrameDecoder::new()
+            .read_frame::<_, Frame>(stream)
+            .map_err(io::Error::other)?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "replica closed the connection",
+                )
+            })?;
+        stream.set_read_timeout(None)?;
+        match RequestCommand::from(frame) {
+            RequestCommand::Recv(Response::Integer(x)) if x >= 0 => Ok(Some(x as u64)),
+            RequestCommand::Recv(Response::Nil) => Ok(None),
+            x => Err(io::Error::other(format!("refused to replicate: {}", x))),
+        }
+    }
+
+    /// Replaces everything on the replica with a copy of `cache` and returns
+    /// the sequence number to stream on from.
+    ///
+    /// The copy is taken while writes go on, so it may already hold some of
+    /// the records after that sequence number; replaying them is harmless as
+    /// every record is an absolute write.
+    fn full_sync(&self, cache: &Cache, wal: &WriteLog, w: &mut impl Write) -> io::Result<u64> {
+        self.set_state(ReplicaState::Syncing);
+        let seq = wal.last_seq();
+        let entries = cache.dump();
+        eprintln!(
+            "[Replicator] {}: full sync of {} keys at {}",
+            self.addr,
+            entries.len(),
+            seq
+        );
+
+        let mut send = |command: RequestCommand| w.write_all(&self.frame(0, command));
+        send(RequestCommand::Flush)?;
+        for (key, x) in cache.crdts() {
+            send(RequestCommand::Merge(key, x))?;
+        }
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        for x in entries {
+            size += x.key.len() + x.value.len();
+            chunk.push(x);
+            if size >= SYNC_CHUNK_SIZE {
+                send(RequestCommand::Load(std::mem::take(&mut chunk)))?;
+                size = 0;
+            }
+        }
+        // The last chunk tells the replica where it stands once applied.
+        w.write_all(&self.frame(seq, RequestCommand::Load(chunk)))?;
+        w.flush()?;
+        self.status.lock().unwrap().sent = seq;
+        Ok(seq)
+    }
+
+    /// Merges a copy of `cache` into another primary, with the stamps every
+    /// key and tombstone was written with, and returns the sequence number
+    /// to stream on from. Keys the other primary wrote later are kept.
+    fn merge_sync(
+        &self,
+        clock: &Clock,
+        cache: &Cache,
+        wal: &WriteLog,
+        w: &mut impl Write,
+    ) -> io::Result<u64> {
+        self.set_state(ReplicaState::Syncing);
+        let seq = wal.last_seq();
+        let mut stamps: HashMap<String, Stamp> = cache.stamps().into_iter().collect();
+        let entries = cache.dump();
+        eprintln!(
+            "[Replicator] {}: merging {} keys at {}",
+            self.addr,
+            entries.len(),
+            seq
+        );
+
+        let mut send = |stamp: Stamp, command: RequestCommand| {
+            let command = RequestCommand::Stamped(stamp, Box::new(command));
+            w.write_all(&self.frame(0, command))
+        };
+        // Stamps are dropped once every primary saw them, by then the
+        // primaries agree on the value.
+        let zero = Stamp::zero(clock.origin());
+        for x in entries {
+            let stamp = stamps.remove(&x.key).unwrap_or_else(|| zero.clone());
+            if let Some(deadline) = x.expires_at {
+                send(stamp.clone(), RequestCommand::Set(x.key.clone(), x.value))?;
+                send(stamp, RequestCommand::ExpireAt(x.key, deadline))?;
+            } else {
+                send(stamp, RequestCommand::Set(x.key, x.value))?;
+            }
+        }
+        // What is left are tombstones.
+        for (key, stamp) in stamps {
+            send(stamp, RequestCommand::Delete(key))?;
+        }
+        // CRDT values merge whatever their stamp.
+        for (key, x) in cache.crdts() {
+            send(zero.clone(), RequestCommand::Merge(key, x))?;
+        }
+        // The heartbeat tells the other primary where it stands.
+        w.write_all(&self.frame(seq, clock.heartbeat()))?;
+        w.flush()?;
+        self.status.lock().unwrap().sent = seq;
+        Ok(seq)
+    }
+
+    /// Whether `record` goes to the replica. Another primary only gets the
+    /// writes stamped here, it streams its own and those of the others
+    /// come from them.
+    fn streams(&self, record: &Record) -> bool {
+        match (&self.clock, &record.command) {
+            (None, _) => true,
+            (Some(clock), RequestCommand::Stamped(stamp, _)) => stamp.origin == clock.origin(),
+            (Some(_), _) => false,
+        }
+    }
+
+    /// Sends the records after `sent` up to `until` from the WAL.
+    fn catch_up(
+        &self,
+        wal: &WriteLog,
+        w: &mut impl Write,
+        sent: &mut u64,
+        until: u64,
+    ) -> io::Result<()> {
+        let started = Instant::now();
+        while *sent < until {
+            self.set_state(ReplicaState::CatchingUp);
+            let mut res = Ok(());
+            let retained = wal
+                .records_since(*sent, |x| {
+                    if res.is_ok() && x.seq == *sent + 1 && x.seq <= until {
+                        if self.streams(&x) {
+                            res = self.send(w, &x);
+                        }
+                        *sent = x.seq;
+                    }
+                })
+                .map_err(io::Error::other)?;
+            res?;
+            w.flush()?;
+            self.status.lock().unwrap().sent = *sent;
+
+            if !retained {
+                return Err(io::Error::other(format!(
+                    "records after {} were compacted away, resyncing",
+                    sent
+                )));
+            }
+            // The rest is numbered but not written by the WAL writer yet.
+            if *sent < until {
+                if started.elapsed() > CATCH_UP_TIMEOUT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("record {} did not reach the WAL", *sent + 1),
+                    ));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns a thread recording which records the replica answered, until
+    /// the connection closes.
+    fn read_acks(self: &Arc<Self>, stream: TcpStream, closed: Arc<AtomicBool>) -> JoinHandle<()> {
+        let replica = self.clone();
+        thread::spawn(move || {
+            let mut decoder = FrameDecoder::new();
+            while let Ok(Some(frame)) = decoder.read_frame::<_, Frame>(&stream) {
+                let id = frame.id();
+                if let RequestCommand::Recv(res) = RequestCommand::from(frame) {
+                    // Acknowledgements cover every record up to theirs, so
+                    // none that follow can be trusted; the replica resumes
+                    // from what it applied once reconnected.
+                    if res.is_error() {
+                        eprintln!(
+                            "[Replicator] {} rejected record {}: {}, reconnecting",
+                            replica.addr, id, res
+                        );
+                        break;
+                    }
+                }
+                replica.acked.fetch_max(id, Ordering::SeqCst);
+                let _lock = replica.acks.lock.lock().unwrap();
+                replica.acks.changed.notify_all();
+            }
+            closed.store(true, Ordering::SeqCst);
+        })
+    }
+
+    fn send(&self, w: &mut impl Write, record: &Record) -> io::Result<()> {
+        w.write_all(&self.frame(record.seq, record.command.clone()))
+    }
+
+    /// The frame carrying `command` as the record at `seq`, with the token
+    /// the replica attached with.
+    fn frame(&self, seq: u64, command: RequestCommand) -> Vec<u8> {
+        let token = self.token.load(Ordering::SeqCst);
+        Frame::new(
+            seq,
+            RequestCommand::Replicate(seq, token, Box::new(command)),
+        )
+        .into()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicaOptions {
+    /// Records queued per replica before it is caught up from the WAL.
+    pub backlog: usize,
+    /// Replicas that acknowledge a write before its client hears back.
+    pub concern: WriteConcern,
+    /// How long a write waits for them.
+    pub timeout: Duration,
+    /// Address this server is reached at, told to primaries and replicas.
+    pub announce: String,
+    /// Multi-primary mode: every server takes writes and stamps them, and
+    /// streams them to the others as replicas.
+    pub clock: Option<Arc<Clock>>,
+    /// Promote a replica once a majority of members cannot reach its
+    /// primary, rather than only tracking which replicas are alive.
+    pub failover: bool,
+}
+
+/// Whether this server takes writes or follows a primary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    Primary,
+    /// Read-only, applying what the primary at this address streams to it.
+    Replica(String),
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Primary => write!(f, "primary"),
+            Role::Replica(_) => write!(f, "replica"),
+        }
+    }
+}
+
+/// Streams the WAL to every replica, each from its own thread and backlog,
+/// and applies what a primary streams to this server.
+pub struct Replicator {
+    replicas: Mutex<Vec<Arc<Replica>>>,
+    role: Arc<Mutex<Role>>,
+    /// Last record applied from the primary, `None` until a full sync.
+    offset: Arc<Mutex<Option<u64>>>,
+    /// Multi-primary mode: last record applied from each other primary.
+    offsets: Arc<Mutex<HashMap<String, u64>>>,
+    /// Handed to the primary this server follows, or to the other
+    /// primaries, when asking them to stream; records that do not carry it
+    /// are refused. Replaced whenever this server follows another primary.
+    token: Arc<AtomicU64>,
+    /// The dead primary this server was promoted to replace.
+    succeeds: Mutex<Option<String>>,
+    /// Failover mode: this primary reaches no majority of members, which
+    /// may be replacing it, and refuses writes.
+    isolated: AtomicBool,
+    acks: Arc<Acks>,
+    cache: Cache,
+    wal: WriteLog,
+    options: ReplicaOptions,
+}
+
+impl Middleware for &Replicator {
+    fn on_request(&self, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        match f {
+            RequestCommand::Replicas => self.replicas(),
+            RequestCommand::Info => self.info(next.on_request(f)),
+            RequestCommand::Attach(addr, token) => {
+                self.attach(addr).token.store(*token, Ordering::SeqCst);
+                Response::Ok
+            }
+            RequestCommand::ReplicaOf(addr) => {
+                self.follow(addr);
+                Response::Ok
+            }
+            RequestCommand::Promote => {
+                self.promote();
+                Response::Ok
+            }
+            RequestCommand::ReplicaOffset(_, token) | RequestCommand::Replicate(_, token, _)
+                if *token != self.token.load(Ordering::SeqCst) =>
+            {
+                Response::error(
+                    ErrorCode::ReadOnly,
+                    "this server did not ask the sender to stream to it",
+                )
+            }
+            RequestCommand::ReplicaOffset(from, _) if self.options.clock.is_some() => {
+                match self.offsets.lock().unwrap().get(from) {
+                    Some(x) => Response::Integer(*x as i64),
+                    None => Response::Nil,
+                }
+            }
+            RequestCommand::ReplicaOffset(primary, _) => match self.primary() {
+                Some(x) if same_addr(&x, primary) => match *self.offset.lock().unwrap() {
+                    Some(x) => Response::Integer(x as i64),
+                    None => Response::Nil,
+                },
+                _ => Response::error(ErrorCode::ReadOnly, format!("not a replica of {}", primary)),
+            },
+            RequestCommand::Replicate(seq, _, x) if self.options.clock.is_some() => {
+                self.merge(*seq, x, next)
+            }
+            RequestCommand::Replicate(seq, _, x) => {
+                if self.primary().is_none() {
+                    return Response::error(ErrorCode::ReadOnly, "not a replica");
+                }
+                let mut offset = self.offset.lock().unwrap();
+                let res = next.on_request(x);
+                if !res.is_error() {
+                    *offset = Some(*seq).filter(|x| *x > 0);
+                }
+                res
+            }
+            x if is_write(x) || matches!(x, RequestCommand::WithConcern(_, _)) => {
+                if let Some(primary) = self.primary() {
+                    return Response::error(
+                        ErrorCode::ReadOnly,
+                        format!("this server is a replica of {}", primary),
+                    );
+                }
+                if self.isolated.load(Ordering::SeqCst) {
+                    return Response::error(
+                        ErrorCode::ReadOnly,
+                        "this server cannot reach a majority of members",
+                    );
+                }
+                match x {
+                    RequestCommand::WithConcern(concern, x) => self.write(x, *concern, next),
+                    x => self.write(x, self.options.concern, next),
+                }
+            }
+            x => next.on_request(x),
+        }
+    }
+}
+
+impl Replicator {
+    /// Starts streaming the log to `addrs`, each replica resuming where it
+    /// left off or starting with a full sync. With `replica_of` set this
+    /// server starts out as a replica and asks that primary to stream to it.
+    pub fn new(
+        addrs: Vec<String>,
+        replica_of: Option<String>,
+        cache: &Cache,
+        wal: &WriteLog,
+        options: ReplicaOptions,
+    ) -> Self {
+        let role = replica_of.map_or(Role::Primary, Role::Replica);
+        // Where a restarted server left off, as long as it applies the same
+        // primaries as before.
+        let saved = load_applied(&wal.path);
+        let offset = match (&role, &options.clock) {
+            (Role::Replica(primary), None) => saved
+                .iter()
+                .find(|(x, _)| same_addr(x, primary))
+                .map(|(_, x)| *x),
+            _ => None,
+        };
+        let offsets = match &options.clock {
+            Some(_) => saved.clone(),
+            None => HashMap::new(),
+        };
+        let applied = applied(&role, offset, &offsets);
+        if applied != saved {
+            if let Err(err) = save_applied(&wal.path, &applied) {
+                eprintln!("[Replicator] Failed to save the records applied: {}", err);
+            }
+        }
+        let replicator = Replicator {
+            replicas: Mutex::new(Vec::new()),
+            role: Arc::new(Mutex::new(role)),
+            offset: Arc::new(Mutex::new(offset)),
+            offsets: Arc::new(Mutex::new(offsets)),
+            token: Arc::new(AtomicU64::new(new_token())),
+            succeeds: Mutex::new(None),
+            isolated: AtomicBool::new(false),
+            acks: Arc::new(Acks::default()),
+            cache: cache.clone(),
+            wal: wal.clone(),
+            options,
+        };
+        for addr in addrs {
+            replicator.attach(&addr);
+        }
+        replicator.start_following();
+        replicator.start_saving(applied);
+        replicator
+    }
+
+    /// Follows the primary at `addr`, as a read-only replica.
+    fn follow(&self, addr: &str) {
+        let mut role = self.role.lock().unwrap();
+        if *role != Role::Replica(addr.to_owned()) {
+            eprintln!("[Replicator] Following {}", addr);
+            *role = Role::Replica(addr.to_owned());
+            // Sequence numbers of another primary mean nothing here, and
+            // the one followed before must not stream here any longer.
+            *self.offset.lock().unwrap() = None;
+            self.forget_applied();
+            self.token.store(new_token(), Ordering::SeqCst);
+            *self.succeeds.lock().unwrap() = None;
+            self.isolated.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn promote(&self) {
+        let mut role = self.role.lock().unwrap();
+        if *role != Role::Primary {
+            eprintln!("[Replicator] Promoted to primary");
+            *role = Role::Primary;
+            self.forget_applied();
+        }
+    }
+
+    /// The primary this server follows, if it is a replica.
+    pub fn primary(&self) -> Option<String> {
+        match &*self.role.lock().unwrap() {
+            Role::Primary => None,
+            Role::Replica(x) => Some(x.clone()),
+        }
+    }
+
+    /// Starts streaming to the replica at `addr` unless it already is.
+    fn attach(&self, addr: &str) -> Arc<Replica> {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(x) = replicas.iter().find(|x| x.addr == addr) {
+            return x.clone();
+        }
+        eprintln!("[Replicator] Streaming to {}", addr);
+        let replica = Arc::new(Replica {
+            addr: addr.to_owned(),
+            from: self.options.announce.clone(),
+            status: Mutex::new(Status {
+                state: ReplicaState::Connecting,
+                sent: 0,
+                reconnects: 0,
+                last_error: None,
+            }),
+            acked: AtomicU64::new(0),
+            acks: self.acks.clone(),
+            detached: AtomicBool::new(false),
+            clock: self.options.clock.clone(),
+            token: AtomicU64::new(0),
+        });
+        let rx = self.wal.subscribe(self.options.backlog);
+        let cache = self.cache.clone();
+        let wal = self.wal.clone();
+        let r = replica.clone();
+        thread::spawn(move || r.run(&cache, &wal, &rx));
+        replicas.push(replica.clone());
+        replica
+    }
+
+    /// Stops streaming to the replica at `addr`, if it was.
+    fn detach(&self, addr: &str) {
+        let mut replicas = self.replicas.lock().unwrap();
+        if let Some(i) = replicas.iter().position(|x| x.addr == addr) {
+            eprintln!("[Replicator] No longer streaming to {}", addr);
+            replicas.remove(i).detached.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// The dead primary this server took over from, if it did.
+    pub fn succeeds(&self) -> Option<String> {
+        self.succeeds.lock().unwrap().clone()
+    }
+
+    /// Follows membership: streams to every live member that replicates
+    /// this server and stops streaming to dead ones.
+    ///
+    /// With failover on, once its primary is dead and a majority of members
+    /// could not reach it with their own probes, a replica follows whichever
+    /// member took its place. Until one did, the live replica of it with the
+    /// lowest address promotes itself; the others agree on it as long as
+    /// they share the same view. A primary that does not reach a majority
+    /// itself refuses writes meanwhile, as the others may be replacing it
+    /// and it would lose them on stepping down.
+    pub fn on_members(&self, members: &[Member]) {
+        // Primaries of a multi-primary deployment stream to each other for
+        // good and have nobody to fail over to.
+        if self.options.clock.is_some() {
+            return;
+        }
+        let me = &self.options.announce;
+        for x in members {
+            match x.state {
+                MemberState::Alive if x.primary.as_deref().is_some_and(|x| same_addr(x, me)) => {
+                    self.attach(&x.addr);
+                }
+                MemberState::Dead => self.detach(&x.addr),
+                _ => {}
+            }
+        }
+        if !self.options.failover {
+            return;
+        }
+
+        let majority = members.len() / 2 + 1;
+        let Some(primary) = self.primary() else {
+            let alive = members
+                .iter()
+                .filter(|x| x.state == MemberState::Alive)
+                .count();
+            let isolated = alive < majority;
+            if self.isolated.swap(isolated, Ordering::SeqCst) != isolated {
+                match isolated {
+                    true => eprintln!(
+                        "[Replicator] Reaching {} of {} members, refusing writes",
+                        alive,
+                        members.len()
+                    ),
+                    false => eprintln!("[Replicator] Reaching a majority again, taking writes"),
+                }
+            }
+            // A primary back from the dead steps down to its successor.
+            let successor = members.iter().find(|x| {
+                x.state == MemberState::Alive
+                    && x.succeeds.as_deref().is_some_and(|x| same_addr(x, me))
+            });
+            if let Some(x) = successor {
+                eprintln!(
+                    "[Replicator] {} took this server's place, following it",
+                    x.addr
+                );
+                self.follow(&x.addr);
+            }
+            return;
+        };
+        let dead = members
+            .iter()
+            .any(|x| x.state == MemberState::Dead && same_addr(&x.addr, &primary));
+        let votes = members
+            .iter()
+            .filter(|x| x.state == MemberState::Alive)
+            .filter(|x| x.down.iter().any(|x| same_addr(x, &primary)))
+            .count();
+        if !dead || votes < majority {
+            return;
+        }
+        let alive = members.iter().filter(|x| x.state == MemberState::Alive);
+        let successor = alive
+            .clone()
+            .find(|x| {
+                x.succeeds
+                    .as_deref()
+                    .is_some_and(|x| same_addr(x, &primary))
+            })
+            .or_else(|| {
+                alive
+                    .filter(|x| x.primary.as_deref().is_some_and(|x| same_addr(x, &primary)))
+                    .min_by(|a, b| a.addr.cmp(&b.addr))
+            });
+        match successor {
+            Some(x) if same_addr(&x.addr, me) => {
+                eprintln!("[Replicator] Primary {} is dead, taking its place", primary);
+                self.promote();
+                *self.succeeds.lock().unwrap() = Some(primary);
+            }
+            Some(x) => self.follow(&x.addr),
+            None => {}
+        }
+    }
+
+    /// Spawns a thread that, while this server is a replica, keeps asking
+    /// its primary to stream to it, or in multi-primary mode the other
+    /// primaries. Asking again is harmless and covers a primary that
+    /// restarted and forgot about this server and its token.
+    fn start_following(&self) {
+        let role = self.role.clone();
+        let token = self.token.clone();
+        let announce = self.options.announce.clone();
+        let primaries = self
+            .options
+            .clock
+            .as_ref()
+            .map_or(Vec::new(), |x| x.peers());
+        thread::spawn(move || {
+            let mut attached: HashMap<String, (Peer, Option<Instant>)> = HashMap::new();
+            loop {
+                // Read together, so a token never goes to a primary it was
+                // not made for.
+                let (targets, token) = {
+                    let role = role.lock().unwrap();
+                    let targets = match &*role {
+                        Role::Primary => primaries.clone(),
+                        Role::Replica(x) => vec![x.clone()],
+                    };
+                    (targets, token.load(Ordering::SeqCst))
+                };
+                attached.retain(|x, _| targets.contains(x));
+                for addr in targets {
+                    let (peer, at) = attached
+                        .entry(addr.clone())
+                        .or_insert_with(|| (Peer::new(&addr, IO_TIMEOUT), None));
+                    if at.is_some_and(|x| x.elapsed() < ATTACH_INTERVAL) {
+                        continue;
+                    }
+                    match peer.call(RequestCommand::Attach(announce.clone(), token)) {
+                        Ok(Response::Ok) => {}
+                        Ok(x) => eprintln!("[Replicator] {} refused to stream: {}", addr, x),
+                        Err(err) => eprintln!("[Replicator] {}: {}", addr, err),
+                    }
+                    *at = Some(Instant::now());
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+
+    /// Saves where this server stands in the logs of its primaries every
+    /// [`SAVE_INTERVAL`], so that restarted it resumes there rather than
+    /// syncing in full. Only records already on disk are counted, as those
+    /// logged since are lost to a crash.
+    fn start_saving(&self, mut saved: HashMap<String, u64>) {
+        let role = self.role.clone();
+        let offset = self.offset.clone();
+        let offsets = self.offsets.clone();
+        let wal = self.wal.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SAVE_INTERVAL);
+            // Held throughout, so no record is applied meanwhile and nobody
+            // else saves.
+            let role = role.lock().unwrap();
+            let offset = offset.lock().unwrap();
+            let offsets = offsets.lock().unwrap();
+            let applied = applied(&role, *offset, &offsets);
+            if applied == saved {
+                continue;
+            }
+            match wal
+                .sync()
+                .and_then(|_| save_applied(&wal.path, &applied).map_err(|err| err.to_string()))
+            {
+                Ok(_) => saved = applied,
+                Err(err) => eprintln!("[Replicator] Failed to save the records applied: {}", err),
+            }
+        });
+    }
+
+    /// Forgets where this server stood in the log of the primary it stops
+    /// following, before it applies anything else. Called with the role
+    /// locked.
+    fn forget_applied(&self) {
+        if let Err(err) = save_applied(&self.wal.path, &HashMap::new()) {
+            eprintln!("[Replicator] Failed to save the records applied: {}", err);
+        }
+    }
+
+    /// Applies `f` and, if it is a write, waits until `concern` is met.
+    fn write(&self, f: &RequestCommand, concern: WriteConcern, next: MiddlewareNext) -> Response {
+        let stamped;
+        let f = match &self.options.clock {
+            Some(clock) if is_write(f) && !matches!(f, RequestCommand::Stamped(_, _)) => {
+                stamped = RequestCommand::Stamped(clock.now(), Box::new(f.clone()));
+                &stamped
+            }
+            _ => f,
+        };
+        let replicas = self.replicas.lock().unwrap().clone();
+        let required = concern.required(replicas.len());
+        if !is_write(f) || required == 0 {
+            return next.on_request(f);
+        }
+        if required > replicas.len() {
+            return Response::error(
+                ErrorCode::Internal,
+                format!(
+                    "write concern {} needs {} replicas, {} are configured",
+                    concern,
+                    required,
+                    replicas.len()
+                ),
+            );
+        }
+
+        let res = next.on_request(f);
+        if res.is_error() {
+            return res;
+        }
+        // Concurrent writes may be numbered after this one, waiting for them
+        // too only errs on the safe side.
+        let seq = self.wal.last_seq();
+        let deadline = Instant::now() + self.options.timeout;
+        let mut lock = self.acks.lock.lock().unwrap();
+        loop {
+            let acked = replicas
+                .iter()
+                .filter(|x| x.acked.load(Ordering::SeqCst) >= seq)
+                .count();
+            if acked >= required {
+                return res;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Response::error(
+                    ErrorCode::Timeout,
+                    format!(
+                        "write concern {} timed out: {} of {} replicas applied it",
+                        concern, acked, required
+                    ),
+                );
+            }
+            lock = self
+                .acks
+                .changed
+                .wait_timeout(lock, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Applies a record streamed by another primary, which stamped it.
+    fn merge(&self, seq: u64, f: &RequestCommand, next: MiddlewareNext) -> Response {
+        let clock = self.options.clock.as_ref().unwrap();
+        let RequestCommand::Stamped(stamp, x) = f else {
+            return Response::error(ErrorCode::Protocol, "record from a primary is not stamped");
+        };
+        clock.observe(stamp);
+        let mut offsets = self.offsets.lock().unwrap();
+        let res = match &**x {
+            RequestCommand::Seen(horizon) => {
+                clock.seen(stamp, *horizon);
+                Response::Ok
+            }
+            _ => next.on_request(f),
+        };
+        // The parts of a merge carry the stamps of whoever wrote them.
+        if seq > 0 {
+            clock.heard(stamp);
+            offsets.insert(stamp.origin.clone(), seq);
+        }
+        res
+    }
+
+    /// Adds a replication section to the `INFO` of the cache.
+    fn info(&self, res: Response) -> Response {
+        let Response::Value(mut info) = res else {
+            return res;
+        };
+        let role = self.role.lock().unwrap().clone();
+        info.extend(format!("\r\n# Replication\r\nrole:{}\r\n", role).as_bytes());
+        if let Role::Replica(primary) = role {
+            let offset = self.offset.lock().unwrap().map_or(-1, |x| x as i64);
+            info.extend(format!("primary:{}\r\nreplica_offset:{}\r\n", primary, offset).as_bytes());
+        }
+        let replicas = self.replicas.lock().unwrap().len();
+        info.extend(format!("replicas:{}\r\n", replicas).as_bytes());
+        if let Some(clock) = &self.options.clock {
+            info.extend(clock.info(&self.cache).as_bytes());
+        }
+        Response::Value(info)
+    }
+
+    /// One line per replica with its state and position in the log.
+    pub fn replicas(&self) -> Response {
+        Response::Array(
+            self.replicas
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|x| {
+                    let status = x.status.lock().unwrap();
+                    let acked = x.acked.load(Ordering::SeqCst);
+                    Response::Value(
+                        format!(
+                            "addr={} state={} sent={} acked={} reconnects={} last_error={}",
+                            x.addr,
+                            status.state,
+                            status.sent,
+                            acked,
+                            status.reconnects,
+                            status.last_error.as_deref().unwrap_or("-")
+                        )
+                        .into_bytes(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The last record applied from each primary, by its address.
+fn applied(
+    role: &Role,
+    offset: Option<u64>,
+    offsets: &HashMap<String, u64>,
+) -> HashMap<String, u64> {
+    match role {
+        Role::Replica(primary) => offset.map(|x| (primary.clone(), x)).into_iter().collect(),
+        Role::Primary => offsets.clone(),
+    }
+}
+
+/// Reads the records applied saved in `dir`, none if there are none or
+/// they cannot be read, which only costs a full sync.
+fn load_applied(dir: &str) -> HashMap<String, u64> {
+    let path = Path::new(dir).join(APPLIED);
+    match fs::read(&path) {
+        Ok(buf) => serde_json::from_slice(&buf).unwrap_or_else(|err| {
+            eprintln!("[Replicator] Ignoring {}: {}", path.display(), err);
+            HashMap::new()
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+        Err(err) => {
+            eprintln!("[Replicator] Ignoring {}: {}", path.display(), err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Replaces the records applied saved in `dir` atomically.
+fn save_applied(dir: &str, applied: &HashMap<String, u64>) -> io::Result<()> {
+    let path = Path::new(dir).join(APPLIED);
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp)?;
+    f.write_all(&serde_json::to_vec_pretty(applied)?)?;
+    f.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    sync_dir(&path)
+}
+
+/// A token for primaries to stream with, never `0`, which replicas that
+/// did not attach yet get.
+fn new_token() -> u64 {
+    rand::thread_rng().gen_range(1..=u64::MAX)
+}
+
+/// Whether two `host:port` strings resolve to a common address.
+fn same_addr(a: &str, b: &str) -> bool {
+    if a == b {
+        return true;
+    }
+    let (Ok(a), Ok(b)) = (a.to_socket_addrs(), b.to_socket_addrs()) else {
+        return false;
+    };
+    let b = b.collect::<Vec<_>>();
+    a.into_iter().any(|x| b.contains(&x))
+}
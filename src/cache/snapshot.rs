@@ -0,0 +1,80 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::crdt::Crdt;
+use crate::cache::multi::Stamp;
+use crate::cache::wal;
+use crate::cache::wal::WalError;
+
+/// Identifies a snapshot file and its format.
+const MAGIC: &[u8; 8] = b"PTSNAP04";
+
+/// A key as stored in the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// Absolute deadline in unix milliseconds.
+    pub expires_at: Option<u64>,
+}
+
+/// The cache as of WAL sequence number `seq`; only later records need replaying.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub seq: u64,
+    pub entries: Vec<SnapshotEntry>,
+    /// Multi-primary mode: the stamps of recent writes, tombstones included.
+    pub stamps: Vec<(String, Stamp)>,
+    pub crdts: Vec<(String, Crdt)>,
+    /// Consensus mode: the term of the log entry at `seq`.
+    pub term: u64,
+}
+
+/// Where the snapshot of the WAL directory `dir` is kept.
+pub fn path(dir: &str) -> PathBuf {
+    Path::new(dir).join("snapshot")
+}
+
+/// Writes `snapshot` next to its final place and renames it over the old
+/// one once durable, so a crash leaves either snapshot intact.
+///
+/// The file is `[magic][crc32c u32][bincode Snapshot]`.
+pub fn write(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let body = bincode::serialize(snapshot).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&crc32c::crc32c(&body).to_le_bytes())?;
+    w.write_all(&body)?;
+    w.into_inner().map_err(|x| x.into_error())?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    wal::sync_dir(path)
+}
+
+/// Reads the snapshot at `path`, `None` if there is none yet.
+pub fn read(path: &Path) -> Result<Option<Snapshot>, WalError> {
+    let mut buf = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut buf)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let invalid = |reason: &str| WalError::Snapshot(format!("{}: {}", path.display(), reason));
+    if buf.len() < MAGIC.len() + 4 || !buf.starts_with(MAGIC) {
+        return Err(invalid("not a snapshot"));
+    }
+    let crc = u32::from_le_bytes(buf[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+    let body = &buf[MAGIC.len() + 4..];
+    if crc32c::crc32c(body) != crc {
+        return Err(invalid("checksum mismatch"));
+    }
+    bincode::deserialize(body)
+        .map(Some)
+        .map_err(|err| invalid(&err.to_string()))
+}
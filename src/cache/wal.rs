@@ -0,0 +1,809 @@
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::{fs, process};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+use crate::cache::wal::manifest::{Manifest, Segment};
+use crate::proto::{RequestCommand, MAX_FRAME_SIZE};
+
+pub mod manifest;
+
+/// Identifies a log file and the format of its records. Version 1 is the
+/// `wal.log` of earlier releases, which had neither a header nor checksums.
+pub const MAGIC: &[u8; 8] = b"PTWAL002";
+
+/// Length, checksum and sequence number in front of every payload.
+///
+/// A record is `[length u32][crc32c u32][sequence u64][payload]`, little
+/// endian, following the [`MAGIC`] header of the file. The payload is a
+/// bincode `RequestCommand` and the checksum covers the sequence number and
+/// the payload.
+pub const HEADER_SIZE: usize = 16;
+
+pub fn encode(seq: u64, command: &RequestCommand) -> Vec<u8> {
+    let payload = bincode::serialize(command).expect("[WAL] Failed to encode record");
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend((payload.len() as u32).to_le_bytes());
+    buf.extend(checksum(seq, &payload).to_le_bytes());
+    buf.extend(seq.to_le_bytes());
+    buf.extend(payload);
+    buf
+}
+
+fn checksum(seq: u64, payload: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc32c::crc32c(&seq.to_le_bytes()), payload)
+}
+
+#[derive(Debug)]
+pub enum WalError {
+    Io(io::Error),
+    /// The WAL directory cannot be created, locked or written.
+    Dir(PathBuf, io::Error),
+    /// Another server holds the lock of the WAL directory.
+    Locked(PathBuf, String),
+    /// The snapshot exists but cannot be trusted.
+    Snapshot(String),
+    /// A log written in a format this build does not read.
+    Format(String),
+    /// A damaged record with intact data behind it, which a crash mid-write
+    /// cannot explain.
+    Corrupt {
+        offset: u64,
+        reason: String,
+    },
+}
+
+impl Display for WalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalError::Io(err) => write!(f, "WAL i/o error: {}", err),
+            WalError::Dir(path, err) => {
+                write!(f, "cannot use WAL directory {}: {}", path.display(), err)
+            }
+            WalError::Locked(path, owner) => write!(
+                f,
+                "{} is held by another server ({}), two servers cannot share a WAL",
+                path.display(),
+                owner
+            ),
+            WalError::Snapshot(reason) => write!(f, "snapshot is unreadable: {}", reason),
+            WalError::Format(reason) => write!(
+                f,
+                "WAL cannot be read by this version of the server: {}",
+                reason
+            ),
+            WalError::Corrupt { offset, reason } => write!(
+                f,
+                "WAL is corrupt at offset {}: {}, start with --wal-repair to truncate it there",
+                offset, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl From<io::Error> for WalError {
+    fn from(value: io::Error) -> Self {
+        WalError::Io(value)
+    }
+}
+
+/// Outcome of scanning a log on startup.
+#[derive(Debug, Default)]
+pub struct Recovery {
+    pub records: usize,
+    /// Sequence number of the last intact record, or where the log was
+    /// expected to continue from if it is empty.
+    pub last_seq: u64,
+    /// Bytes cut off the end of the log, with the reason they were dropped.
+    pub truncated: Option<(u64, String)>,
+}
+
+/// One entry of the log.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub seq: u64,
+    pub command: RequestCommand,
+}
+
+/// Why reading stopped at a record.
+enum Damage {
+    Io(io::Error),
+    /// The record runs past the end of the file.
+    Torn,
+    /// The record does not check out; its size if the header could be trusted.
+    Invalid(String, Option<u64>),
+}
+
+impl From<io::Error> for Damage {
+    fn from(value: io::Error) -> Self {
+        Damage::Io(value)
+    }
+}
+
+/// Reads the next record and its size on disk, `Ok(None)` at a clean end of the log.
+fn read_record(r: &mut impl Read, remaining: u64) -> Result<Option<(Record, u64)>, Damage> {
+    if remaining == 0 {
+        return Ok(None);
+    }
+    if remaining < HEADER_SIZE as u64 {
+        return Err(Damage::Torn);
+    }
+    let mut header = [0u8; HEADER_SIZE];
+    r.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let seq = u64::from_le_bytes(header[8..16].try_into().unwrap());
+
+    if len > MAX_FRAME_SIZE {
+        let reason = format!("record length {} is too large", len);
+        return Err(Damage::Invalid(reason, None));
+    }
+    let size = (HEADER_SIZE + len) as u64;
+    if size > remaining {
+        return Err(Damage::Torn);
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    if checksum(seq, &payload) != crc {
+        return Err(Damage::Invalid("checksum mismatch".to_owned(), Some(size)));
+    }
+    match bincode::deserialize(&payload) {
+        Ok(command) => Ok(Some((Record { seq, command }, size))),
+        Err(err) => {
+            let reason = format!("undecodable record: {}", err);
+            Err(Damage::Invalid(reason, Some(size)))
+        }
+    }
+}
+
+/// Whether everything from `offset` on is zero, as filesystems may leave
+/// behind after a crash.
+fn zeroed_from(file: &File, offset: u64) -> io::Result<bool> {
+    let mut r = BufReader::new(file);
+    r.seek(SeekFrom::Start(offset))?;
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        match r.read(&mut chunk)? {
+            0 => return Ok(true),
+            n if chunk[..n].iter().any(|x| *x != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Passes every intact record of `segment` to `apply` in order, checking
+/// that sequence numbers continue from `after` (`0` if unknown).
+///
+/// A file that does not start with [`MAGIC`] is refused as
+/// [`WalError::Format`] whatever `repair` says, as it is not damaged but
+/// written by another version.
+///
+/// A damaged last record of the active segment, or one followed only by
+/// zeros, is a write cut short by a crash and is truncated. Other damage,
+/// including anything wrong with a sealed segment, is reported as
+/// [`WalError::Corrupt`], unless `repair` is set, in which case the segment
+/// is truncated there as well.
+pub fn recover(
+    file: &File,
+    segment: &Segment,
+    after: u64,
+    repair: bool,
+    mut apply: impl FnMut(Record),
+) -> Result<Recovery, WalError> {
+    let len = file.metadata()?.len();
+    let mut r = BufReader::new(file);
+    r.seek(SeekFrom::Start(0))?;
+
+    let mut recovery = Recovery {
+        last_seq: after,
+        ..Default::default()
+    };
+    let mut magic = [0u8; MAGIC.len()];
+    match r.read_exact(&mut magic) {
+        Ok(()) if &magic == MAGIC => {}
+        // Created right before a crash, the writer starts it over.
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !segment.is_sealed() => {
+            if len > 0 {
+                file.set_len(0)?;
+                file.sync_data()?;
+                recovery.truncated = Some((len, "torn header".to_owned()));
+            }
+            return Ok(recovery);
+        }
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err.into()),
+        _ if magic.starts_with(b"PTWAL") => {
+            let reason = format!("unknown format {}", String::from_utf8_lossy(&magic));
+            return Err(WalError::Format(reason));
+        }
+        _ => {
+            let reason = "no header, the log was written before it was versioned".to_owned();
+            return Err(WalError::Format(reason));
+        }
+    }
+    let mut offset = MAGIC.len() as u64;
+    let damage = loop {
+        match read_record(&mut r, len - offset) {
+            Ok(None) => match segment.last_seq {
+                Some(x) if x != recovery.last_seq => {
+                    let reason =
+                        format!("sealed at sequence {} but ends at {}", x, recovery.last_seq);
+                    break Damage::Invalid(reason, None);
+                }
+                _ => return Ok(recovery),
+            },
+            Ok(Some((x, _))) if recovery.last_seq != 0 && x.seq != recovery.last_seq + 1 => {
+                let reason = format!("sequence {} follows {}", x.seq, recovery.last_seq);
+                break Damage::Invalid(reason, None);
+            }
+            Ok(Some((x, size))) => {
+                recovery.records += 1;
+                recovery.last_seq = x.seq;
+                offset += size;
+                apply(x);
+            }
+            Err(damage) => break damage,
+        }
+    };
+
+    let reason = match damage {
+        Damage::Io(err) => return Err(err.into()),
+        Damage::Torn | Damage::Invalid(_, _) if segment.is_sealed() && !repair => {
+            let reason = match damage {
+                Damage::Invalid(reason, _) => reason,
+                _ => "truncated record".to_owned(),
+            };
+            return Err(WalError::Corrupt { offset, reason });
+        }
+        Damage::Torn => "torn write".to_owned(),
+        Damage::Invalid(reason, Some(size)) if offset + size == len => {
+            format!("torn write ({})", reason)
+        }
+        Damage::Invalid(reason, _) if zeroed_from(file, offset)? => {
+            format!("torn write ({})", reason)
+        }
+        Damage::Invalid(reason, _) if repair => reason,
+        Damage::Invalid(reason, _) => return Err(WalError::Corrupt { offset, reason }),
+    };
+    file.set_len(offset)?;
+    file.sync_data()?;
+    recovery.truncated = Some((len - offset, format!("{} at offset {}", reason, offset)));
+    Ok(recovery)
+}
+
+/// Creates the WAL directory if needed and takes its `LOCK` file, which is
+/// held for as long as the returned file stays open.
+///
+/// The lock file is rewritten with our pid and synced, so a directory that
+/// is read-only, not ours or on a full disk is reported before we serve.
+pub fn init_dir(dir: &str) -> Result<File, WalError> {
+    let fail = |path: &Path, err| WalError::Dir(path.to_owned(), err);
+    fs::create_dir_all(dir).map_err(|err| fail(Path::new(dir), err))?;
+
+    let path = Path::new(dir).join("LOCK");
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|err| fail(&path, err))?;
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(fail(&path, err));
+        }
+        let mut owner = String::new();
+        let _ = f.read_to_string(&mut owner);
+        return Err(WalError::Locked(path, owner.trim().to_owned()));
+    }
+
+    f.set_len(0)
+        .and_then(|_| f.write_all(format!("pid {}\n", process::id()).as_bytes()))
+        .and_then(|_| f.sync_all())
+        .map_err(|err| fail(&path, err))?;
+    Ok(f)
+}
+
+/// Passes the commands of a version 1 log, the headerless `wal.log` of
+/// earlier releases, to `apply`. Its records are `[length u64][payload]`
+/// without checksums or sequence numbers. Returns the bytes of an incomplete
+/// last record, left behind by a crash.
+pub fn read_v1(file: &File, mut apply: impl FnMut(RequestCommand)) -> Result<u64, WalError> {
+    let len = file.metadata()?.len();
+    let mut r = BufReader::new(file);
+    let mut offset = 0;
+    while len - offset >= 8 {
+        let mut prefix = [0u8; 8];
+        r.read_exact(&mut prefix)?;
+        let size = u64::from_le_bytes(prefix);
+        if size > MAX_FRAME_SIZE as u64 {
+            let reason = format!("record length {} at offset {} is too large", size, offset);
+            return Err(WalError::Format(reason));
+        }
+        if 8 + size > len - offset {
+            break;
+        }
+        let mut payload = vec![0u8; size as usize];
+        r.read_exact(&mut payload)?;
+        let command = bincode::deserialize(&payload).map_err(|err| {
+            WalError::Format(format!("undecodable record at offset {}: {}", offset, err))
+        })?;
+        apply(command);
+        offset += 8 + size;
+    }
+    Ok(len - offset)
+}
+
+/// Creates the archive directory if needed and checks that segments can be
+/// copied into it, so a bad path is reported before we serve rather than on
+/// every compaction.
+pub fn init_archive(dir: &str) -> Result<(), WalError> {
+    let fail = |path: &Path, err| WalError::Dir(path.to_owned(), err);
+    fs::create_dir_all(dir).map_err(|err| fail(Path::new(dir), err))?;
+    let probe = Path::new(dir).join("probe.tmp");
+    File::create(&probe)
+        .and_then(|x| x.sync_all())
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| fail(&probe, err))
+}
+
+/// Starts a log file with [`MAGIC`] if nothing was written to it yet, and
+/// returns its size.
+pub fn write_header(file: &File) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    if len > 0 {
+        return Ok(len);
+    }
+    let mut w = file;
+    w.write_all(MAGIC)?;
+    Ok(MAGIC.len() as u64)
+}
+
+/// Makes a rename or creation of `path` durable in its directory.
+pub fn sync_dir(path: impl AsRef<Path>) -> io::Result<()> {
+    let dir = match path.as_ref().parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Passes the records of a segment that may still be appended to, stopping
+/// quietly at the first one that is incomplete or damaged.
+pub fn scan(file: &File, mut f: impl FnMut(Record)) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let mut r = BufReader::new(file);
+    let mut magic = [0u8; MAGIC.len()];
+    match r.read_exact(&mut magic) {
+        Ok(()) if &magic == MAGIC => {}
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err),
+        _ => return Ok(()),
+    }
+    let mut offset = MAGIC.len() as u64;
+    loop {
+        match read_record(&mut r, len - offset) {
+            Ok(Some((x, size))) => {
+                offset += size;
+                f(x);
+            }
+            Ok(None) | Err(Damage::Torn) | Err(Damage::Invalid(_, _)) => return Ok(()),
+            Err(Damage::Io(err)) => return Err(err),
+        }
+    }
+}
+
+/// When the WAL writer forces records to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WalSync {
+    /// fsync before a write is acknowledged, one fsync per batch of concurrent writes
+    Always,
+    /// fsync at most once per second
+    Everysec,
+    /// Only hand records to the operating system
+    None,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    pub sync: WalSync,
+    /// Size after which the active segment is sealed and a new one started.
+    pub segment_size: u64,
+    /// Sealed segments kept after a snapshot covers them.
+    pub retain_segments: usize,
+    /// Where sealed segments are copied before they may be deleted.
+    pub archive_dir: Option<String>,
+}
+
+/// Records of one write, with where to report once they are durable.
+pub struct Append {
+    pub records: Vec<Record>,
+    pub synced: Option<Sender<Result<(), String>>>,
+}
+
+pub enum WalMessage {
+    Append(Append),
+    /// Seals the active segment once the records sent before it are
+    /// written.
+    Seal(Sender<io::Result<()>>),
+    /// Forces every record sent before it to disk, whatever the sync policy.
+    Sync(Sender<Result<(), String>>),
+}
+
+/// Appends to the active segment on a thread of its own.
+pub struct Writer {
+    dir: String,
+    manifest: Arc<Mutex<Manifest>>,
+    options: WalOptions,
+    /// Opened on the first write after a segment was sealed.
+    w: Option<BufWriter<File>>,
+    /// Bytes in the active segment.
+    written: u64,
+    /// Sequence number of the last record written, which the segment ends
+    /// at when sealed. Records are numbered before they are sent here, so
+    /// the next number says nothing about what was written.
+    last: u64,
+    last_sync: Instant,
+    /// Whether records were written since the last fsync.
+    dirty: bool,
+}
+
+impl Writer {
+    pub fn new(dir: &str, manifest: Arc<Mutex<Manifest>>, options: WalOptions) -> Self {
+        Writer {
+            dir: dir.to_owned(),
+            manifest,
+            options,
+            w: None,
+            written: 0,
+            last: 0,
+            last_sync: Instant::now(),
+            dirty: false,
+        }
+    }
+
+    pub fn run(mut self, rx: Receiver<WalMessage>) {
+        loop {
+            // Wake up at least once a second so `everysec` syncs an idle log too.
+            let first = match rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(x) => Some(x),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // Whatever queued up meanwhile shares this flush and fsync.
+            let mut batch = Vec::new();
+            for x in first.into_iter().chain(rx.try_iter()) {
+                match x {
+                    WalMessage::Append(x) => batch.push(x),
+                    WalMessage::Seal(reply) => {
+                        self.commit(std::mem::take(&mut batch), true);
+                        let _ = reply.send(self.seal());
+                    }
+                    WalMessage::Sync(reply) => {
+                        batch.push(Append {
+                            records: Vec::new(),
+                            synced: Some(reply),
+                        });
+                        self.commit(std::mem::take(&mut batch), true);
+                    }
+                }
+            }
+
+            let due = match self.options.sync {
+                WalSync::Always => !batch.is_empty(),
+                WalSync::Everysec => {
+                    (self.dirty || !batch.is_empty())
+                        && self.last_sync.elapsed() >= Duration::from_secs(1)
+                }
+                WalSync::None => false,
+            };
+            self.commit(batch, due);
+            if self.written >= self.options.segment_size {
+                if let Err(err) = self.seal() {
+                    eprintln!("[WAL] Failed to seal segment: {}", err);
+                }
+            }
+        }
+        self.commit(Vec::new(), self.options.sync != WalSync::None);
+    }
+
+    /// Writes `batch` and tells the writers waiting on it how it went.
+    fn commit(&mut self, batch: Vec<Append>, sync: bool) {
+        self.dirty |= !batch.is_empty();
+        let res = self.write_batch(&batch, sync);
+        if sync && res.is_ok() {
+            self.last_sync = Instant::now();
+            self.dirty = false;
+        }
+        if let Err(err) = &res {
+            eprintln!("[WAL] Failed to write records: {}", err);
+        }
+        for x in batch {
+            if let Some(synced) = x.synced {
+                let _ = synced.send(res.as_ref().map_err(|x| x.to_string()).copied());
+            }
+        }
+    }
+
+    /// Appends the records of `batch` and hands them to the operating
+    /// system, forcing them to disk as well if `sync` is set.
+    fn write_batch(&mut self, batch: &[Append], sync: bool) -> io::Result<()> {
+        let mut records = batch.iter().flat_map(|x| &x.records).peekable();
+        if let Some(x) = records.peek().filter(|_| self.w.is_none()) {
+            self.open_segment(x.seq)?;
+        }
+        let Some(w) = self.w.as_mut() else {
+            return Ok(());
+        };
+        for x in records {
+            let buf = encode(x.seq, &x.command);
+            w.write_all(&buf)?;
+            self.written += buf.len() as u64;
+            self.last = x.seq;
+        }
+        w.flush()?;
+        if sync {
+            w.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Reopens the unsealed segment left from the last run, or lists and
+    /// creates a new one starting at `first_seq`.
+    fn open_segment(&mut self, first_seq: u64) -> io::Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let segment = match manifest.active() {
+            Some(x) => x.clone(),
+            None => {
+                let segment = Segment {
+                    first_seq,
+                    last_seq: None,
+                    archived: false,
+                };
+                manifest.segments.push(segment.clone());
+                manifest.save(&self.dir)?;
+                segment
+            }
+        };
+        let path = Path::new(&self.dir).join(segment.file_name());
+        let f = OpenOptions::new().create(true).append(true).open(&path)?;
+        sync_dir(&path)?;
+        self.written = write_header(&f)?;
+        self.w = Some(BufWriter::new(f));
+        Ok(())
+    }
+
+    /// Makes the active segment durable and marks it sealed, so the next
+    /// write starts a new one.
+    fn seal(&mut self) -> io::Result<()> {
+        if let Some(mut w) = self.w.take() {
+            w.flush()?;
+            w.get_ref().sync_data()?;
+            let mut manifest = self.manifest.lock().unwrap();
+            if let Some(x) = manifest.segments.last_mut() {
+                x.last_seq = Some(self.last);
+            }
+            manifest.save(&self.dir)?;
+            self.written = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use super::*;
+
+    /// An empty directory for one test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("plaintcp-wal-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn seals_at_the_last_record_written_while_appends_are_in_flight() {
+        let dir = TempDir::new("seal");
+        let manifest = Arc::new(Mutex::new(Manifest::default()));
+        let options = WalOptions {
+            sync: WalSync::None,
+            // Every batch fills a segment, which is sealed right after.
+            segment_size: 1,
+            retain_segments: 0,
+            archive_dir: None,
+        };
+        let (tx, rx) = channel();
+        let writer = Writer::new(dir.path(), manifest.clone(), options);
+        let writer = thread::spawn(move || writer.run(rx));
+
+        // Numbered and sent in order under one lock, as the log does, but
+        // numbered a moment before being sent.
+        let next = Arc::new(AtomicU64::new(1));
+        let order = Arc::new(Mutex::new(()));
+        let appenders: Vec<_> = (0..4)
+            .map(|_| {
+                let (tx, next, order) = (tx.clone(), next.clone(), order.clone());
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let _order = order.lock().unwrap();
+                        let seq = next.fetch_add(1, Ordering::SeqCst);
+                        thread::yield_now();
+                        let records = vec![Record {
+                            seq,
+                            command: RequestCommand::Delete(seq.to_string()),
+                        }];
+                        let append = Append {
+                            records,
+                            synced: None,
+                        };
+                        tx.send(WalMessage::Append(append)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for x in appenders {
+            x.join().unwrap();
+        }
+        drop(tx);
+        writer.join().unwrap();
+
+        let manifest = manifest.lock().unwrap();
+        assert!(manifest.segments.iter().filter(|x| x.is_sealed()).count() > 1);
+        let (mut after, mut records) = (0, 0);
+        for segment in &manifest.segments {
+            let f = File::open(Path::new(dir.path()).join(segment.file_name())).unwrap();
+            let recovery = recover(&f, segment, after, false, |_| {}).unwrap();
+            (after, records) = (recovery.last_seq, records + recovery.records);
+        }
+        assert_eq!((after, records), (200, 200));
+    }
+
+    /// A segment file holding `MAGIC` and then `records`, each of them
+    /// [`encode`]d.
+    fn segment_file(dir: &TempDir, records: &[Vec<u8>]) -> File {
+        file_with(dir, &[&MAGIC[..], &records.concat()].concat())
+    }
+
+    fn file_with(dir: &TempDir, bytes: &[u8]) -> File {
+        let path = Path::new(dir.path()).join("segment.log");
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        f.write_all(bytes).unwrap();
+        f
+    }
+
+    fn records(n: u64) -> Vec<Vec<u8>> {
+        (1..=n)
+            .map(|seq| encode(seq, &RequestCommand::Delete(format!("key{}", seq))))
+            .collect()
+    }
+
+    const ACTIVE: Segment = Segment {
+        first_seq: 1,
+        last_seq: None,
+        archived: false,
+    };
+
+    #[test]
+    fn truncates_a_torn_last_record() {
+        let dir = TempDir::new("torn");
+        let records = records(3);
+        let f = segment_file(&dir, &records);
+        let intact = (MAGIC.len() + records[0].len() + records[1].len()) as u64;
+        f.set_len(intact + 5).unwrap();
+
+        let mut seen = Vec::new();
+        let recovery = recover(&f, &ACTIVE, 0, false, |x| seen.push(x.seq)).unwrap();
+        assert_eq!(seen, [1, 2]);
+        assert_eq!((recovery.records, recovery.last_seq), (2, 2));
+        let expected = format!("torn write at offset {}", intact);
+        assert_eq!(recovery.truncated, Some((5, expected)));
+        assert_eq!(f.metadata().unwrap().len(), intact);
+
+        // Only a sealed segment cannot have been cut short by a crash.
+        let f = segment_file(&dir, &records);
+        f.set_len(intact + 5).unwrap();
+        let sealed = Segment {
+            last_seq: Some(3),
+            ..ACTIVE
+        };
+        match recover(&f, &sealed, 0, false, |_| {}) {
+            Err(WalError::Corrupt { offset, reason }) => {
+                assert_eq!((offset, reason.as_str()), (intact, "truncated record"));
+            }
+            x => panic!("recovered {:?}", x),
+        }
+    }
+
+    #[test]
+    fn refuses_a_checksum_mismatch_before_intact_records() {
+        let dir = TempDir::new("crc");
+        let mut records = records(3);
+        let last = records[1].len() - 1;
+        records[1][last] ^= 0xff;
+        let f = segment_file(&dir, &records);
+        let damaged = (MAGIC.len() + records[0].len()) as u64;
+
+        match recover(&f, &ACTIVE, 0, false, |_| {}) {
+            Err(WalError::Corrupt { offset, reason }) => {
+                assert_eq!((offset, reason.as_str()), (damaged, "checksum mismatch"));
+            }
+            x => panic!("recovered {:?}", x),
+        }
+        let len = f.metadata().unwrap().len();
+        assert_eq!(len, damaged + (records[1].len() + records[2].len()) as u64);
+    }
+
+    #[test]
+    fn repair_truncates_at_the_first_damaged_record() {
+        let dir = TempDir::new("repair");
+        let mut records = records(3);
+        let last = records[1].len() - 1;
+        records[1][last] ^= 0xff;
+        let f = segment_file(&dir, &records);
+        let damaged = (MAGIC.len() + records[0].len()) as u64;
+        let len = f.metadata().unwrap().len();
+
+        let mut seen = Vec::new();
+        let recovery = recover(&f, &ACTIVE, 0, true, |x| seen.push(x.seq)).unwrap();
+        assert_eq!(seen, [1]);
+        let expected = format!("checksum mismatch at offset {}", damaged);
+        assert_eq!(recovery.truncated, Some((len - damaged, expected)));
+        assert_eq!(f.metadata().unwrap().len(), damaged);
+
+        let recovery = recover(&f, &ACTIVE, 0, false, |_| {}).unwrap();
+        assert_eq!((recovery.records, recovery.truncated), (1, None));
+    }
+
+    #[test]
+    fn refuses_logs_of_other_formats() {
+        let dir = TempDir::new("format");
+        let f = file_with(&dir, &[&b"PTWAL009"[..], &records(1)[0]].concat());
+        match recover(&f, &ACTIVE, 0, true, |_| {}) {
+            Err(WalError::Format(reason)) => assert_eq!(reason, "unknown format PTWAL009"),
+            x => panic!("recovered {:?}", x),
+        }
+
+        // A version 1 log, without a header, is not taken for a damaged one.
+        let f = file_with(&dir, &[&17u64.to_le_bytes()[..], &[0u8; 17]].concat());
+        match recover(&f, &ACTIVE, 0, true, |_| {}) {
+            Err(WalError::Format(reason)) => assert!(reason.starts_with("no header")),
+            x => panic!("recovered {:?}", x),
+        }
+        assert_eq!(f.metadata().unwrap().len(), 8 + 17);
+    }
+}
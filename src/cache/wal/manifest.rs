@@ -0,0 +1,77 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::wal::{sync_dir, WalError};
+
+/// File listing the segments of a WAL directory.
+const MANIFEST: &str = "MANIFEST";
+
+/// One file of the log, named after the sequence number of its first record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub first_seq: u64,
+    /// Sequence number of the last record, set once the segment is sealed
+    /// and will not be written again.
+    pub last_seq: Option<u64>,
+    /// Whether a copy was placed in the archive directory.
+    #[serde(default)]
+    pub archived: bool,
+}
+
+impl Segment {
+    pub fn file_name(&self) -> String {
+        format!("{:020}.log", self.first_seq)
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.last_seq.is_some()
+    }
+}
+
+/// What the WAL directory holds, rewritten whole on every change.
+///
+/// A segment is listed before its file is created, so a listed file may be
+/// missing after a crash but a record is never written to an unlisted one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Sequence number covered by the snapshot, `0` without one.
+    pub snapshot_seq: u64,
+    /// Oldest first; only the last one may be unsealed.
+    pub segments: Vec<Segment>,
+}
+
+impl Manifest {
+    /// Reads the manifest of `dir`, empty if there is none yet.
+    pub fn load(dir: &str) -> Result<Self, WalError> {
+        let path = Path::new(dir).join(MANIFEST);
+        match fs::read(&path) {
+            Ok(buf) => serde_json::from_slice(&buf).map_err(|err| WalError::Corrupt {
+                offset: err.column() as u64,
+                reason: format!("{}: {}", path.display(), err),
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replaces the manifest of `dir` atomically.
+    pub fn save(&self, dir: &str) -> io::Result<()> {
+        let path = Path::new(dir).join(MANIFEST);
+        let tmp = path.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec_pretty(self)?)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&path)
+    }
+
+    /// The segment records are appended to, if it is not sealed yet.
+    pub fn active(&self) -> Option<&Segment> {
+        self.segments.last().filter(|x| !x.is_sealed())
+    }
+}
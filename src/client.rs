@@ -1,6 +1,10 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::io;
 use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
 use std::str::FromStr;
+use std::sync::Mutex;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
 
@@ -9,127 +13,623 @@ use promkit::suggest::Suggest;
 use rand::distributions::{Alphanumeric, DistString};
 use regex::Regex;
 
-use proto::Frame;
-
+use crate::cache::cluster::{slot, SlotMap};
 use crate::cli::Args;
-use crate::proto;
-use crate::proto::RequestCommand;
+use crate::proto::{
+    ErrorCode, Feature, Frame, FrameDecoder, ProtoError, RequestCommand, Response, FEATURES,
+    MIN_VERSION, VERSION,
+};
 
-pub fn start(args: &Args) -> Result<(), Box<dyn Error>> {
-    let addr = args.addr.clone();
+/// Largest number of requests written before their responses are read back.
+const PIPELINE_WINDOW: usize = 1024;
+
+/// Redirects a request follows before its last answer is returned as is.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long connecting, or writing a request or waiting for an answer, may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Blocking connection that tags every request with its own id.
+pub struct Client {
+    con: std::net::TcpStream,
+    decoder: FrameDecoder,
+    next_id: u64,
+    /// Protocol version and features agreed on in the handshake.
+    version: u8,
+    features: Vec<Feature>,
+}
+
+impl Client {
+    pub fn connect(addr: &str) -> Result<Self, ProtoError> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address did not resolve"))?;
+        let con = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        con.set_nodelay(true)?;
+        con.set_read_timeout(Some(TIMEOUT))?;
+        con.set_write_timeout(Some(TIMEOUT))?;
+        let mut client = Client {
+            con,
+            decoder: FrameDecoder::new(),
+            next_id: 1,
+            version: MIN_VERSION,
+            features: Vec::new(),
+        };
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// Agrees on a version and features with the server. Servers that predate
+    /// the handshake answer with a protocol error and are spoken to in
+    /// [`MIN_VERSION`] without any features.
+    fn handshake(&mut self) -> Result<(), ProtoError> {
+        let hello = RequestCommand::Hello(MIN_VERSION, VERSION, FEATURES.to_vec());
+        (&self.con).write_all(&Vec::<u8>::from(Frame::new(0, hello)))?;
+
+        let frame: Frame = self
+            .decoder
+            .read_frame(&self.con)?
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        match frame.into() {
+            RequestCommand::Recv(Response::Hello(version, features)) => {
+                self.version = version;
+                self.features = features;
+            }
+            RequestCommand::Recv(Response::Error(ErrorCode::UnsupportedVersion, message)) => {
+                return Err(ProtoError::Handshake(message));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    pub fn execute(&mut self, request: RequestCommand) -> Result<Response, ProtoError> {
+        let mut res = self.pipeline(vec![request])?;
+        Ok(res.remove(0))
+    }
+
+    /// Sends all `requests` without waiting in between and returns their
+    /// responses in request order, matched up by id.
+    pub fn pipeline(&mut self, requests: Vec<RequestCommand>) -> Result<Vec<Response>, ProtoError> {
+        let window = if self.features.contains(&Feature::Pipelining) {
+            PIPELINE_WINDOW
+        } else {
+            1
+        };
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut requests = requests.into_iter().peekable();
+
+        while requests.peek().is_some() {
+            let mut buf = Vec::new();
+            let mut pending = HashMap::new();
+            for (i, request) in requests.by_ref().take(window).enumerate() {
+                let id = self.next_id;
+                self.next_id += 1;
+                pending.insert(id, i);
+                buf.extend(Vec::<u8>::from(
+                    Frame::new(id, request).with_version(self.version),
+                ));
+            }
+            (&self.con).write_all(&buf)?;
+
+            let mut window = vec![None; pending.len()];
+            while !pending.is_empty() {
+                let frame: Frame = self
+                    .decoder
+                    .read_frame(&self.con)?
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                let i = pending
+                    .remove(&frame.id())
+                    .ok_or(ProtoError::UnexpectedFrame(frame.id()))?;
+                window[i] = Some(match frame.into() {
+                    RequestCommand::Recv(res) => res,
+                    x => Response::error(ErrorCode::Protocol, format!("unexpected reply {}", x)),
+                });
+            }
+            responses.extend(window.into_iter().flatten());
+        }
+
+        Ok(responses)
+    }
+}
+
+/// Connections to the nodes of a cluster, sending every request for a key
+/// straight to the node owning its slot. The slot map is cached and fetched
+/// again whenever a node answers with a redirect; a server that is not in
+/// cluster mode has no map, and everything goes to it.
+pub struct ClusterClient {
+    seed: String,
+    slots: SlotMap,
+    nodes: HashMap<String, Client>,
+}
+
+impl ClusterClient {
+    pub fn connect(addr: &str) -> Result<Self, ProtoError> {
+        let mut client = ClusterClient {
+            seed: addr.to_owned(),
+            slots: SlotMap::default(),
+            nodes: HashMap::from([(addr.to_owned(), Client::connect(addr)?)]),
+        };
+        client.refresh()?;
+        Ok(client)
+    }
+
+    /// The connection to the node first connected to.
+    pub fn seed(&self) -> &Client {
+        &self.nodes[&self.seed]
+    }
+
+    /// Fetches the slot map from the seed node.
+    pub fn refresh(&mut self) -> Result<(), ProtoError> {
+        let seed = self.seed.clone();
+        let res = self.node(&seed)?.execute(RequestCommand::Slots)?;
+        self.slots = SlotMap::from_response(&res).unwrap_or_default();
+        Ok(())
+    }
+
+    fn node(&mut self, addr: &str) -> Result<&mut Client, ProtoError> {
+        if !self.nodes.contains_key(addr) {
+            let client = Client::connect(addr)?;
+            self.nodes.insert(addr.to_owned(), client);
+        }
+        Ok(self.nodes.get_mut(addr).unwrap())
+    }
+
+    /// Sends `request` to the node owning its key, following `MOVED`, `ASK`
+    /// and `NOTLEADER` redirects.
+    pub fn execute(&mut self, request: RequestCommand) -> Result<Response, ProtoError> {
+        // Migrations are started, resumed and rolled back by the slots' owner.
+        let slot = match &request {
+            RequestCommand::Migrate(start, _, _) => Some(*start),
+            x => x.key().map(slot),
+        };
+        let mut addr = slot
+            .and_then(|x| self.slots.owner(x))
+            .unwrap_or(&self.seed)
+            .to_owned();
+        let mut attempt = request.clone();
+        for _ in 0..MAX_REDIRECTS {
+            let res = self.node(&addr)?.execute(attempt)?;
+            attempt = request.clone();
+            addr = match &res {
+                Response::Error(ErrorCode::Moved, message) => match message.split_once(' ') {
+                    Some((_, owner)) => {
+                        self.refresh()?;
+                        owner.to_owned()
+                    }
+                    None => return Ok(res),
+                },
+                // Only this request goes there; the slot map stays as it is
+                // until the migration completes.
+                Response::Error(ErrorCode::Ask, message) => match message.split_once(' ') {
+                    Some((_, node)) => {
+                        attempt = RequestCommand::Asking(Box::new(request.clone()));
+                        node.to_owned()
+                    }
+                    None => return Ok(res),
+                },
+                Response::Error(ErrorCode::NotLeader, leader) if !leader.contains(' ') => {
+                    leader.clone()
+                }
+                _ => return Ok(res),
+            };
+        }
+        self.node(&addr)?.execute(attempt)
+    }
+}
+
+/// Blocking connection between servers, opened on first use and again after
+/// any failure, so a late answer is never taken for the next one.
+#[derive(Debug)]
+pub struct Peer {
+    pub addr: String,
+    timeout: Duration,
+    conn: Mutex<Option<(TcpStream, FrameDecoder)>>,
+}
 
-    let con = std::net::TcpStream::connect(addr).unwrap();
-    con.set_nodelay(true)?;
+impl Peer {
+    /// A peer at `addr` that must connect and answer within `timeout`.
+    pub fn new(addr: &str, timeout: Duration) -> Self {
+        Peer {
+            addr: addr.to_owned(),
+            timeout,
+            conn: Mutex::new(None),
+        }
+    }
+
+    pub fn call(&self, command: RequestCommand) -> io::Result<Response> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.is_none() {
+            let addr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "address did not resolve")
+            })?;
+            let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            *conn = Some((stream, FrameDecoder::new()));
+        }
+        let (stream, decoder) = conn.as_mut().unwrap();
+
+        let res = (|| {
+            let buf: Vec<u8> = Frame::new(1, command).into();
+            (&*stream).write_all(&buf)?;
+            let frame = decoder
+                .read_frame::<_, Frame>(&*stream)
+                .map_err(io::Error::other)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            match RequestCommand::from(frame) {
+                RequestCommand::Recv(x) => Ok(x),
+                x => Err(io::Error::other(format!("unexpected reply {}", x))),
+            }
+        })();
+        if res.is_err() {
+            *conn = None;
+        }
+        res
+    }
+}
+
+pub fn start(args: &Args) -> Result<(), Box<dyn Error>> {
+    let mut client = Client::connect(&args.addr)?;
 
     loop {
         let str = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
 
         let t = SystemTime::now();
-        proto::encode(
-            &con,
-            &Frame::new(RequestCommand::Set(str.clone(), str.clone().into_bytes())),
-        )?;
-        let res = proto::decode(&con)?;
-        println!("Response ({:?}) : {:?}", t.elapsed().unwrap(), res.unwrap());
-        let t = SystemTime::now();
-        proto::encode(&con, &Frame::new(RequestCommand::Get(str.clone())))?;
-        let res = proto::decode(&con)?;
-        println!("Response ({:?}) : {:?}", t.elapsed().unwrap(), res.unwrap());
-        // let t = SystemTime::now();
-        // proto::encode(&con, &Frame::new(RequestCommand::Delete(str.clone())))?;
-        // let res = proto::decode(&con)?;
-        // println!("Response ({:?}) : {:?}", t.elapsed().unwrap(), res.unwrap());
+        let res = client.pipeline(vec![
+            RequestCommand::Set(str.clone(), str.clone().into_bytes()),
+            RequestCommand::Get(str.clone()),
+        ])?;
+        println!("Response ({:?}) : {:?}", t.elapsed().unwrap(), res);
         sleep(Duration::from_millis(200));
     }
 }
 
-fn execute_request(
-    con: &std::net::TcpStream,
-    frame: &RequestCommand,
-) -> Result<Option<RequestCommand>, std::io::Error> {
-    let mut con = con;
-    let buf: Vec<u8> = Frame::new(frame.clone()).into();
-    con.write(&*buf).expect("[Request] Error:");
-    let res: Option<Frame> = proto::deserialize(con).expect("[Response] Error:");
-    Ok(Some(res.unwrap().into()))
-}
-
 pub fn interactive(args: &Args) -> Result<(), Box<dyn Error>> {
-    let addr = args.addr.clone();
-
-    let con = std::net::TcpStream::connect(addr).unwrap();
-    con.set_nodelay(true)?;
+    let mut client = ClusterClient::connect(&args.addr)?;
+    println!(
+        "Connected to {} (protocol v{}, {:?})",
+        args.addr,
+        client.seed().version(),
+        client.seed().features()
+    );
+    let get = Regex::new(r"^GET (\w*)").unwrap();
+    let set = Regex::new(r"^SET (\w*) (.*)").unwrap();
+    let delete = Regex::new(r"^DELETE (\w*)").unwrap();
+    let keys = Regex::new(r"^KEYS (\d+) (\d+)").unwrap();
+    let setex = Regex::new(r"^SETEX (\w*) (\d+) (.*)").unwrap();
+    let expire = Regex::new(r"^EXPIRE (\w*) (\d+)").unwrap();
+    let ttl = Regex::new(r"^TTL (\w*)").unwrap();
+    let persist = Regex::new(r"^PERSIST (\w*)").unwrap();
+    let replica_of = Regex::new(r"^REPLICAOF (\S+)").unwrap();
+    let migrate = Regex::new(r"^MIGRATE (\d+) (\d+) (\S+)").unwrap();
+    let counter_incrby = Regex::new(r"^COUNTER\.INCRBY (\w*) (-?\d+)").unwrap();
+    let counter_get = Regex::new(r"^COUNTER\.GET (\w*)").unwrap();
+    let orset = Regex::new(r"^ORSET\.(ADD|REM) (\w*) (.+)").unwrap();
+    let orset_members = Regex::new(r"^ORSET\.MEMBERS (\w*)").unwrap();
+    let register_set = Regex::new(r"^REGISTER\.SET (\w*) (.*)").unwrap();
+    let register_get = Regex::new(r"^REGISTER\.GET (\w*)").unwrap();
     let mut p = Readline::default()
-        .enable_suggest(Suggest::from_iter(["GET", "SET", "DELETE", "KEYS"]))
+        .enable_suggest(Suggest::from_iter([
+            "GET",
+            "SET",
+            "SETEX",
+            "DELETE",
+            "KEYS",
+            "EXPIRE",
+            "TTL",
+            "PERSIST",
+            "REPLICAS",
+            "REPLICAOF",
+            "PROMOTE",
+            "MIGRATE",
+            "MEMBERS",
+            "COUNTER.INCRBY",
+            "COUNTER.GET",
+            "ORSET.ADD",
+            "ORSET.REM",
+            "ORSET.MEMBERS",
+            "REGISTER.SET",
+            "REGISTER.GET",
+        ]))
         .enable_history()
         .prompt()?;
 
     loop {
         let res = p.run()?;
         let result = match res.as_str() {
-            x if Regex::new("^GET").unwrap().is_match(x) => {
-                match Regex::new(r"^GET (\w*)").unwrap().captures(x) {
-                    Some(x) => {
-                        let key = x.get(1).unwrap().as_str();
-                        execute_request(&con, &RequestCommand::Get(key.to_owned()))
-                            .expect("Failed to connect to remote")
-                    }
-                    None => {
-                        println!("GET <key>");
-                        None
-                    }
+            x if x.starts_with("GET") => match get.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Get(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
                 }
-            }
-            x if Regex::new("^SET").unwrap().is_match(x) => {
-                match Regex::new(r"^SET (\w*) (.*)").unwrap().captures(x) {
-                    Some(x) => {
-                        let key = x.get(1).unwrap().as_str();
-                        let body = x.get(2).unwrap().as_str();
-                        execute_request(
-                            &con,
-                            &RequestCommand::Set(key.to_owned(), body.as_bytes().into()),
-                        )
-                        .expect("Failed to connect to remote")
-                    }
-                    None => {
-                        println!("SET <key> <data>");
-                        None
-                    }
+                None => {
+                    println!("GET <key>");
+                    None
                 }
-            }
-            x if Regex::new("^DELETE").unwrap().is_match(x) => {
-                match Regex::new(r"^DELETE (\w*)").unwrap().captures(x) {
-                    Some(x) => {
-                        let key = x.get(1).unwrap().as_str();
-                        execute_request(&con, &RequestCommand::Delete(key.to_owned()))
-                            .expect("Failed to connect to remote")
-                    }
-                    None => {
-                        println!("DELETE <key>");
-                        None
-                    }
+            },
+            x if x.starts_with("SETEX") => match setex.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    let secs = x.get(2).unwrap().as_str();
+                    let body = x.get(3).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::SetEx(
+                                key.to_owned(),
+                                body.as_bytes().into(),
+                                u64::from_str(secs).unwrap(),
+                            ))
+                            .expect("Failed to connect to remote"),
+                    )
                 }
-            }
-            x if Regex::new(r"^KEYS").unwrap().is_match(x) => {
-                match Regex::new(r"^KEYS (\d+) (\d+)").unwrap().captures(x) {
-                    Some(x) => {
-                        let take = x.get(1).unwrap().as_str();
-                        let skip = x.get(2).unwrap().as_str();
-                        execute_request(
-                            &con,
-                            &RequestCommand::Keys(
+                None => {
+                    println!("SETEX <key> <seconds> <data>");
+                    None
+                }
+            },
+            x if x.starts_with("SET") => match set.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    let body = x.get(2).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Set(key.to_owned(), body.as_bytes().into()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("SET <key> <data>");
+                    None
+                }
+            },
+            x if x.starts_with("DELETE") => match delete.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Delete(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("DELETE <key>");
+                    None
+                }
+            },
+            x if x.starts_with("KEYS") => match keys.captures(x) {
+                Some(x) => {
+                    let take = x.get(1).unwrap().as_str();
+                    let skip = x.get(2).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Keys(
                                 usize::from_str(take).unwrap(),
                                 usize::from_str(skip).unwrap(),
-                            ),
-                        )
-                        .expect("Failed to connect to remote")
+                            ))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("KEYS <take> <skip>");
+                    None
+                }
+            },
+            x if x.starts_with("EXPIRE") => match expire.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    let secs = x.get(2).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Expire(
+                                key.to_owned(),
+                                u64::from_str(secs).unwrap(),
+                            ))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("EXPIRE <key> <seconds>");
+                    None
+                }
+            },
+            x if x.starts_with("TTL") => match ttl.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Ttl(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("TTL <key>");
+                    None
+                }
+            },
+            x if x.starts_with("PERSIST") => match persist.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::Persist(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("PERSIST <key>");
+                    None
+                }
+            },
+            "REPLICAS" => Some(
+                client
+                    .execute(RequestCommand::Replicas)
+                    .expect("Failed to connect to remote"),
+            ),
+            x if x.starts_with("REPLICAOF") => match replica_of.captures(x) {
+                Some(x) => {
+                    let addr = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::ReplicaOf(addr.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("REPLICAOF <addr>");
+                    None
+                }
+            },
+            "PROMOTE" => Some(
+                client
+                    .execute(RequestCommand::Promote)
+                    .expect("Failed to connect to remote"),
+            ),
+            "MEMBERS" => Some(
+                client
+                    .execute(RequestCommand::Members)
+                    .expect("Failed to connect to remote"),
+            ),
+            x if x.starts_with("MIGRATE") => match migrate.captures(x) {
+                Some(x) => {
+                    let start = x.get(1).unwrap().as_str();
+                    let end = x.get(2).unwrap().as_str();
+                    let addr = x.get(3).unwrap().as_str();
+                    match (u16::from_str(start), u16::from_str(end)) {
+                        (Ok(start), Ok(end)) => Some(
+                            client
+                                .execute(RequestCommand::Migrate(start, end, addr.to_owned()))
+                                .expect("Failed to connect to remote"),
+                        ),
+                        _ => {
+                            println!("Slots go from 0 to 16383");
+                            None
+                        }
                     }
-                    None => {
-                        println!("KEYS <take> <skip>");
-                        None
+                }
+                None => {
+                    println!("MIGRATE <start slot> <end slot> <addr>");
+                    None
+                }
+            },
+            x if x.starts_with("COUNTER.INCRBY") => match counter_incrby.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    match i64::from_str(x.get(2).unwrap().as_str()) {
+                        Ok(by) => Some(
+                            client
+                                .execute(RequestCommand::CounterAdd(key.to_owned(), by))
+                                .expect("Failed to connect to remote"),
+                        ),
+                        Err(_) => {
+                            println!("The increment does not fit in 64 bits");
+                            None
+                        }
                     }
                 }
-            }
+                None => {
+                    println!("COUNTER.INCRBY <key> <increment>");
+                    None
+                }
+            },
+            x if x.starts_with("COUNTER.GET") => match counter_get.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::CounterGet(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("COUNTER.GET <key>");
+                    None
+                }
+            },
+            x if x.starts_with("ORSET.MEMBERS") => match orset_members.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::SetMembers(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("ORSET.MEMBERS <key>");
+                    None
+                }
+            },
+            x if x.starts_with("ORSET.") => match orset.captures(x) {
+                Some(x) => {
+                    let key = x.get(2).unwrap().as_str().to_owned();
+                    let members = x
+                        .get(3)
+                        .unwrap()
+                        .as_str()
+                        .split_whitespace()
+                        .map(|x| x.as_bytes().to_vec())
+                        .collect();
+                    Some(
+                        client
+                            .execute(match x.get(1).unwrap().as_str() {
+                                "ADD" => RequestCommand::SetAdd(key, members),
+                                _ => RequestCommand::SetRemove(key, members),
+                            })
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("ORSET.ADD|ORSET.REM <key> <member>...");
+                    None
+                }
+            },
+            x if x.starts_with("REGISTER.SET") => match register_set.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    let body = x.get(2).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::RegisterSet(
+                                key.to_owned(),
+                                body.as_bytes().into(),
+                            ))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("REGISTER.SET <key> <data>");
+                    None
+                }
+            },
+            x if x.starts_with("REGISTER.GET") => match register_get.captures(x) {
+                Some(x) => {
+                    let key = x.get(1).unwrap().as_str();
+                    Some(
+                        client
+                            .execute(RequestCommand::RegisterGet(key.to_owned()))
+                            .expect("Failed to connect to remote"),
+                    )
+                }
+                None => {
+                    println!("REGISTER.GET <key>");
+                    None
+                }
+            },
             _ => None,
         };
 
@@ -0,0 +1,448 @@
+use std::io::Read;
+
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::proto::{ErrorCode, RequestCommand, Response, WriteConcern, MAX_FRAME_SIZE};
+
+/// Largest request line plus headers accepted.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Page size for `GET /keys` when no `limit` is given.
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+impl HttpRequest {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Problems that leave the stream unusable; the status is sent before closing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Incremental HTTP/1.1 request parser for bodies sent with `Content-Length`.
+#[derive(Debug, Default)]
+pub struct HttpDecoder {
+    buf: Vec<u8>,
+}
+
+impl HttpDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_from<R: Read>(&mut self, mut r: R) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 16 * 1024];
+        let n = r.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// Takes the next complete request out of the buffer, if there is one.
+    pub fn decode(&mut self) -> Result<Option<HttpRequest>, HttpError> {
+        let Some(head_end) = self.buf.windows(4).position(|x| x == b"\r\n\r\n") else {
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::new(431, "request header fields too large"));
+            }
+            return Ok(None);
+        };
+
+        let head = String::from_utf8_lossy(&self.buf[..head_end]).to_string();
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(HttpError::new(400, "malformed request line"));
+        };
+
+        let mut content_length = 0;
+        let mut keep_alive = version != "HTTP/1.0";
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(HttpError::new(400, "malformed header"));
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    content_length = value
+                        .parse()
+                        .map_err(|_| HttpError::new(400, "invalid content-length"))?;
+                }
+                "transfer-encoding" => {
+                    return Err(HttpError::new(501, "transfer-encoding is not supported"));
+                }
+                "connection" => keep_alive = value.eq_ignore_ascii_case("keep-alive"),
+                _ => {}
+            }
+        }
+        if content_length > MAX_FRAME_SIZE {
+            return Err(HttpError::new(413, "payload too large"));
+        }
+
+        let body_start = head_end + 4;
+        if self.buf.len() < body_start + content_length {
+            return Ok(None);
+        }
+        let body = self.buf[body_start..body_start + content_length].to_vec();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let request = HttpRequest {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: query
+                .split('&')
+                .filter(|x| !x.is_empty())
+                .map(|x| {
+                    let (k, v) = x.split_once('=').unwrap_or((x, ""));
+                    (percent_decode(k), percent_decode(v))
+                })
+                .collect(),
+            body,
+            keep_alive,
+        };
+        self.buf.drain(..body_start + content_length);
+        Ok(Some(request))
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (s[i], hex) {
+            (b'%', Some(x)) => {
+                out.push(x);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (x, _) => {
+                out.push(x);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Writes a complete response with the given status, content type and body.
+pub fn write_response(
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+    keep_alive: bool,
+    out: &mut Vec<u8>,
+) {
+    out.extend(
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+            status,
+            reason(status),
+            content_type,
+            body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        )
+        .as_bytes(),
+    );
+    out.extend(body);
+}
+
+fn write_json(status: u16, body: serde_json::Value, keep_alive: bool, out: &mut Vec<u8>) {
+    let body = body.to_string();
+    write_response(status, "application/json", body.as_bytes(), keep_alive, out);
+}
+
+pub fn write_error(status: u16, code: &str, message: &str, keep_alive: bool, out: &mut Vec<u8>) {
+    write_json(
+        status,
+        json!({ "error": { "code": code, "message": message } }),
+        keep_alive,
+        out,
+    );
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        504 => "Gateway Timeout",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+fn status_of(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::Protocol | ErrorCode::InvalidArgument => 400,
+        ErrorCode::Unsupported => 501,
+        ErrorCode::OutOfMemory => 507,
+        ErrorCode::Timeout => 504,
+        ErrorCode::ReadOnly => 403,
+        ErrorCode::WrongType => 409,
+        ErrorCode::NotLeader | ErrorCode::Moved | ErrorCode::Ask => 421,
+        _ => 500,
+    }
+}
+
+/// Routes one request onto cache commands sent through `handler` and appends
+/// the response to `out`.
+///
+/// - `GET /keys/{key}` answers the raw value,
+/// - `PUT /keys/{key}[?ttl=<seconds>]` stores the request body,
+/// - `DELETE /keys/{key}` removes the key,
+/// - `GET /keys?prefix=&limit=&cursor=` lists keys as JSON in order, each
+///   page answering the cursor of the next.
+///
+/// Writes take an optional `w=<none|one|majority|all>` write concern.
+pub fn execute(
+    request: &HttpRequest,
+    handler: &dyn Fn(&RequestCommand) -> Response,
+    out: &mut Vec<u8>,
+) {
+    let keep_alive = request.keep_alive;
+    let key = request
+        .path
+        .strip_prefix("/keys/")
+        .filter(|x| !x.is_empty())
+        .map(percent_decode);
+
+    let concern = match request.param("w").map(|x| WriteConcern::from_str(x, true)) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(_)) => {
+            let message = "w must be one of none, one, majority or all";
+            return write_error(400, "ERR", message, keep_alive, out);
+        }
+    };
+    let write = |x: RequestCommand| match concern {
+        Some(concern) => handler(&RequestCommand::WithConcern(concern, Box::new(x))),
+        None => handler(&x),
+    };
+
+    let res = match (request.method.as_str(), request.path.as_str(), key) {
+        ("GET", "/keys", _) => return list_keys(request, handler, out),
+        ("GET", _, Some(key)) => handler(&RequestCommand::Get(key)),
+        ("PUT", _, Some(key)) => match request.param("ttl").map(|x| x.parse::<u64>()) {
+            None => write(RequestCommand::Set(key, request.body.clone())),
+            Some(Ok(secs)) => write(RequestCommand::SetEx(key, request.body.clone(), secs)),
+            Some(Err(_)) => {
+                return write_error(400, "ERR", "ttl must be a number", keep_alive, out)
+            }
+        },
+        ("DELETE", _, Some(key)) => write(RequestCommand::Delete(key)),
+        (_, "/keys", _) | (_, _, Some(_)) => {
+            return write_error(405, "ERR", "method not allowed", keep_alive, out)
+        }
+        _ => return write_error(404, "ERR", "no such route", keep_alive, out),
+    };
+
+    match res {
+        Response::Value(x) => write_response(200, "application/octet-stream", &x, keep_alive, out),
+        Response::Nil => write_error(404, "NIL", "no such key", keep_alive, out),
+        Response::Ok => write_json(200, json!({ "result": "OK" }), keep_alive, out),
+        Response::Integer(x) if request.method == "DELETE" => {
+            write_json(200, json!({ "deleted": x }), keep_alive, out)
+        }
+        Response::Error(code, message) => write_error(
+            status_of(code),
+            &code.to_string(),
+            &message,
+            keep_alive,
+            out,
+        ),
+        x => write_json(200, json!({ "result": x.to_string() }), keep_alive, out),
+    }
+}
+
+fn list_keys(
+    request: &HttpRequest,
+    handler: &dyn Fn(&RequestCommand) -> Response,
+    out: &mut Vec<u8>,
+) {
+    let keep_alive = request.keep_alive;
+    let prefix = request.param("prefix").unwrap_or_default();
+    let Ok(limit) = request
+        .param("limit")
+        .map_or(Ok(DEFAULT_LIMIT), |x| x.parse::<usize>())
+    else {
+        return write_error(400, "ERR", "limit must be a number", keep_alive, out);
+    };
+    // The cursor is the last key of the previous page, so paging lists
+    // every key that stays once however the others change.
+    let cursor = request.param("cursor").map(str::to_owned);
+
+    let keys = match handler(&RequestCommand::KeysAfter(
+        prefix.to_owned(),
+        cursor.clone(),
+        limit.saturating_add(1),
+    )) {
+        Response::Array(x) => x,
+        Response::Error(code, message) => {
+            return write_error(
+                status_of(code),
+                &code.to_string(),
+                &message,
+                keep_alive,
+                out,
+            )
+        }
+        _ => Vec::new(),
+    };
+    let mut page: Vec<String> = keys
+        .into_iter()
+        .filter_map(|x| match x {
+            Response::Value(key) => Some(String::from_utf8_lossy(&key).to_string()),
+            _ => None,
+        })
+        .collect();
+    let more = page.len() > limit;
+    page.truncate(limit);
+    let next = more.then(|| page.last().cloned().or(cursor)).flatten();
+
+    write_json(
+        200,
+        json!({ "keys": page, "cursor": next }),
+        keep_alive,
+        out,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &[u8]) -> Result<Option<HttpRequest>, HttpError> {
+        let mut decoder = HttpDecoder::new();
+        decoder.read_from(input).unwrap();
+        decoder.decode()
+    }
+
+    #[test]
+    fn decodes_requests_fed_byte_by_byte() {
+        let input =
+            b"PUT /keys/a%2Fb?ttl=5&w=all HTTP/1.1\r\nContent-Length: 8\r\n\r\nv\r\n\r\nxyz\
+            GET /keys HTTP/1.0\r\nHost: x\r\n\r\n";
+        let mut decoder = HttpDecoder::new();
+        let mut requests = Vec::new();
+        for x in input {
+            decoder.read_from(&[*x][..]).unwrap();
+            while let Some(x) = decoder.decode().unwrap() {
+                requests.push(x);
+            }
+        }
+        let put = HttpRequest {
+            method: "PUT".to_owned(),
+            path: "/keys/a%2Fb".to_owned(),
+            query: vec![
+                ("ttl".to_owned(), "5".to_owned()),
+                ("w".to_owned(), "all".to_owned()),
+            ],
+            body: b"v\r\n\r\nxyz".to_vec(),
+            keep_alive: true,
+        };
+        let get = HttpRequest {
+            method: "GET".to_owned(),
+            path: "/keys".to_owned(),
+            query: Vec::new(),
+            body: Vec::new(),
+            keep_alive: false,
+        };
+        assert_eq!(requests, [put, get]);
+        assert!(decoder.buf.is_empty());
+    }
+
+    #[test]
+    fn refuses_malformed_requests() {
+        for (input, status, message) in [
+            (&b"GET /\r\n\r\n"[..], 400, "malformed request line"),
+            (b"GET / HTTP/1.1\r\nHost\r\n\r\n", 400, "malformed header"),
+            (
+                b"PUT / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+                400,
+                "invalid content-length",
+            ),
+            (
+                b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                501,
+                "transfer-encoding is not supported",
+            ),
+        ] {
+            assert_eq!(decode(input), Err(HttpError::new(status, message)));
+        }
+    }
+
+    #[test]
+    fn refuses_requests_over_the_limits() {
+        let head = vec![b'x'; MAX_HEAD_SIZE];
+        assert_eq!(decode(&head), Ok(None));
+        let mut decoder = HttpDecoder { buf: head };
+        decoder.buf.push(b'x');
+        let error = HttpError::new(431, "request header fields too large");
+        assert_eq!(decoder.decode(), Err(error));
+
+        let input = format!(
+            "PUT / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_FRAME_SIZE + 1
+        );
+        let error = HttpError::new(413, "payload too large");
+        assert_eq!(decode(input.as_bytes()), Err(error));
+    }
+
+    #[test]
+    fn percent_decodes_query_strings() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("%e2%9c%93"), "✓");
+        // Escapes that are cut short or not hex are kept as they are.
+        assert_eq!(percent_decode("100%zz%4"), "100%zz%4");
+    }
+}
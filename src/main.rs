@@ -1,5 +1,5 @@
 use std::error::Error;
-use std::thread;
+use std::{process, thread};
 
 use clap::Parser;
 use libc::{SCHED_FIFO, sched_param, sched_setscheduler};
@@ -9,6 +9,10 @@ use crate::cli::Args;
 pub mod cli {
     use clap::Parser;
 
+    use crate::cache::eviction::EvictionKind;
+    use crate::cache::wal::WalSync;
+    use crate::proto::WriteConcern;
+
     #[derive(Parser, Debug, Clone)]
     #[command(version, about, long_about = None)]
     pub struct Args {
@@ -24,17 +28,137 @@ pub mod cli {
         #[arg(short, long, default_value_t = ("127.0.0.1:9000").to_owned())]
         pub addr: String,
 
-        #[arg(short, long, default_value_t = ("./wal.log").to_owned())]
+        /// Directory holding the WAL segments, their manifest and the snapshot;
+        /// a `<wal>.log` of earlier releases is imported on first start
+        #[arg(short, long, default_value_t = ("./wal").to_owned())]
         pub wal: String,
 
+        /// When WAL records are forced to disk
+        #[arg(long, value_enum, default_value_t = WalSync::Everysec)]
+        pub wal_sync: WalSync,
+
+        /// Truncate the WAL at the first corrupt record instead of refusing to start
+        #[arg(long, default_value_t = false)]
+        pub wal_repair: bool,
+
+        /// Bytes after which a WAL segment is sealed and a new one started
+        #[arg(long, default_value_t = 64 * 1024 * 1024)]
+        pub wal_segment_size: u64,
+
+        /// Sealed segments kept after a snapshot covers them, for replicas to catch up from
+        #[arg(long, default_value_t = 2)]
+        pub wal_retain_segments: usize,
+
+        /// Copy sealed segments here before they may be deleted
+        #[arg(long)]
+        pub wal_archive_dir: Option<String>,
+
+        /// Seconds between snapshots that let the WAL be compacted, 0 to disable
+        #[arg(long, default_value_t = 300)]
+        pub snapshot_interval: u64,
+
         #[clap(short, long, value_parser, num_args = 1.., value_delimiter = ' ')]
         pub replica: Vec<String>,
+
+        /// Start as a read-only replica of the primary at this address
+        #[arg(long)]
+        pub replica_of: Option<String>,
+
+        /// Other primaries of a multi-primary deployment, by their --addr; enables stamped, last-writer-wins writes
+        #[arg(long, num_args = 1.., value_delimiter = ' ')]
+        pub multi_primary_peers: Vec<String>,
+
+        /// Every node of a sharded cluster, this one included; the hash slots are split evenly between them
+        #[arg(long, num_args = 1.., value_delimiter = ' ')]
+        pub cluster_nodes: Vec<String>,
+
+        /// Nodes to join the gossip membership through, besides replicas, primary and cluster nodes
+        #[arg(long, num_args = 1.., value_delimiter = ' ')]
+        pub gossip_seeds: Vec<String>,
+
+        /// Milliseconds between gossip probes, each of one member
+        #[arg(long, default_value_t = 500)]
+        pub gossip_interval: u64,
+
+        /// Milliseconds a suspected member has to refute before it is declared dead
+        #[arg(long, default_value_t = 3000)]
+        pub gossip_suspect_timeout: u64,
+
+        /// Promote a replica once a majority of gossip members cannot reach its primary
+        #[arg(long, default_value_t = false)]
+        pub failover: bool,
+
+        /// Other members of a consensus cluster; enables leader election
+        #[arg(long, num_args = 1.., value_delimiter = ' ')]
+        pub raft_peers: Vec<String>,
+
+        /// Milliseconds without a leader before standing for election
+        #[arg(long, default_value_t = 500)]
+        pub raft_election_timeout: u64,
+
+        /// Applied consensus entries after which the cache is snapshotted and the log compacted
+        #[arg(long, default_value_t = 10_000)]
+        pub raft_compact_after: u64,
+
+        /// Records buffered per replica before it has to catch up from the WAL
+        #[arg(long, default_value_t = 10_000)]
+        pub replica_backlog: usize,
+
+        /// Replicas that must apply a write before it is acknowledged, unless the write asks otherwise
+        #[arg(long, value_enum, default_value_t = WriteConcern::None)]
+        pub write_concern: WriteConcern,
+
+        /// Milliseconds a write waits for its write concern before failing
+        #[arg(long, default_value_t = 1000)]
+        pub write_concern_timeout: u64,
+
+        /// Pending response bytes after which a connection stops being read
+        #[arg(long, default_value_t = 4 * 1024 * 1024)]
+        pub high_water_mark: usize,
+
+        /// Also accept Redis (RESP2/RESP3) clients on this address
+        #[arg(long)]
+        pub resp_addr: Option<String>,
+
+        /// Also serve the HTTP/JSON gateway on this address
+        #[arg(long)]
+        pub http_addr: Option<String>,
+
+        /// Milliseconds between active expiry sweeps
+        #[arg(long, default_value_t = 100)]
+        pub expiry_interval: u64,
+
+        /// Key and value bytes the cache may hold, 0 for no limit
+        #[arg(long, default_value_t = 0)]
+        pub max_memory: usize,
+
+        /// How to make room once --max-memory is reached
+        #[arg(long, value_enum, default_value_t = EvictionKind::None)]
+        pub eviction: EvictionKind,
+
+        /// Event loop threads serving connections, defaults to one per core
+        #[arg(long, default_value_t = default_workers())]
+        pub workers: usize,
+
+        /// Most threads answering requests at once, off the event loops
+        #[arg(long, default_value_t = 256)]
+        pub max_handlers: usize,
+
+        /// Independently locked partitions of the keyspace
+        #[arg(long, default_value_t = 64)]
+        pub shards: usize,
+    }
+
+    fn default_workers() -> usize {
+        std::thread::available_parallelism().map_or(1, |x| x.get())
     }
 }
 
 pub mod cache;
 pub mod client;
+pub mod http;
 pub mod proto;
+pub mod resp;
 pub mod server;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -45,7 +169,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     if args.server {
-        return server::start(&args);
+        if let Err(err) = server::start(&args) {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return Ok(());
     }
 
     if args.test {
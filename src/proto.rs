@@ -2,13 +2,38 @@ use std::fmt::{Debug, Display, Formatter};
 use std::io::{Read, Write};
 use std::mem::size_of;
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
 
-const VERSION: u8 = 1;
+use crate::cache::crdt::Crdt;
+use crate::cache::gossip::Member;
+use crate::cache::multi::Stamp;
+use crate::cache::snapshot::SnapshotEntry;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Newest protocol version this build speaks.
+pub const VERSION: u8 = 1;
+/// Oldest protocol version this build still accepts.
+pub const MIN_VERSION: u8 = 1;
+
+/// Optional protocol capabilities agreed on during the handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    Pipelining,
+}
+
+/// Features this build implements.
+pub const FEATURES: &[Feature] = &[Feature::Pipelining];
+
+/// Size of the little-endian length prefix in front of every encoded frame.
+const LENGTH_PREFIX: usize = size_of::<u64>();
+
+/// Largest frame body a decoder accepts unless configured otherwise.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub enum RequestCommand {
+    #[default]
     Empty,
     Get(String),
     Set(String, Vec<u8>),
@@ -16,18 +41,181 @@ pub enum RequestCommand {
     Keys(usize, usize),
 
     Error(Vec<u8>),
-    Recv(Vec<u8>),
+    Recv(Response),
+
+    /// Set with a time to live in seconds.
+    SetEx(String, Vec<u8>, u64),
+    /// Relative timeout in seconds.
+    Expire(String, u64),
+    /// Absolute deadline in unix milliseconds, as stored in the WAL.
+    ExpireAt(String, u64),
+    Ttl(String),
+    Persist(String),
+
+    /// Handshake: the oldest and newest version the sender speaks, and the
+    /// features it would like to use.
+    Hello(u8, u8, Vec<Feature>),
+    /// Server statistics as `field:value` lines.
+    Info,
+    /// State of every replica this server streams its log to.
+    Replicas,
+    /// A write acknowledged only once the given replicas applied it.
+    WithConcern(WriteConcern, Box<RequestCommand>),
+    /// Removes every key.
+    Flush,
+    /// Stores entries copied from another node as they are.
+    Load(Vec<SnapshotEntry>),
+    /// The record a primary logged at the given sequence number, `0` for
+    /// the parts of a full sync that leave the replica without a position,
+    /// and the token the replica attached with.
+    Replicate(u64, u64, Box<RequestCommand>),
+    /// Asks a replica for the last record it applied, so the primary at the
+    /// given address knows where to resume or that it needs a full sync,
+    /// with the token the replica attached with.
+    ReplicaOffset(String, u64),
+    /// Asks a primary to stream its log to the replica at the given address,
+    /// which only applies records carrying the given token.
+    Attach(String, u64),
+    /// Turns this server into a read-only replica of the given primary.
+    ReplicaOf(String),
+    /// Turns a replica into a primary accepting writes.
+    Promote,
+    /// Consensus: term, candidate, index and term of its last log entry.
+    RequestVote(u64, String, u64, u64),
+    /// Consensus: term, leader, index and term of the entry before
+    /// `entries`, the entries with their terms, and the leader's commit index.
+    AppendEntries(u64, String, u64, u64, Vec<(u64, RequestCommand)>, u64),
+    /// A command in the consensus log, with the term of the leader that
+    /// appended it.
+    Entry(u64, Box<RequestCommand>),
+    /// Cluster mode: which node owns which hash slots.
+    Slots,
+    /// Moves the keys of an inclusive slot range owned by this node to the
+    /// given node, which owns the slots once all of them arrived.
+    Migrate(u16, u16, String),
+    /// Tells a node that the keys of a slot range are on their way to it
+    /// from the given node.
+    Import(u16, u16, String),
+    /// Assigns an inclusive slot range to the given node.
+    SetSlots(u16, u16, String),
+    /// A request redirected with an `ASK` error, served by a node that is
+    /// still importing the key's slot.
+    Asking(Box<RequestCommand>),
+    /// Gossip: a probe from the given node, carrying its view of the members.
+    Ping(String, Vec<Member>),
+    /// Gossip: asks for the given node to be probed on the sender's behalf.
+    PingReq(String, Vec<Member>),
+    /// Every known member with its state.
+    Members,
+    /// Multi-primary: a write with the stamp it is ordered by against
+    /// concurrent writes to the same key on other primaries.
+    Stamped(Stamp, Box<RequestCommand>),
+    /// Multi-primary heartbeat: every write stamped before this unix
+    /// millisecond reached the sender.
+    Seen(u64),
+    /// PN-Counter: adds to the counter, or subtracts if negative.
+    CounterAdd(String, i64),
+    CounterGet(String),
+    /// OR-Set: adds members to the set.
+    SetAdd(String, Vec<Vec<u8>>),
+    SetRemove(String, Vec<Vec<u8>>),
+    SetMembers(String),
+    /// LWW-Register: replaces the value unless a later write was merged.
+    RegisterSet(String, Vec<u8>),
+    RegisterGet(String),
+    /// Joins a CRDT value into the one at the key; updates of CRDT values
+    /// are logged and replicated as this.
+    Merge(String, Crdt),
+    /// Set with an absolute deadline in unix milliseconds, as stored in the
+    /// WAL.
+    SetExAt(String, Vec<u8>, u64),
+    /// Consensus: term, leader, index and term of the last entry the
+    /// snapshot covers, and a chunk of the snapshot file at the given
+    /// offset, the last one if set.
+    InstallSnapshot(u64, String, u64, u64, u64, Vec<u8>, bool),
+    /// Removes the CRDT value at the key, which a migration moved to
+    /// another node.
+    DeleteCrdt(String),
+    /// Up to the given number of keys starting with the prefix, in order,
+    /// after the given key if any.
+    KeysAfter(String, Option<String>, usize),
+    /// Cluster log: the key, of a slot range moving away, was written here
+    /// and the receiving node's copy may be out of date.
+    Stale(String),
+    /// Cluster log: the receiving node's copies of these keys are up to
+    /// date, or dropped where this node no longer holds them.
+    Synced(Vec<String>),
 }
 
-impl Into<Vec<u8>> for RequestCommand {
-    fn into(self) -> Vec<u8> {
-        encode_vec(self)
+/// How many replicas must apply a write before it is acknowledged.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WriteConcern {
+    /// Acknowledge once applied locally.
+    None,
+    One,
+    /// More than half of the primary and its replicas, the primary included.
+    Majority,
+    All,
+}
+
+impl WriteConcern {
+    /// Replicas out of `replicas` that must acknowledge a write.
+    pub fn required(&self, replicas: usize) -> usize {
+        match self {
+            WriteConcern::None => 0,
+            WriteConcern::One => 1,
+            WriteConcern::Majority => replicas.div_ceil(2),
+            WriteConcern::All => replicas,
+        }
     }
 }
 
-impl Default for RequestCommand {
-    fn default() -> Self {
-        RequestCommand::Empty
+impl Display for WriteConcern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            WriteConcern::None => "none",
+            WriteConcern::One => "one",
+            WriteConcern::Majority => "majority",
+            WriteConcern::All => "all",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl From<RequestCommand> for Vec<u8> {
+    fn from(value: RequestCommand) -> Self {
+        encode_vec(value)
+    }
+}
+
+impl RequestCommand {
+    /// The key a single-key command reads or writes.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            RequestCommand::Get(key)
+            | RequestCommand::Set(key, _)
+            | RequestCommand::Delete(key)
+            | RequestCommand::SetEx(key, _, _)
+            | RequestCommand::SetExAt(key, _, _)
+            | RequestCommand::Expire(key, _)
+            | RequestCommand::ExpireAt(key, _)
+            | RequestCommand::Ttl(key)
+            | RequestCommand::Persist(key)
+            | RequestCommand::CounterAdd(key, _)
+            | RequestCommand::CounterGet(key)
+            | RequestCommand::SetAdd(key, _)
+            | RequestCommand::SetRemove(key, _)
+            | RequestCommand::SetMembers(key)
+            | RequestCommand::RegisterSet(key, _)
+            | RequestCommand::RegisterGet(key)
+            | RequestCommand::Merge(key, _)
+            | RequestCommand::DeleteCrdt(key) => Some(key),
+            RequestCommand::WithConcern(_, x)
+            | RequestCommand::Replicate(_, _, x)
+            | RequestCommand::Asking(x)
+            | RequestCommand::Stamped(_, x) => x.key(),
+            _ => None,
+        }
     }
 }
 
@@ -42,12 +230,7 @@ impl Display for RequestCommand {
                 write!(f, "GET {}", key)
             }
             RequestCommand::Set(key, body) => {
-                write!(
-                    f,
-                    "SET {}, {}",
-                    key,
-                    String::from_utf8(body.clone()).unwrap()
-                )
+                write!(f, "SET {}, {}", key, String::from_utf8_lossy(body))
             }
             RequestCommand::Delete(key) => {
                 write!(f, "DELETE {}", key)
@@ -57,15 +240,304 @@ impl Display for RequestCommand {
             }
 
             RequestCommand::Error(error) => {
-                write!(f, "ERROR {}", String::from_utf8(error.clone()).unwrap())
+                write!(f, "ERROR {}", String::from_utf8_lossy(error))
+            }
+            RequestCommand::Recv(res) => {
+                write!(f, "<< {}", res)
+            }
+
+            RequestCommand::SetEx(key, body, secs) => {
+                write!(
+                    f,
+                    "SETEX {} {}, {}",
+                    key,
+                    secs,
+                    String::from_utf8_lossy(body)
+                )
+            }
+            RequestCommand::Expire(key, secs) => {
+                write!(f, "EXPIRE {} {}", key, secs)
+            }
+            RequestCommand::ExpireAt(key, deadline) => {
+                write!(f, "EXPIREAT {} {}", key, deadline)
+            }
+            RequestCommand::Ttl(key) => {
+                write!(f, "TTL {}", key)
+            }
+            RequestCommand::Persist(key) => {
+                write!(f, "PERSIST {}", key)
+            }
+
+            RequestCommand::Hello(min, max, features) => {
+                write!(f, "HELLO {}..={} {:?}", min, max, features)
+            }
+            RequestCommand::Info => {
+                write!(f, "INFO")
+            }
+            RequestCommand::Replicas => {
+                write!(f, "REPLICAS")
+            }
+            RequestCommand::WithConcern(concern, x) => {
+                write!(f, "{} W={}", x, concern)
+            }
+            RequestCommand::Flush => {
+                write!(f, "FLUSH")
+            }
+            RequestCommand::Load(entries) => {
+                write!(f, "LOAD {}", entries.len())
+            }
+            RequestCommand::Replicate(seq, _, x) => {
+                write!(f, "#{} {}", seq, x)
+            }
+            RequestCommand::ReplicaOffset(addr, _) => {
+                write!(f, "REPLICAOFFSET {}", addr)
+            }
+            RequestCommand::Attach(addr, _) => {
+                write!(f, "ATTACH {}", addr)
+            }
+            RequestCommand::ReplicaOf(addr) => {
+                write!(f, "REPLICAOF {}", addr)
+            }
+            RequestCommand::Promote => {
+                write!(f, "PROMOTE")
+            }
+            RequestCommand::RequestVote(term, candidate, index, last_term) => {
+                write!(
+                    f,
+                    "REQUESTVOTE {} {} {}@{}",
+                    term, candidate, index, last_term
+                )
+            }
+            RequestCommand::AppendEntries(term, leader, index, prev_term, entries, commit) => {
+                write!(
+                    f,
+                    "APPENDENTRIES {} {} {}@{} +{} commit {}",
+                    term,
+                    leader,
+                    index,
+                    prev_term,
+                    entries.len(),
+                    commit
+                )
+            }
+            RequestCommand::Entry(term, x) => {
+                write!(f, "@{} {}", term, x)
+            }
+            RequestCommand::Slots => {
+                write!(f, "SLOTS")
+            }
+            RequestCommand::Migrate(start, end, addr) => {
+                write!(f, "MIGRATE {}-{} {}", start, end, addr)
+            }
+            RequestCommand::Import(start, end, addr) => {
+                write!(f, "IMPORT {}-{} {}", start, end, addr)
+            }
+            RequestCommand::SetSlots(start, end, addr) => {
+                write!(f, "SETSLOTS {}-{} {}", start, end, addr)
+            }
+            RequestCommand::Asking(x) => {
+                write!(f, "ASKING {}", x)
+            }
+            RequestCommand::Ping(from, members) => {
+                write!(f, "PING {} +{}", from, members.len())
+            }
+            RequestCommand::PingReq(target, members) => {
+                write!(f, "PINGREQ {} +{}", target, members.len())
+            }
+            RequestCommand::Members => {
+                write!(f, "MEMBERS")
+            }
+            RequestCommand::Stamped(stamp, x) => {
+                write!(f, "{} T={}", x, stamp)
+            }
+            RequestCommand::Seen(horizon) => {
+                write!(f, "SEEN {}", horizon)
+            }
+            RequestCommand::CounterAdd(key, by) => {
+                write!(f, "COUNTER.INCRBY {} {}", key, by)
+            }
+            RequestCommand::CounterGet(key) => {
+                write!(f, "COUNTER.GET {}", key)
+            }
+            RequestCommand::SetAdd(key, members) => {
+                write!(f, "ORSET.ADD {} +{}", key, members.len())
+            }
+            RequestCommand::SetRemove(key, members) => {
+                write!(f, "ORSET.REM {} +{}", key, members.len())
+            }
+            RequestCommand::SetMembers(key) => {
+                write!(f, "ORSET.MEMBERS {}", key)
+            }
+            RequestCommand::RegisterSet(key, body) => {
+                write!(f, "REGISTER.SET {}, {}", key, String::from_utf8_lossy(body))
+            }
+            RequestCommand::RegisterGet(key) => {
+                write!(f, "REGISTER.GET {}", key)
+            }
+            RequestCommand::Merge(key, x) => {
+                write!(f, "MERGE {} {}", key, x.kind())
+            }
+            RequestCommand::SetExAt(key, body, deadline) => {
+                write!(
+                    f,
+                    "SETEXAT {} {}, {}",
+                    key,
+                    deadline,
+                    String::from_utf8_lossy(body)
+                )
+            }
+            RequestCommand::InstallSnapshot(term, leader, index, last_term, offset, data, done) => {
+                write!(
+                    f,
+                    "INSTALLSNAPSHOT {} {} {}@{} {}+{}{}",
+                    term,
+                    leader,
+                    index,
+                    last_term,
+                    offset,
+                    data.len(),
+                    if *done { " done" } else { "" }
+                )
+            }
+            RequestCommand::DeleteCrdt(key) => {
+                write!(f, "DELETECRDT {}", key)
+            }
+            RequestCommand::KeysAfter(prefix, after, limit) => {
+                write!(
+                    f,
+                    "KEYSAFTER {}* {} {}",
+                    prefix,
+                    after.as_deref().unwrap_or("-"),
+                    limit
+                )
+            }
+            RequestCommand::Stale(key) => {
+                write!(f, "STALE {}", key)
             }
-            RequestCommand::Recv(buf) => {
-                write!(f, "<< {}", String::from_utf8(buf.clone()).unwrap())
+            RequestCommand::Synced(keys) => {
+                write!(f, "SYNCED {}", keys.join(" "))
             }
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Anything without a more specific code.
+    Internal,
+    /// The request could not be decoded.
+    Protocol,
+    /// The server does not handle this command.
+    Unsupported,
+    /// The write would exceed the memory limit.
+    OutOfMemory,
+    /// No protocol version is shared with the peer.
+    UnsupportedVersion,
+    /// Replicas did not acknowledge a write in time; it was applied locally.
+    Timeout,
+    /// Writes go to the primary, this server is a replica.
+    ReadOnly,
+    /// Consensus mode: the request goes to the leader named in the message.
+    NotLeader,
+    /// Cluster mode: the key's slot and the address of the node owning it.
+    Moved,
+    /// Cluster mode: the key's slot is being migrated and the key is not
+    /// here; retry once on the named node with an `Asking` request.
+    Ask,
+    /// The key holds a CRDT value of another type.
+    WrongType,
+    /// An argument is out of range, such as a timeout too long to keep.
+    InvalidArgument,
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorCode::Internal => write!(f, "ERR"),
+            ErrorCode::Protocol => write!(f, "PROTO"),
+            ErrorCode::Unsupported => write!(f, "UNSUPPORTED"),
+            ErrorCode::OutOfMemory => write!(f, "OOM"),
+            ErrorCode::UnsupportedVersion => write!(f, "VERSION"),
+            ErrorCode::Timeout => write!(f, "TIMEOUT"),
+            ErrorCode::ReadOnly => write!(f, "READONLY"),
+            ErrorCode::NotLeader => write!(f, "NOTLEADER"),
+            ErrorCode::Moved => write!(f, "MOVED"),
+            ErrorCode::Ask => write!(f, "ASK"),
+            ErrorCode::WrongType => write!(f, "WRONGTYPE"),
+            ErrorCode::InvalidArgument => write!(f, "INVALID"),
+        }
+    }
+}
+
+/// Result of a command, carried back to the client in [`RequestCommand::Recv`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Response {
+    Value(Vec<u8>),
+    /// The key does not exist.
+    Nil,
+    Ok,
+    Integer(i64),
+    Array(Vec<Response>),
+    Error(ErrorCode, String),
+    /// Handshake reply: the agreed version and features.
+    Hello(u8, Vec<Feature>),
+    /// Consensus: the voter's term and whether it granted its vote.
+    Vote(u64, bool),
+    /// Consensus: the follower's term, whether the entries matched its log,
+    /// and its last matching index, or a hint where to retry from.
+    Appended(u64, bool, u64),
+    /// Gossip: the responder's view of the members.
+    Members(Vec<Member>),
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error(code, message.into())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Response::Error(_, _))
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Value(buf) => write!(f, "{}", String::from_utf8_lossy(buf)),
+            Response::Nil => write!(f, "(nil)"),
+            Response::Ok => write!(f, "OK"),
+            Response::Integer(x) => write!(f, "(integer) {}", x),
+            Response::Array(items) => {
+                if items.is_empty() {
+                    return write!(f, "(empty array)");
+                }
+                for (i, x) in items.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}) {}", i + 1, x)?;
+                }
+                Ok(())
+            }
+            Response::Error(code, message) => write!(f, "(error) {} {}", code, message),
+            Response::Hello(version, features) => write!(f, "HELLO {} {:?}", version, features),
+            Response::Vote(term, granted) => write!(f, "VOTE {} {}", term, granted),
+            Response::Appended(term, success, index) => {
+                write!(f, "APPENDED {} {} {}", term, success, index)
+            }
+            Response::Members(members) => write!(f, "MEMBERS +{}", members.len()),
+        }
+    }
+}
+
+/// Leading fields of every [`Frame`], readable whatever the command layout of
+/// the version that wrote it.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub id: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Frame {
     version: u8,
@@ -73,27 +545,40 @@ pub struct Frame {
     command: RequestCommand,
 }
 
-impl Into<Vec<u8>> for Frame {
-    fn into(self) -> Vec<u8> {
-        encode_vec(self)
+impl From<Frame> for Vec<u8> {
+    fn from(value: Frame) -> Self {
+        encode_vec(value)
     }
 }
 
-impl Into<RequestCommand> for Frame {
-    fn into(self) -> RequestCommand {
-        self.command
+impl From<Frame> for RequestCommand {
+    fn from(value: Frame) -> Self {
+        value.command
     }
 }
 
 impl Frame {
-    pub fn new(command: RequestCommand) -> Self {
+    pub fn new(id: u64, command: RequestCommand) -> Self {
         Self {
             version: VERSION,
-            id: 1,
+            id,
             command,
         }
     }
 
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     pub fn to_response(&self, command: RequestCommand) -> Self {
         Self {
             version: self.version,
@@ -103,59 +588,267 @@ impl Frame {
     }
 }
 
-pub fn decode<T: Read>(mut r: T) -> Result<Option<RequestCommand>, std::io::Error> {
-    let mut buf = [0u8; size_of::<usize>()];
+#[derive(Debug)]
+pub enum ProtoError {
+    Io(std::io::Error),
+    /// A complete frame was received but its body could not be decoded.
+    Malformed(bincode::Error),
+    /// The length prefix announced a body larger than the decoder accepts.
+    FrameTooLarge(u64),
+    /// A response arrived for a request id that is not outstanding.
+    UnexpectedFrame(u64),
+    /// The peer refused the handshake.
+    Handshake(String),
+}
 
-    let i = r.read(&mut buf)?;
-    if i == 0 {
-        return Ok(None);
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtoError::Io(err) => write!(f, "io error: {}", err),
+            ProtoError::Malformed(err) => write!(f, "malformed frame: {}", err),
+            ProtoError::FrameTooLarge(size) => write!(f, "frame of {} bytes is too large", size),
+            ProtoError::UnexpectedFrame(id) => write!(f, "unexpected response for request {}", id),
+            ProtoError::Handshake(message) => write!(f, "handshake refused: {}", message),
+        }
     }
-    let size = usize::from_le_bytes(buf);
-    let mut buf = vec![0u8; size];
-    let i = r.read(&mut buf)?;
-    if i == 0 {
-        return Ok(None);
+}
+
+impl std::error::Error for ProtoError {}
+
+impl From<std::io::Error> for ProtoError {
+    fn from(value: std::io::Error) -> Self {
+        ProtoError::Io(value)
     }
+}
 
-    let frame = bincode::deserialize::<Frame>(&*buf).unwrap();
-    Ok(Some(frame.command))
+impl From<bincode::Error> for ProtoError {
+    fn from(value: bincode::Error) -> Self {
+        ProtoError::Malformed(value)
+    }
 }
 
-pub fn deserialize<T, R>(mut r: T) -> Result<Option<R>, std::io::Error>
-where
-    T: Read,
-    R: DeserializeOwned,
-{
-    let mut buf = [0u8; size_of::<usize>()];
+/// Incremental decoder for length-prefixed frames.
+///
+/// Bytes are accumulated across reads, so a frame split over several packets
+/// (or several frames merged into one) is handed out exactly once and whole.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    max_frame_size: usize,
+}
 
-    let i = r.read(&mut buf)?;
-    if i == 0 {
-        return Ok(None);
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
     }
-    let size = usize::from_le_bytes(buf);
-    let mut buf = vec![0u8; size];
-    let i = r.read(&mut buf)?;
-    if i == 0 {
-        return Ok(None);
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
     }
 
-    Ok(Some(bincode::deserialize::<R>(&*buf).unwrap()))
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Returns true when no partial frame is buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Performs a single read from `r` into the internal buffer.
+    ///
+    /// Returns the number of bytes read, `0` meaning the peer closed the stream.
+    pub fn read_from<R: Read>(&mut self, mut r: R) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 16 * 1024];
+        let n = r.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// Takes the next complete frame out of the buffer, if there is one.
+    ///
+    /// A malformed body is consumed before the error is returned, so the
+    /// decoder stays aligned on the next frame.
+    pub fn decode<D: DeserializeOwned>(&mut self) -> Result<Option<D>, ProtoError> {
+        match self.decode_raw()? {
+            Some(body) => Ok(Some(bincode::deserialize::<D>(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`FrameDecoder::decode`], but hands out the undecoded body.
+    pub fn decode_raw(&mut self) -> Result<Option<Vec<u8>>, ProtoError> {
+        if self.buf.len() < LENGTH_PREFIX {
+            return Ok(None);
+        }
+
+        let mut prefix = [0u8; LENGTH_PREFIX];
+        prefix.copy_from_slice(&self.buf[..LENGTH_PREFIX]);
+        let size = u64::from_le_bytes(prefix);
+        let size = match usize::try_from(size) {
+            Ok(size) if size <= self.max_frame_size => size,
+            _ => return Err(ProtoError::FrameTooLarge(size)),
+        };
+
+        let end = LENGTH_PREFIX + size;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+
+        let body = self.buf[LENGTH_PREFIX..end].to_vec();
+        self.buf.drain(..end);
+        Ok(Some(body))
+    }
+
+    /// Reads from a blocking `r` until a whole frame is available.
+    ///
+    /// Returns `Ok(None)` on a clean end of stream between frames.
+    pub fn read_frame<R: Read, D: DeserializeOwned>(
+        &mut self,
+        mut r: R,
+    ) -> Result<Option<D>, ProtoError> {
+        loop {
+            if let Some(x) = self.decode()? {
+                return Ok(Some(x));
+            }
+
+            match self.read_from(&mut r) {
+                Ok(0) if self.is_empty() => return Ok(None),
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Picks the newest version both sides speak and the requested features this
+/// build implements.
+pub fn negotiate(min: u8, max: u8, features: &[Feature]) -> Response {
+    let version = max.min(VERSION);
+    if version < min.max(MIN_VERSION) {
+        return Response::error(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "no common protocol version, peer speaks {}..={} and server {}..={}",
+                min, max, MIN_VERSION, VERSION
+            ),
+        );
+    }
+    let features = features
+        .iter()
+        .filter(|x| FEATURES.contains(x))
+        .copied()
+        .collect();
+    Response::Hello(version, features)
 }
 
 pub fn encode_vec<T: Serialize>(f: T) -> Vec<u8> {
     let mut buf = bincode::serialize(&f).unwrap();
-    let mut size = buf.len().to_le_bytes().to_vec();
+    let mut size = (buf.len() as u64).to_le_bytes().to_vec();
 
     size.append(&mut buf);
     size
 }
 
 pub fn encode<T: Write>(mut w: T, f: &Frame) -> Result<usize, std::io::Error> {
-    let mut buf = bincode::serialize(f).unwrap();
-    let mut size = buf.len().to_le_bytes().to_vec();
+    let buf = encode_vec(f);
 
-    size.append(&mut buf);
+    w.write_all(&buf)?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u64, key: &str) -> Vec<u8> {
+        Frame::new(id, RequestCommand::Get(key.to_owned())).into()
+    }
+
+    /// The id and command of a decoded frame.
+    fn parts(x: Frame) -> (u64, String) {
+        (x.id(), RequestCommand::from(x).to_string())
+    }
+
+    #[test]
+    fn decodes_frames_fed_byte_by_byte() {
+        let input = [frame(1, "a"), frame(2, "bb")].concat();
+        let first = frame(1, "a").len();
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for (i, x) in input.iter().enumerate() {
+            assert_eq!(decoder.read_from(&[*x][..]).unwrap(), 1);
+            match decoder.decode::<Frame>().unwrap() {
+                Some(x) => frames.push((i + 1, parts(x))),
+                None => assert!(!decoder.is_empty()),
+            }
+        }
+        assert_eq!(
+            frames,
+            [
+                (first, (1, "GET a".to_owned())),
+                (input.len(), (2, "GET bb".to_owned()))
+            ]
+        );
+        assert!(decoder.is_empty());
+    }
+
+    #[test]
+    fn refuses_frames_over_the_limit_before_buffering_them() {
+        let body = frame(1, "abc").len() - LENGTH_PREFIX;
+        let mut decoder = FrameDecoder::with_max_frame_size(body - 1);
+        decoder
+            .read_from(&frame(1, "abc")[..LENGTH_PREFIX])
+            .unwrap();
+        match decoder.decode_raw() {
+            Err(ProtoError::FrameTooLarge(x)) => assert_eq!(x, body as u64),
+            x => panic!("decoded {:?}", x),
+        }
+
+        let mut decoder = FrameDecoder::with_max_frame_size(body);
+        decoder.read_from(&frame(1, "abc")[..]).unwrap();
+        assert_eq!(decoder.decode_raw().unwrap().map(|x| x.len()), Some(body));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.read_from(&u64::MAX.to_le_bytes()[..]).unwrap();
+        assert!(matches!(
+            decoder.decode_raw(),
+            Err(ProtoError::FrameTooLarge(u64::MAX))
+        ));
+    }
 
-    let i = w.write(&*size)?;
-    Ok(i)
+    #[test]
+    fn skips_a_malformed_body_and_stays_aligned() {
+        let garbage = encode_vec([0xffu8; 4]);
+        let mut decoder = FrameDecoder::new();
+        decoder
+            .read_from(&[garbage, frame(7, "key")].concat()[..])
+            .unwrap();
+        assert!(matches!(
+            decoder.decode::<Frame>(),
+            Err(ProtoError::Malformed(_))
+        ));
+        let x = decoder.decode::<Frame>().unwrap().unwrap();
+        assert_eq!(parts(x), (7, "GET key".to_owned()));
+    }
+
+    #[test]
+    fn tells_a_clean_end_of_stream_from_a_cut_frame() {
+        let mut decoder = FrameDecoder::new();
+        assert!(decoder.read_frame::<_, Frame>(&[][..]).unwrap().is_none());
+
+        let input = frame(1, "a");
+        let mut decoder = FrameDecoder::new();
+        match decoder.read_frame::<_, Frame>(&input[..input.len() - 1]) {
+            Err(ProtoError::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof),
+            x => panic!("read {:?}", x),
+        }
+    }
 }
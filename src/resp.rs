@@ -0,0 +1,824 @@
+use std::io::Read;
+
+use clap::ValueEnum;
+
+use crate::cache::cluster::{slot, SlotMap, SLOTS};
+use crate::cache::crdt::is_update;
+use crate::cache::middlewares::is_write;
+use crate::proto::{RequestCommand, Response, WriteConcern, MAX_FRAME_SIZE};
+
+/// Most arguments a single RESP command may carry.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// A reply in RESP terms, before it is written for RESP2 or RESP3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl From<Response> for RespValue {
+    fn from(value: Response) -> Self {
+        match value {
+            Response::Value(x) => RespValue::Bulk(x),
+            Response::Nil => RespValue::Null,
+            Response::Ok => RespValue::Simple("OK".to_owned()),
+            Response::Integer(x) => RespValue::Integer(x),
+            Response::Array(x) => RespValue::Array(x.into_iter().map(|x| x.into()).collect()),
+            Response::Error(code, message) => RespValue::Error(format!("{} {}", code, message)),
+            Response::Hello(version, _) => RespValue::Integer(version as i64),
+            x @ (Response::Vote(_, _) | Response::Appended(_, _, _) | Response::Members(_)) => {
+                RespValue::Simple(x.to_string())
+            }
+        }
+    }
+}
+
+impl RespValue {
+    fn error(message: impl Into<String>) -> Self {
+        RespValue::Error(format!("ERR {}", message.into()))
+    }
+
+    /// Appends the wire form of the value; RESP2 has no null or map type, so
+    /// those fall back to a null bulk string and a flat array.
+    pub fn encode(&self, resp3: bool, buf: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(x) => buf.extend(format!("+{}\r\n", x).as_bytes()),
+            RespValue::Error(x) => buf.extend(format!("-{}\r\n", x).as_bytes()),
+            RespValue::Integer(x) => buf.extend(format!(":{}\r\n", x).as_bytes()),
+            RespValue::Bulk(x) => {
+                buf.extend(format!("${}\r\n", x.len()).as_bytes());
+                buf.extend(x);
+                buf.extend(b"\r\n");
+            }
+            RespValue::Null if resp3 => buf.extend(b"_\r\n"),
+            RespValue::Null => buf.extend(b"$-1\r\n"),
+            RespValue::Array(items) => {
+                buf.extend(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|x| x.encode(resp3, buf));
+            }
+            RespValue::Map(items) => {
+                if resp3 {
+                    buf.extend(format!("%{}\r\n", items.len()).as_bytes());
+                } else {
+                    buf.extend(format!("*{}\r\n", items.len() * 2).as_bytes());
+                }
+                for (k, v) in items {
+                    k.encode(resp3, buf);
+                    v.encode(resp3, buf);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespError {
+    /// The bytes are not valid RESP.
+    Protocol(String),
+    /// A bulk string or array exceeds the configured limits.
+    TooLarge,
+}
+
+impl std::fmt::Display for RespError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RespError::Protocol(x) => write!(f, "protocol error: {}", x),
+            RespError::TooLarge => write!(f, "request too large"),
+        }
+    }
+}
+
+impl std::error::Error for RespError {}
+
+/// Incremental parser for client commands: arrays of bulk strings, or inline
+/// commands as typed into a telnet session.
+#[derive(Debug, Default)]
+pub struct RespDecoder {
+    buf: Vec<u8>,
+}
+
+impl RespDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_from<R: Read>(&mut self, mut r: R) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 16 * 1024];
+        let n = r.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+
+    /// Takes the next complete command out of the buffer, if there is one.
+    pub fn decode(&mut self) -> Result<Option<Vec<Vec<u8>>>, RespError> {
+        // Blank inline lines and null arrays hold no command; they are
+        // skipped in one pass and dropped together.
+        let mut from = 0;
+        let command = loop {
+            match self.parse(from)? {
+                Parsed::Skip(end) => from = end,
+                Parsed::Command(args, end) => {
+                    from = end;
+                    break Some(args);
+                }
+                Parsed::Incomplete => break None,
+            }
+        };
+        self.buf.drain(..from);
+        Ok(command)
+    }
+
+    /// Parses what starts at offset `from` of the buffer.
+    fn parse(&self, from: usize) -> Result<Parsed, RespError> {
+        let Some(first) = self.buf.get(from) else {
+            return Ok(Parsed::Incomplete);
+        };
+
+        if *first != b'*' {
+            let Some(end) = find_crlf(&self.buf, from) else {
+                return self.check_inline(from);
+            };
+            let args: Vec<Vec<u8>> = String::from_utf8_lossy(&self.buf[from..end])
+                .split_whitespace()
+                .map(|x| x.as_bytes().to_vec())
+                .collect();
+            return Ok(match args.is_empty() {
+                true => Parsed::Skip(end + 2),
+                false => Parsed::Command(args, end + 2),
+            });
+        }
+
+        let Some((count, mut pos)) = read_number(&self.buf, from + 1)? else {
+            return Ok(Parsed::Incomplete);
+        };
+        if count < 0 {
+            return Ok(Parsed::Skip(pos));
+        }
+        if count as usize > MAX_ARGS {
+            return Err(RespError::TooLarge);
+        }
+
+        // The count is the client's word; arguments are only made room for
+        // as they arrive.
+        let mut args = Vec::new();
+        for _ in 0..count {
+            match self.buf.get(pos) {
+                None => return Ok(Parsed::Incomplete),
+                Some(b'$') => {}
+                Some(x) => {
+                    return Err(RespError::Protocol(format!(
+                        "expected '$', got '{}'",
+                        *x as char
+                    )))
+                }
+            }
+            let Some((len, start)) = read_number(&self.buf, pos + 1)? else {
+                return Ok(Parsed::Incomplete);
+            };
+            if len < 0 || len as usize > MAX_FRAME_SIZE {
+                return Err(RespError::TooLarge);
+            }
+            let end = start + len as usize;
+            if self.buf.len() < end + 2 {
+                return Ok(Parsed::Incomplete);
+            }
+            if &self.buf[end..end + 2] != b"\r\n" {
+                return Err(RespError::Protocol("bulk string not terminated".to_owned()));
+            }
+            args.push(self.buf[start..end].to_vec());
+            pos = end + 2;
+        }
+        Ok(Parsed::Command(args, pos))
+    }
+
+    fn check_inline(&self, from: usize) -> Result<Parsed, RespError> {
+        if self.buf.len() - from > MAX_FRAME_SIZE {
+            return Err(RespError::TooLarge);
+        }
+        Ok(Parsed::Incomplete)
+    }
+}
+
+/// What the buffer holds at some offset.
+enum Parsed {
+    /// Nothing complete yet.
+    Incomplete,
+    /// Something without a command, ending at the offset.
+    Skip(usize),
+    /// A command, ending at the offset.
+    Command(Vec<Vec<u8>>, usize),
+}
+
+fn find_crlf(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?
+        .windows(2)
+        .position(|x| x == b"\r\n")
+        .map(|x| x + from)
+}
+
+/// Reads a decimal line starting at `from`, returning it and the offset after its CRLF.
+fn read_number(buf: &[u8], from: usize) -> Result<Option<(i64, usize)>, RespError> {
+    let Some(end) = find_crlf(buf, from) else {
+        if buf.len() - from > 32 {
+            return Err(RespError::Protocol("length line too long".to_owned()));
+        }
+        return Ok(None);
+    };
+    let x = std::str::from_utf8(&buf[from..end])
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| RespError::Protocol("invalid length".to_owned()))?;
+    Ok(Some((x, end + 2)))
+}
+
+/// Per-connection RESP state.
+#[derive(Debug, Default)]
+pub struct RespSession {
+    pub decoder: RespDecoder,
+    resp3: bool,
+    /// The next command follows an `ASK` redirect.
+    asking: bool,
+    /// Write concern of the writes on this connection, set by `CONCERN`.
+    concern: Option<WriteConcern>,
+}
+
+impl RespSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs one command, sending cache commands through `handler`, and
+    /// appends the encoded reply to `buf`.
+    pub fn execute(
+        &mut self,
+        args: &[Vec<u8>],
+        handler: &dyn Fn(&RequestCommand) -> Response,
+        buf: &mut Vec<u8>,
+    ) {
+        let reply = self.reply(args, handler);
+        reply.encode(self.resp3, buf);
+    }
+
+    fn reply(
+        &mut self,
+        args: &[Vec<u8>],
+        handler: &dyn Fn(&RequestCommand) -> Response,
+    ) -> RespValue {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let concern = self.concern;
+        let asking = std::mem::take(&mut self.asking);
+        let handler = |x: &RequestCommand| {
+            let x = match concern {
+                Some(concern) if is_write(x) || is_update(x) => {
+                    RequestCommand::WithConcern(concern, Box::new(x.clone()))
+                }
+                _ if !asking => return handler(x),
+                _ => x.clone(),
+            };
+            match asking {
+                true => handler(&RequestCommand::Asking(Box::new(x))),
+                false => handler(&x),
+            }
+        };
+        self.run(&name, &args[1..], &handler)
+            .unwrap_or_else(|err| err)
+    }
+
+    fn run(
+        &mut self,
+        name: &str,
+        args: &[Vec<u8>],
+        handler: &dyn Fn(&RequestCommand) -> Response,
+    ) -> Result<RespValue, RespValue> {
+        match name {
+            "PING" => {
+                arity(name, args, 0, 1)?;
+                Ok(match args.first() {
+                    Some(x) => RespValue::Bulk(x.clone()),
+                    None => RespValue::Simple("PONG".to_owned()),
+                })
+            }
+            "GET" => {
+                arity(name, args, 1, 1)?;
+                Ok(handler(&RequestCommand::Get(text(args, 0))).into())
+            }
+            "SET" => {
+                arity(name, args, 2, 4)?;
+                let value = args[1].clone();
+                match args.len() {
+                    2 => Ok(handler(&RequestCommand::Set(text(args, 0), value)).into()),
+                    4 => {
+                        let secs = match text(args, 2).to_uppercase().as_str() {
+                            "EX" => number(args, 3)?,
+                            "PX" => number(args, 3)?.div_ceil(1000),
+                            _ => return Err(RespValue::error("syntax error")),
+                        };
+                        Ok(handler(&RequestCommand::SetEx(text(args, 0), value, secs)).into())
+                    }
+                    _ => Err(RespValue::error("syntax error")),
+                }
+            }
+            "SETEX" => {
+                arity(name, args, 3, 3)?;
+                let secs = number(args, 1)?;
+                Ok(handler(&RequestCommand::SetEx(text(args, 0), args[2].clone(), secs)).into())
+            }
+            "DEL" => {
+                arity(name, args, 1, usize::MAX)?;
+                let mut deleted = 0;
+                for i in 0..args.len() {
+                    match handler(&RequestCommand::Delete(text(args, i))) {
+                        Response::Integer(x) => deleted += x,
+                        x if x.is_error() => return Ok(x.into()),
+                        _ => {}
+                    }
+                }
+                Ok(RespValue::Integer(deleted))
+            }
+            "KEYS" => {
+                arity(name, args, 1, 1)?;
+                let keys = handler(&RequestCommand::Keys(usize::MAX, 0));
+                Ok(filter_keys(keys, &text(args, 0)).into())
+            }
+            "SCAN" => {
+                arity(name, args, 1, 5)?;
+                let after = scan_cursor(&args[0])?;
+                let mut pattern = "*".to_owned();
+                let mut count = 10;
+                for option in args[1..].chunks(2) {
+                    match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+                        (b"MATCH", Some(x)) => pattern = String::from_utf8_lossy(x).to_string(),
+                        (b"COUNT", Some(_)) => count = number(option, 1)? as usize,
+                        _ => return Err(RespValue::error("syntax error")),
+                    }
+                }
+                // Pages run in key order from the last key of the previous
+                // one, so keys written or deleted meanwhile move no others.
+                let prefix = pattern
+                    [..pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len())]
+                    .to_owned();
+                let page = handler(&RequestCommand::KeysAfter(prefix, after, count));
+                let next = match &page {
+                    Response::Array(x) if x.len() == count && count > 0 => match x.last() {
+                        Some(Response::Value(key)) => encode_cursor(key),
+                        _ => "0".to_owned(),
+                    },
+                    _ => "0".to_owned(),
+                };
+                Ok(RespValue::Array(vec![
+                    RespValue::Bulk(next.into_bytes()),
+                    filter_keys(page, &pattern).into(),
+                ]))
+            }
+            "EXPIRE" => {
+                arity(name, args, 2, 2)?;
+                let secs = number(args, 1)?;
+                Ok(handler(&RequestCommand::Expire(text(args, 0), secs)).into())
+            }
+            "TTL" => {
+                arity(name, args, 1, 1)?;
+                Ok(handler(&RequestCommand::Ttl(text(args, 0))).into())
+            }
+            "PERSIST" => {
+                arity(name, args, 1, 1)?;
+                Ok(handler(&RequestCommand::Persist(text(args, 0))).into())
+            }
+            "INFO" => {
+                arity(name, args, 0, 1)?;
+                Ok(handler(&RequestCommand::Info).into())
+            }
+            "REPLICAS" => {
+                arity(name, args, 0, 0)?;
+                Ok(handler(&RequestCommand::Replicas).into())
+            }
+            "REPLICAOF" => {
+                arity(name, args, 2, 2)?;
+                let (host, port) = (text(args, 0), text(args, 1));
+                match host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                    true => Ok(handler(&RequestCommand::Promote).into()),
+                    false => Ok(
+                        handler(&RequestCommand::ReplicaOf(format!("{}:{}", host, port))).into(),
+                    ),
+                }
+            }
+            "PROMOTE" => {
+                arity(name, args, 0, 0)?;
+                Ok(handler(&RequestCommand::Promote).into())
+            }
+            "MEMBERS" => {
+                arity(name, args, 0, 0)?;
+                Ok(handler(&RequestCommand::Members).into())
+            }
+            "COUNTER.INCRBY" => {
+                arity(name, args, 2, 2)?;
+                let by = signed(args, 1)?;
+                Ok(handler(&RequestCommand::CounterAdd(text(args, 0), by)).into())
+            }
+            "COUNTER.GET" => {
+                arity(name, args, 1, 1)?;
+                Ok(handler(&RequestCommand::CounterGet(text(args, 0))).into())
+            }
+            "ORSET.ADD" | "ORSET.REM" => {
+                arity(name, args, 2, usize::MAX)?;
+                let members = args[1..].to_vec();
+                Ok(handler(&match name {
+                    "ORSET.ADD" => RequestCommand::SetAdd(text(args, 0), members),
+                    _ => RequestCommand::SetRemove(text(args, 0), members),
+                })
+                .into())
+            }
+            "ORSET.MEMBERS" => {
+                arity(name, args, 1, 1)?;
+                Ok(handler(&RequestCommand::SetMembers(text(args, 0))).into())
+            }
+            "REGISTER.SET" => {
+                arity(name, args, 2, 2)?;
+                Ok(handler(&RequestCommand::RegisterSet(text(args, 0), args[1].clone())).into())
+            }
+            "REGISTER.GET" => {
+                arity(name, args, 1, 1)?;
+                Ok(handler(&RequestCommand::RegisterGet(text(args, 0))).into())
+            }
+            "ASKING" => {
+                arity(name, args, 0, 0)?;
+                self.asking = true;
+                Ok(RespValue::Simple("OK".to_owned()))
+            }
+            "CLUSTER" => {
+                arity(name, args, 1, 4)?;
+                match text(args, 0).to_ascii_uppercase().as_str() {
+                    "KEYSLOT" => {
+                        arity(name, args, 2, 2)?;
+                        Ok(RespValue::Integer(slot(&text(args, 1)) as i64))
+                    }
+                    "MIGRATE" | "SETSLOTS" => {
+                        arity(name, args, 4, 4)?;
+                        let (start, end) = (slot_number(args, 1)?, slot_number(args, 2)?);
+                        let addr = text(args, 3);
+                        Ok(handler(&match text(args, 0).to_ascii_uppercase().as_str() {
+                            "MIGRATE" => RequestCommand::Migrate(start, end, addr),
+                            _ => RequestCommand::SetSlots(start, end, addr),
+                        })
+                        .into())
+                    }
+                    "SLOTS" => {
+                        let res = handler(&RequestCommand::Slots);
+                        let Some(map) = SlotMap::from_response(&res) else {
+                            return Ok(res.into());
+                        };
+                        // Redis clients expect `[start, end, [host, port]]`.
+                        Ok(RespValue::Array(
+                            map.ranges
+                                .into_iter()
+                                .map(|(start, end, addr)| {
+                                    let (host, port) =
+                                        addr.rsplit_once(':').unwrap_or((&addr, "0"));
+                                    RespValue::Array(vec![
+                                        RespValue::Integer(start as i64),
+                                        RespValue::Integer(end as i64),
+                                        RespValue::Array(vec![
+                                            RespValue::Bulk(host.as_bytes().to_vec()),
+                                            RespValue::Integer(port.parse().unwrap_or(0)),
+                                        ]),
+                                    ])
+                                })
+                                .collect(),
+                        ))
+                    }
+                    x => Err(RespValue::error(format!(
+                        "unknown CLUSTER subcommand '{}'",
+                        x
+                    ))),
+                }
+            }
+            "HELLO" => {
+                match args.first().map(|x| x.as_slice()) {
+                    None => {}
+                    Some(b"2") => self.resp3 = false,
+                    Some(b"3") => self.resp3 = true,
+                    Some(_) => {
+                        return Err(RespValue::Error(
+                            "NOPROTO unsupported protocol version".to_owned(),
+                        ))
+                    }
+                }
+                let field = |k: &str, v: RespValue| (RespValue::Bulk(k.as_bytes().to_vec()), v);
+                Ok(RespValue::Map(vec![
+                    field("server", RespValue::Bulk(b"plaintcp".to_vec())),
+                    field(
+                        "version",
+                        RespValue::Bulk(env!("CARGO_PKG_VERSION").as_bytes().to_vec()),
+                    ),
+                    field("proto", RespValue::Integer(if self.resp3 { 3 } else { 2 })),
+                    field("mode", RespValue::Bulk(b"standalone".to_vec())),
+                ]))
+            }
+            "CONCERN" => {
+                arity(name, args, 1, 1)?;
+                self.concern = match text(args, 0).as_str() {
+                    x if x.eq_ignore_ascii_case("default") => None,
+                    x => Some(WriteConcern::from_str(x, true).map_err(|_| {
+                        RespValue::error(
+                            "concern must be one of none, one, majority, all or default",
+                        )
+                    })?),
+                };
+                Ok(RespValue::Simple("OK".to_owned()))
+            }
+            // Probed by redis-cli and redis-benchmark on connect.
+            "COMMAND" | "CONFIG" => Ok(RespValue::Array(Vec::new())),
+            _ => Err(RespValue::error(format!(
+                "unknown command '{}'",
+                name.to_lowercase()
+            ))),
+        }
+    }
+}
+
+fn text(args: &[Vec<u8>], i: usize) -> String {
+    String::from_utf8_lossy(&args[i]).to_string()
+}
+
+fn number(args: &[Vec<u8>], i: usize) -> Result<u64, RespValue> {
+    text(args, i)
+        .parse()
+        .map_err(|_| RespValue::error("value is not an integer or out of range"))
+}
+
+fn signed(args: &[Vec<u8>], i: usize) -> Result<i64, RespValue> {
+    text(args, i)
+        .parse()
+        .map_err(|_| RespValue::error("value is not an integer or out of range"))
+}
+
+fn slot_number(args: &[Vec<u8>], i: usize) -> Result<u16, RespValue> {
+    text(args, i)
+        .parse()
+        .ok()
+        .filter(|x| *x < SLOTS)
+        .ok_or_else(|| RespValue::error("invalid or out of range slot"))
+}
+
+fn arity(name: &str, args: &[Vec<u8>], min: usize, max: usize) -> Result<(), RespValue> {
+    if args.len() < min || args.len() > max {
+        return Err(RespValue::error(format!(
+            "wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        )));
+    }
+    Ok(())
+}
+
+/// Encodes the last key of a `SCAN` page as the cursor of the next one: a
+/// `1`, then every byte of the key as three decimal digits, so clients that
+/// take cursors for numbers still can.
+fn encode_cursor(key: &[u8]) -> String {
+    let mut cursor = "1".to_owned();
+    for x in key {
+        cursor.push_str(&format!("{:03}", x));
+    }
+    cursor
+}
+
+/// The key a `SCAN` cursor continues after, `None` for `0`, which starts over.
+fn scan_cursor(cursor: &[u8]) -> Result<Option<String>, RespValue> {
+    let invalid = || RespValue::error("invalid cursor");
+    match cursor {
+        b"0" => Ok(None),
+        [b'1', digits @ ..] if digits.len() % 3 == 0 && digits.iter().all(u8::is_ascii_digit) => {
+            let key = digits
+                .chunks(3)
+                .map(|x| std::str::from_utf8(x).ok()?.parse::<u8>().ok())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(invalid)?;
+            String::from_utf8(key).map(Some).map_err(|_| invalid())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn filter_keys(keys: Response, pattern: &str) -> Response {
+    match keys {
+        Response::Array(keys) => Response::Array(
+            keys.into_iter()
+                .filter(|x| match x {
+                    Response::Value(key) => glob_match(pattern.as_bytes(), key),
+                    _ => false,
+                })
+                .collect(),
+        ),
+        x => x,
+    }
+}
+
+/// Redis style glob: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+///
+/// Only the last `*` is ever backtracked to, as letting it swallow one more
+/// byte covers everything an earlier one could, so matching takes at most
+/// `pattern.len() * s.len()` steps.
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The pattern after the last `*` and where in `s` it resumes.
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        if let Some(len) = match_token(&pattern[p..], s[i]) {
+            p += len;
+            i += 1;
+            continue;
+        }
+        let Some((after, at)) = star else {
+            return false;
+        };
+        star = Some((after, at + 1));
+        (p, i) = (after, at + 1);
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+/// Matches `c` against the token starting `pattern`, other than `*`, and
+/// returns the length of the token.
+fn match_token(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern.first()? {
+        b'?' => Some(1),
+        b'[' => {
+            let Some(close) = pattern.iter().skip(2).position(|x| *x == b']') else {
+                return (c == b'[').then_some(1);
+            };
+            let class = &pattern[1..close + 2];
+            let (negate, class) = match class.first() {
+                Some(b'^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (matched != negate).then_some(close + 3)
+        }
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        x => (*x == c).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_cursors_round_trip_the_last_key() {
+        for key in ["a", "key:17", "ключ", ""] {
+            let cursor = encode_cursor(key.as_bytes());
+            assert!(cursor.bytes().all(|x| x.is_ascii_digit()));
+            assert_eq!(
+                scan_cursor(cursor.as_bytes()).unwrap().as_deref(),
+                Some(key)
+            );
+        }
+        assert_eq!(scan_cursor(b"0").unwrap(), None);
+        for cursor in [&b"12"[..], b"1256", b"1+12", b"x", b"", b"1255"] {
+            assert!(scan_cursor(cursor).is_err());
+        }
+    }
+
+    fn decode_all(decoder: &mut RespDecoder) -> Result<Vec<Vec<Vec<u8>>>, String> {
+        let mut commands = Vec::new();
+        while let Some(x) = decoder.decode().map_err(|err| err.to_string())? {
+            commands.push(x);
+        }
+        Ok(commands)
+    }
+
+    fn decode(input: &[u8]) -> Result<Vec<Vec<Vec<u8>>>, String> {
+        let mut decoder = RespDecoder::new();
+        decoder.read_from(input).unwrap();
+        decode_all(&mut decoder)
+    }
+
+    #[test]
+    fn decodes_commands_fed_byte_by_byte() {
+        let input = b"*2\r\n$3\r\nGET\r\n$4\r\nk\r\nx\r\n\r\n*-1\r\nPING  x\r\n*1\r\n$0\r\n\r\n";
+        let mut decoder = RespDecoder::new();
+        let mut commands = Vec::new();
+        for x in input {
+            decoder.read_from(&[*x][..]).unwrap();
+            commands.extend(decode_all(&mut decoder).unwrap());
+        }
+        let expected: Vec<Vec<Vec<u8>>> = vec![
+            vec![b"GET".to_vec(), b"k\r\nx".to_vec()],
+            vec![b"PING".to_vec(), b"x".to_vec()],
+            vec![Vec::new()],
+        ];
+        assert_eq!(commands, expected);
+        assert_eq!(decode(input).unwrap(), expected);
+        assert!(decoder.buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_announced_arguments_without_making_room_for_them() {
+        let mut decoder = RespDecoder::new();
+        decoder
+            .read_from(&b"*1048576\r\n$3\r\nGET\r\n"[..])
+            .unwrap();
+        assert_eq!(decoder.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_malformed_commands() {
+        for (input, error) in [
+            (
+                &b"*2\r\n:1\r\n"[..],
+                "protocol error: expected '$', got ':'",
+            ),
+            (b"*x\r\n", "protocol error: invalid length"),
+            (b"*1\r\n$1x\r\n", "protocol error: invalid length"),
+            (
+                b"*1\r\n$3\r\nabcd\r\n",
+                "protocol error: bulk string not terminated",
+            ),
+            (b"*1\r\n$-1\r\n", "request too large"),
+        ] {
+            assert_eq!(decode(input).unwrap_err(), error, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn refuses_commands_over_the_limits() {
+        let too_many = format!("*{}\r\n", MAX_ARGS + 1);
+        let too_long = format!("*1\r\n${}\r\n", MAX_FRAME_SIZE + 1);
+        for input in [too_many.as_bytes(), too_long.as_bytes()] {
+            assert_eq!(decode(input).unwrap_err(), "request too large");
+        }
+
+        // Lines without their CRLF are only buffered up to a limit.
+        let long_length = [&b"*"[..], &[b'1'; 33]].concat();
+        let error = "protocol error: length line too long";
+        assert_eq!(decode(&long_length).unwrap_err(), error);
+        assert_eq!(
+            decode(&long_length[..32]).unwrap(),
+            Vec::<Vec<Vec<u8>>>::new()
+        );
+
+        let mut decoder = RespDecoder::new();
+        decoder.buf = vec![b'x'; MAX_FRAME_SIZE];
+        assert_eq!(decoder.decode().unwrap(), None);
+        decoder.buf.push(b'x');
+        assert_eq!(
+            decoder.decode().unwrap_err().to_string(),
+            "request too large"
+        );
+    }
+
+    #[test]
+    fn matches_globs() {
+        for (pattern, matching, other) in [
+            ("*", &["", "key"][..], &[][..]),
+            ("user:*", &["user:", "user:1"], &["user", "users:1"]),
+            ("h?llo", &["hello", "hallo"], &["hllo", "heello"]),
+            ("h*llo", &["hllo", "heeello"], &["hell"]),
+            ("*a*b*", &["ab", "xaxbx", "aab"], &["ba", "b"]),
+            ("h[ae]llo", &["hello", "hallo"], &["hillo", "hllo"]),
+            ("h[^e]llo", &["hallo"], &["hello"]),
+            ("h[a-b]llo", &["hallo", "hbllo"], &["hcllo"]),
+            (r"h\*llo", &["h*llo"], &["hello"]),
+            (r"h\?", &["h?"], &["hx"]),
+            // An unclosed class is a literal `[`.
+            ("[abc", &["[abc"], &["a"]),
+            ("", &[""], &["a"]),
+        ] {
+            for x in matching {
+                assert!(
+                    glob_match(pattern.as_bytes(), x.as_bytes()),
+                    "{} {}",
+                    pattern,
+                    x
+                );
+            }
+            for x in other {
+                assert!(
+                    !glob_match(pattern.as_bytes(), x.as_bytes()),
+                    "{} {}",
+                    pattern,
+                    x
+                );
+            }
+        }
+    }
+}
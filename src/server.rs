@@ -2,128 +2,730 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::io;
 use std::io::Write;
-use std::ops::Deref;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::Scope;
+use std::time::{Duration, SystemTime};
+use std::{process, thread};
 
-use mio::{Events, Interest, Poll, Token};
-use mio::event::Event;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use mio::net::{TcpListener, TcpStream};
 
 use crate::cache::{Cache, CacheServer, middlewares};
 use crate::cache::middlewares::{Middleware, MiddlewareNext};
+use crate::cache::cluster::Cluster;
+use crate::cache::crdt::Crdts;
+use crate::cache::gossip::{Gossip, GossipOptions};
+use crate::cache::multi::{Clock, Hlc};
+use crate::cache::raft::{Raft, RaftOptions};
+use crate::cache::replication::{ReplicaOptions, Replicator};
+use crate::cache::wal::WalOptions;
 use crate::cli::Args;
-use crate::proto;
-use crate::proto::{Frame, RequestCommand};
+use crate::http::HttpDecoder;
+use crate::resp::{RespSession, RespValue};
+use crate::{http, proto};
+use crate::proto::{
+    ErrorCode, Frame, FrameDecoder, FrameHeader, RequestCommand, Response, MIN_VERSION, VERSION,
+};
 
-const SERVER: Token = Token(0);
+/// Wire protocol of a listener and of the connections it accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Native,
+    Resp,
+    Http,
+}
+
+/// Outcome of handling buffered input on a connection.
+enum Progress {
+    /// A request was answered; more may be buffered.
+    Handled,
+    /// No complete request is buffered.
+    NeedMore,
+    /// The reply has been queued and the connection should close after it.
+    Close,
+}
+
+/// Per-connection decoding state for its [`Protocol`].
+enum Codec {
+    Native(FrameDecoder),
+    Resp(RespSession),
+    Http(HttpDecoder),
+}
+
+impl Codec {
+    fn new(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Native => Codec::Native(FrameDecoder::new()),
+            Protocol::Resp => Codec::Resp(RespSession::new()),
+            Protocol::Http => Codec::Http(HttpDecoder::new()),
+        }
+    }
+
+    fn read_from(&mut self, stream: &TcpStream) -> io::Result<usize> {
+        match self {
+            Codec::Native(decoder) => decoder.read_from(stream),
+            Codec::Resp(session) => session.decoder.read_from(stream),
+            Codec::Http(decoder) => decoder.read_from(stream),
+        }
+    }
+
+    /// Handles the next buffered request and appends its reply to `out`.
+    fn next(&mut self, cache: &dyn Fn(&RequestCommand) -> Response, out: &mut Vec<u8>) -> Progress {
+        match self {
+            Codec::Native(decoder) => {
+                let body = match decoder.decode_raw() {
+                    Ok(Some(x)) => x,
+                    Ok(None) => return Progress::NeedMore,
+                    Err(err) => {
+                        println!("dropping connection: {}", err);
+                        return Progress::Close;
+                    }
+                };
+                let request = match decode_request(&body) {
+                    Ok(x) => x,
+                    Err(res) => {
+                        // The frame was consumed whole, so the stream is still usable.
+                        out.extend(Vec::<u8>::from(res));
+                        return Progress::Handled;
+                    }
+                };
+                let res = match request.clone().into() {
+                    RequestCommand::Hello(min, max, features) => {
+                        proto::negotiate(min, max, &features)
+                    }
+                    x => cache(&x),
+                };
+                out.extend(Vec::<u8>::from(
+                    request.to_response(RequestCommand::Recv(res)),
+                ));
+                Progress::Handled
+            }
+            Codec::Resp(session) => {
+                let args = match session.decoder.decode() {
+                    Ok(Some(x)) => x,
+                    Ok(None) => return Progress::NeedMore,
+                    Err(err) => {
+                        RespValue::Error(format!("ERR {}", err)).encode(false, out);
+                        return Progress::Close;
+                    }
+                };
+                session.execute(&args, cache, out);
+                Progress::Handled
+            }
+            Codec::Http(decoder) => {
+                let request = match decoder.decode() {
+                    Ok(Some(x)) => x,
+                    Ok(None) => return Progress::NeedMore,
+                    Err(err) => {
+                        http::write_error(err.status, "ERR", &err.message, false, out);
+                        return Progress::Close;
+                    }
+                };
+                http::execute(&request, cache, out);
+                match request.keep_alive {
+                    true => Progress::Handled,
+                    false => Progress::Close,
+                }
+            }
+        }
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Taken while a [`Job`] answers the connection's requests.
+    codec: Option<Codec>,
+    /// The last job stopped at its reply budget with requests left buffered.
+    buffered: bool,
+    /// Encoded responses not yet accepted by the socket; `written` marks how
+    /// far into the buffer the kernel has taken them.
+    outbound: Vec<u8>,
+    written: usize,
+    /// Once this many bytes are pending the connection stops reading requests.
+    high_water_mark: usize,
+    interest: Interest,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, codec: Codec, high_water_mark: usize) -> Self {
+        Connection {
+            stream,
+            codec: Some(codec),
+            buffered: false,
+            outbound: Vec::new(),
+            written: 0,
+            high_water_mark,
+            interest: Interest::READABLE,
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.outbound.len() - self.written
+    }
+
+    fn is_congested(&self) -> bool {
+        self.pending() > self.high_water_mark
+    }
+
+    /// Writes as much of the outbound queue as the socket accepts.
+    fn flush(&mut self) -> io::Result<()> {
+        while self.pending() > 0 {
+            match (&self.stream).write(&self.outbound[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(ref err) if would_block(err) => break,
+                Err(ref err) if interrupted(err) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.pending() == 0 {
+            self.outbound.clear();
+            self.written = 0;
+        } else if self.written > self.outbound.len() / 2 {
+            self.outbound.drain(..self.written);
+            self.written = 0;
+        }
+        Ok(())
+    }
+
+    /// Flushes, then reads and hands the buffered requests to a [`Job`] unless
+    /// one already holds the codec. Returns whether the connection is done.
+    fn advance(
+        &mut self,
+        token: Token,
+        dispatch: &dyn Fn(Token, Codec, usize),
+    ) -> io::Result<bool> {
+        self.flush()?;
+        if self.is_congested() {
+            return Ok(false);
+        }
+        let Some(codec) = self.codec.as_mut() else {
+            return Ok(false);
+        };
+
+        // Requests left in the decoder while congested go out as soon as the
+        // outbound queue drains, whichever readiness woke us.
+        if !self.buffered {
+            loop {
+                match codec.read_from(&self.stream) {
+                    Ok(0) => {
+                        println!("decoding resulted in disconnect");
+                        return Ok(true);
+                    }
+                    Ok(_) => break,
+                    Err(ref err) if would_block(err) => return Ok(false),
+                    Err(ref err) if interrupted(err) => continue,
+                    // Anything else ends this connection, not the worker.
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let budget = self.high_water_mark - self.pending();
+        dispatch(token, self.codec.take().unwrap(), budget);
+        Ok(false)
+    }
+
+    /// Takes back the codec and replies of a finished job. Returns whether
+    /// the connection should close.
+    fn resume(&mut self, done: Done) -> bool {
+        self.codec = Some(done.codec);
+        self.outbound.extend(done.out);
+        self.buffered = matches!(done.progress, Progress::Handled);
+        if matches!(done.progress, Progress::Close) {
+            // Best effort to deliver the last reply before closing.
+            let _ = self.flush();
+            return true;
+        }
+        false
+    }
+
+    /// Re-registers the socket if its interest changed: WRITABLE only while
+    /// bytes are pending, and no READABLE while congested.
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = if self.is_congested() {
+            Interest::WRITABLE
+        } else if self.pending() > 0 {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+
+        if interest != self.interest {
+            registry.reregister(&mut self.stream, token, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+/// Wakes a worker when the acceptor hands it new connections or a job it
+/// dispatched finishes.
+const WAKER: Token = Token(0);
+
+/// How long a handler thread waits for a job before it stops.
+const HANDLER_IDLE: Duration = Duration::from_secs(10);
+
+/// Shared request path of every worker: the middleware chain, then the cache.
+type Handler<'a> = dyn Fn(&RequestCommand) -> Response + Sync + 'a;
+
+/// An event loop thread owning the connections handed to it.
+struct Worker {
+    tx: Sender<(TcpStream, Protocol)>,
+    waker: Arc<Waker>,
+}
+
+/// The buffered requests of a connection, answered off its event loop, as a
+/// write may wait for replicas, the raft log or the disk before it is.
+struct Job {
+    token: Token,
+    codec: Codec,
+    /// Reply bytes the job may queue before it leaves the rest for later.
+    budget: usize,
+    done: Sender<Done>,
+    waker: Arc<Waker>,
+}
+
+/// A finished [`Job`], handed back to the worker owning its connection.
+struct Done {
+    token: Token,
+    codec: Codec,
+    out: Vec<u8>,
+    /// [`Progress::Handled`] if the job stopped at its budget.
+    progress: Progress,
+}
+
+impl Job {
+    fn run(mut self, handler: &Handler) {
+        let mut out = Vec::new();
+        let progress = loop {
+            if out.len() > self.budget {
+                break Progress::Handled;
+            }
+            match self.codec.next(handler, &mut out) {
+                Progress::Handled => {}
+                progress => break progress,
+            }
+        };
+
+        let done = Done {
+            token: self.token,
+            codec: self.codec,
+            out,
+            progress,
+        };
+        // The worker only goes away by failing, which ends the process.
+        if self.done.send(done).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+/// Threads running [`Job`]s. One starts whenever a job finds every thread
+/// busy, up to `max`, and stops after [`HANDLER_IDLE`] without work.
+struct Handlers {
+    tx: Sender<Job>,
+    rx: Mutex<Receiver<Job>>,
+    /// Threads running, and jobs submitted but not yet finished.
+    load: Mutex<(usize, usize)>,
+    max: usize,
+}
+
+impl Handlers {
+    fn new(max: usize) -> Self {
+        let (tx, rx) = channel();
+        Handlers {
+            tx,
+            rx: Mutex::new(rx),
+            load: Mutex::new((0, 0)),
+            max: max.max(1),
+        }
+    }
+
+    fn submit<'scope>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        handler: &'scope Handler<'scope>,
+        job: Job,
+    ) {
+        let spawn = {
+            let (threads, jobs) = &mut *self.load.lock().unwrap();
+            *jobs += 1;
+            let spawn = *jobs > *threads && *threads < self.max;
+            if spawn {
+                *threads += 1;
+            }
+            spawn
+        };
+        if spawn {
+            scope.spawn(move || self.run(handler));
+        }
+        // Past `max` the job waits for the first thread to free up. The
+        // receiver lives as long as `self`, so this cannot fail.
+        let _ = self.tx.send(job);
+    }
+
+    fn run(&self, handler: &Handler) {
+        loop {
+            let job = self.rx.lock().unwrap().recv_timeout(HANDLER_IDLE);
+            if let Ok(job) = job {
+                job.run(handler);
+                self.load.lock().unwrap().1 -= 1;
+                continue;
+            }
+            let (threads, jobs) = &mut *self.load.lock().unwrap();
+            // Stay if a job was submitted for this thread as it timed out.
+            if *jobs < *threads {
+                *threads -= 1;
+                return;
+            }
+        }
+    }
+}
 
 pub fn start(args: &Args) -> Result<(), Box<dyn Error>> {
     let log = middlewares::Logger::new(args.verbose);
-    let wal = middlewares::WriteLog::new(&args.wal.clone());
-    let replicator = middlewares::Replicator::new(args.clone().replica);
+    let wal = middlewares::WriteLog::open(
+        &args.wal.clone(),
+        WalOptions {
+            sync: args.wal_sync,
+            segment_size: args.wal_segment_size,
+            retain_segments: args.wal_retain_segments,
+            archive_dir: args.wal_archive_dir.clone(),
+        },
+    )?;
+    let cache = &Cache::with_shards(args.shards, args.max_memory, args.eviction);
+
+    wal.preload(cache, args.wal_repair)?;
+    let hlc = Arc::new(Hlc::new(&args.addr));
+    let clock = match args.multi_primary_peers.is_empty() {
+        true => None,
+        false => Some(Arc::new(Clock::open(
+            &hlc,
+            &args.multi_primary_peers,
+            cache,
+        ))),
+    };
+    // The other primaries are replicas of this one, as it is of them.
+    let replicator = Replicator::new(
+        args.replica
+            .iter()
+            .chain(&args.multi_primary_peers)
+            .cloned()
+            .collect(),
+        args.replica_of.clone(),
+        cache,
+        &wal,
+        ReplicaOptions {
+            backlog: args.replica_backlog,
+            concern: args.write_concern,
+            timeout: Duration::from_millis(args.write_concern_timeout),
+            announce: args.addr.clone(),
+            clock: clock.clone(),
+            failover: args.failover,
+        },
+    );
+    if let Some(clock) = &clock {
+        clock.start_collector(cache);
+    }
+
+    let raft = match args.raft_peers.is_empty() {
+        true => None,
+        false => Some(Raft::open(
+            &args.wal,
+            cache,
+            RaftOptions {
+                peers: args.raft_peers.clone(),
+                election_timeout: Duration::from_millis(args.raft_election_timeout),
+                announce: args.addr.clone(),
+                compact_after: args.raft_compact_after,
+            },
+        )?),
+    };
 
-    let mw: Vec<Box<dyn Middleware>> = vec![Box::new(&log), Box::new(&wal), Box::new(&replicator)];
+    let cluster = match args.cluster_nodes.is_empty() {
+        true => None,
+        false => Some(Cluster::open(
+            &args.wal,
+            &args.cluster_nodes,
+            &args.addr,
+            cache,
+            &wal,
+        )?),
+    };
+
+    // Every node this one replicates or shares slots with is a member too.
+    let mut seeds = args.gossip_seeds.clone();
+    seeds.extend(
+        args.replica
+            .iter()
+            .chain(&args.replica_of)
+            .chain(&args.multi_primary_peers)
+            .chain(&args.cluster_nodes)
+            .cloned(),
+    );
+    seeds.sort();
+    seeds.dedup();
+    let gossip = match seeds.is_empty() {
+        true => None,
+        false => Some(Gossip::start(GossipOptions {
+            seeds,
+            interval: Duration::from_millis(args.gossip_interval),
+            suspect_timeout: Duration::from_millis(args.gossip_suspect_timeout),
+            announce: args.addr.clone(),
+        })),
+    };
+
+    let crdts = Crdts::new(&hlc, cache, raft.clone());
+
+    let mut mw: Vec<Box<dyn Middleware + Sync>> = vec![Box::new(&log)];
+    if let Some(gossip) = &gossip {
+        mw.push(Box::new(gossip));
+    }
+    if let Some(cluster) = &cluster {
+        mw.push(Box::new(cluster));
+    }
+    mw.push(Box::new(&crdts));
+    if let Some(raft) = &raft {
+        mw.push(Box::new(raft));
+    }
+    mw.push(Box::new(&replicator));
+    mw.push(Box::new(&wal));
 
-    let cache = &Cache::new();
+    cache.start_expiry_sweep(Duration::from_millis(args.expiry_interval));
+    wal.start_maintenance(
+        cache,
+        Some(Duration::from_secs(args.snapshot_interval)).filter(|x| !x.is_zero()),
+    );
 
-    wal.preload(&cache);
+    let handler = |x: &RequestCommand| {
+        MiddlewareNext::new(
+            &mut mw.iter().map(|mw| mw.as_ref() as &dyn Middleware),
+            Box::new(|r| cache.on_request(r)),
+        )
+        .on_request(x)
+    };
 
     let mut poll = Poll::new()?;
     let mut events = Events::with_capacity(512);
 
-    let addr = args.addr.parse()?;
-    let mut server = TcpListener::bind(addr)?;
-    poll.registry()
-        .register(&mut server, SERVER, Interest::READABLE)?;
+    let mut listeners = vec![(TcpListener::bind(args.addr.parse()?)?, Protocol::Native)];
+    if let Some(addr) = &args.resp_addr {
+        listeners.push((TcpListener::bind(addr.parse()?)?, Protocol::Resp));
+        println!("Listening for RESP clients on {}", addr);
+    }
+    if let Some(addr) = &args.http_addr {
+        listeners.push((TcpListener::bind(addr.parse()?)?, Protocol::Http));
+        println!("Listening for HTTP clients on {}", addr);
+    }
+    for (i, (listener, _)) in listeners.iter_mut().enumerate() {
+        poll.registry()
+            .register(listener, Token(i), Interest::READABLE)?;
+    }
 
-    let mut connections = HashMap::new();
-    let mut client_token: Token = Token(SERVER.0 + 1);
+    let handlers = Handlers::new(args.max_handlers);
 
-    loop {
-        let t = SystemTime::now();
+    thread::scope(|s| {
+        // Membership drives replication and slot ownership, and learns the
+        // role of this node from replication in turn.
+        if let Some(gossip) = &gossip {
+            let changes = gossip.subscribe();
+            let (replicator, cluster) = (&replicator, &cluster);
+            s.spawn(move || loop {
+                gossip.set_role(replicator.primary(), replicator.succeeds());
+                let members = gossip.members();
+                replicator.on_members(&members);
+                if let Some(cluster) = cluster {
+                    cluster.on_members(&members);
+                }
+                let _ = changes.recv_timeout(Duration::from_secs(1));
+                changes.try_iter().count();
+            });
+        }
 
-        if let Err(err) = poll.poll(&mut events, None) {
-            if interrupted(&err) {
-                continue;
-            }
-            return Err(err.into());
+        let mut workers = Vec::new();
+        for i in 0..args.workers.max(1) {
+            let poll = Poll::new()?;
+            let (tx, rx) = channel();
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+            workers.push(Worker {
+                tx,
+                waker: waker.clone(),
+            });
+            let (handler, handlers) = (&handler, &handlers);
+            let submit = move |job| handlers.submit(s, handler, job);
+            s.spawn(move || {
+                if let Err(err) = run_worker(poll, waker, rx, &submit, args.high_water_mark) {
+                    eprintln!("Worker {} failed: {}", i, err);
+                    process::exit(1);
+                }
+            });
         }
+        println!("Serving with {} workers", workers.len());
 
-        for event in events.iter() {
-            match event.token() {
-                SERVER => loop {
-                    let (mut connection, address) = match server.accept() {
+        // Connections are dealt out round robin; each stays on its worker.
+        let mut next_worker = (0..workers.len()).cycle();
+        loop {
+            if let Err(err) = poll.poll(&mut events, None) {
+                if interrupted(&err) {
+                    continue;
+                }
+                return Err(err.into());
+            }
+
+            for event in events.iter() {
+                let (listener, protocol) = &listeners[event.token().0];
+                loop {
+                    let (connection, address) = match listener.accept() {
                         Ok((connection, address)) => (connection, address),
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                             break;
                         }
+                        // The peer gave up before it was accepted.
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                            continue;
+                        }
                         Err(e) => {
                             return Err(e.into());
                         }
                     };
-                    connection.set_nodelay(true)?;
+                    if let Err(err) = connection.set_nodelay(true) {
+                        println!("Dropping connection from {}: {}", address, err);
+                        continue;
+                    }
 
                     println!("Accepted connection from: {}", address);
 
-                    let token = next(&mut client_token);
-                    poll.registry()
-                        .register(&mut connection, token, Interest::READABLE)?;
+                    let worker = &workers[next_worker.next().unwrap()];
+                    worker.tx.send((connection, *protocol))?;
+                    worker.waker.wake()?;
+                }
+            }
+        }
+    })
+}
 
-                    connections.insert(token, connection);
-                },
-                token => {
-                    let done = if let Some(connection) = connections.get_mut(&token) {
-                        handle_connection_event(connection, event, |x| {
-                            MiddlewareNext::new(
-                                &mut mw.iter().map(|mw| mw.as_ref()),
-                                Box::new(|r| cache.on_request(r)),
-                            )
-                            .on_request(x)
-                        })?
-                    } else {
-                        // Sporadic events happen, we can safely ignore them.
-                        false
-                    };
-                    if done {
-                        if let Some(mut connection) = connections.remove(&token) {
-                            poll.registry().deregister(&mut connection)?;
+fn run_worker(
+    mut poll: Poll,
+    waker: Arc<Waker>,
+    rx: Receiver<(TcpStream, Protocol)>,
+    submit: &dyn Fn(Job),
+    high_water_mark: usize,
+) -> io::Result<()> {
+    let mut events = Events::with_capacity(512);
+    let mut connections = HashMap::new();
+    let mut client_token: Token = Token(WAKER.0 + 1);
+    let (done_tx, done_rx) = channel();
+    let dispatch = |token, codec, budget| {
+        submit(Job {
+            token,
+            codec,
+            budget,
+            done: done_tx.clone(),
+            waker: waker.clone(),
+        })
+    };
+
+    loop {
+        let t = SystemTime::now();
+
+        if let Err(err) = poll.poll(&mut events, None) {
+            if interrupted(&err) {
+                continue;
+            }
+            return Err(err);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {
+                    for (mut connection, protocol) in rx.try_iter() {
+                        let token = next(&mut client_token);
+                        poll.registry()
+                            .register(&mut connection, token, Interest::READABLE)?;
+
+                        connections.insert(
+                            token,
+                            Connection::new(connection, Codec::new(protocol), high_water_mark),
+                        );
+                    }
+                    for done in done_rx.try_iter() {
+                        let token = done.token;
+                        // The connection may have failed while the job ran.
+                        if let Some(connection) = connections.get_mut(&token) {
+                            let result = match connection.resume(done) {
+                                true => Ok(true),
+                                false => connection.advance(token, &dispatch),
+                            };
+                            settle(poll.registry(), &mut connections, token, result)?;
                         }
                     }
                 }
+                token => {
+                    if let Some(connection) = connections.get_mut(&token) {
+                        let result = connection.advance(token, &dispatch);
+                        settle(poll.registry(), &mut connections, token, result)?;
+                    }
+                    // Sporadic events happen, we can safely ignore them.
+                }
             }
         }
         println!("Event loop {:?}", t.elapsed());
     }
 }
 
-fn handle_connection_event<T: Fn(&RequestCommand) -> Vec<u8>>(
-    connection: &mut TcpStream,
-    event: &Event,
-    cache: T,
-) -> io::Result<bool> {
-    if event.is_readable() {
-        let mut c = connection.deref();
-
-        loop {
-            let request: Frame = match proto::deserialize(c) {
-                Ok(Some(x)) => x,
-                Err(ref err) if would_block(err) => break,
-                Err(ref err) if interrupted(err) => continue,
-                // Other errors we'll consider fatal.
-                Err(err) => return Err(err),
-                Ok(None) => {
-                    println!("decoding resulted in disconnect");
-                    return Ok(true);
-                }
-            };
-            let res = cache(&request.clone().into());
-            let buf: Vec<u8> = request.to_response(RequestCommand::Recv(res)).into();
-            c.write(&*buf)?;
-            // proto::encode(c, &)?;
+/// Updates the interest of a connection after it advanced, or drops it once
+/// it is done.
+fn settle(
+    registry: &Registry,
+    connections: &mut HashMap<Token, Connection>,
+    token: Token,
+    result: io::Result<bool>,
+) -> io::Result<()> {
+    // A peer resetting its connection only ends that one.
+    let done = result.unwrap_or_else(|err| {
+        println!("Closing connection {}: {}", token.0, err);
+        true
+    });
+    match done {
+        true => {
+            if let Some(mut connection) = connections.remove(&token) {
+                registry.deregister(&mut connection.stream)?;
+            }
+        }
+        false => {
+            if let Some(connection) = connections.get_mut(&token) {
+                connection.update_interest(registry, token)?;
+            }
         }
     }
+    Ok(())
+}
 
-    Ok(false)
+/// Decodes a request body, or builds the error frame to answer it with.
+///
+/// The header is read on its own first, so frames from an unsupported
+/// version are told so under their own id even if their command is unreadable.
+fn decode_request(body: &[u8]) -> Result<Frame, Frame> {
+    let error = |id, code, message: String| {
+        Frame::new(id, RequestCommand::Recv(Response::error(code, message)))
+    };
+
+    let header: FrameHeader =
+        bincode::deserialize(body).map_err(|err| error(0, ErrorCode::Protocol, err.to_string()))?;
+    if !(MIN_VERSION..=VERSION).contains(&header.version) {
+        return Err(error(
+            header.id,
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "protocol version {} is not supported, server speaks {}..={}",
+                header.version, MIN_VERSION, VERSION
+            ),
+        ));
+    }
+    bincode::deserialize(body).map_err(|err| error(header.id, ErrorCode::Protocol, err.to_string()))
 }
 
 fn next(current: &mut Token) -> Token {
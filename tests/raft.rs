@@ -0,0 +1,316 @@
+//! Consensus clusters of three servers on localhost, spoken to over RESP.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a cluster gets to settle before a test gives up.
+const SETTLE: Duration = Duration::from_secs(15);
+
+/// A server of a three node cluster, killed when dropped.
+struct Node {
+    port: u16,
+    resp_port: u16,
+    peers: Vec<String>,
+    dir: PathBuf,
+    compact_after: u64,
+    child: Option<Child>,
+}
+
+impl Node {
+    fn start(&mut self) {
+        let child = Command::new(env!("CARGO_BIN_EXE_plaintcp"))
+            .arg("--server")
+            .args(["--addr", &format!("127.0.0.1:{}", self.port)])
+            .args(["--resp-addr", &format!("127.0.0.1:{}", self.resp_port)])
+            .args(["--wal", self.dir.to_str().unwrap()])
+            .args(["--snapshot-interval", "0"])
+            .args(["--raft-election-timeout", "150"])
+            .args(["--raft-compact-after", &self.compact_after.to_string()])
+            .arg("--raft-peers")
+            .args(&self.peers)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("server starts");
+        self.child = Some(child);
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// Freezes or thaws the process, as if the network to it went down or
+    /// came back.
+    fn signal(&self, signal: &str) {
+        let child = self.child.as_ref().expect("node is running");
+        let status = Command::new("kill")
+            .args([signal, &child.id().to_string()])
+            .status()
+            .expect("kill runs");
+        assert!(status.success());
+    }
+
+    fn is_running(&self) -> bool {
+        self.child.is_some()
+    }
+
+    /// Sends one command, `None` if the node cannot be reached.
+    fn call(&self, args: &[&str]) -> Option<String> {
+        let stream = TcpStream::connect(("127.0.0.1", self.resp_port)).ok()?;
+        stream.set_read_timeout(Some(SETTLE)).ok()?;
+        let mut buf = format!("*{}\r\n", args.len());
+        for x in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", x.len(), x));
+        }
+        (&stream).write_all(buf.as_bytes()).ok()?;
+        read_reply(&mut BufReader::new(stream))
+    }
+
+    fn info(&self) -> HashMap<String, String> {
+        self.call(&["INFO"])
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|x| x.split_once(':'))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    fn role(&self) -> Option<String> {
+        self.info().remove("raft_role")
+    }
+
+    fn stat(&self, name: &str) -> u64 {
+        self.info()
+            .get(name)
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Reads a simple string, error, integer or bulk string reply.
+fn read_reply(r: &mut impl BufRead) -> Option<String> {
+    let mut line = String::new();
+    r.read_line(&mut line).ok()?;
+    let line = line.trim_end();
+    match line.split_at(1) {
+        ("$", "-1") => Some(String::new()),
+        ("$", len) => {
+            let mut buf = vec![0u8; len.parse::<usize>().ok()? + 2];
+            r.read_exact(&mut buf).ok()?;
+            buf.truncate(buf.len() - 2);
+            String::from_utf8(buf).ok()
+        }
+        _ => Some(line.to_owned()),
+    }
+}
+
+/// Three nodes on ports from `base` on, all started.
+fn cluster(name: &str, base: u16, compact_after: u64) -> Vec<Node> {
+    let addrs: Vec<String> = (0..3).map(|i| format!("127.0.0.1:{}", base + i)).collect();
+    let mut nodes: Vec<Node> = (0..3)
+        .map(|i| Node {
+            port: base + i,
+            resp_port: base + 10 + i,
+            peers: addrs
+                .iter()
+                .filter(|x| **x != addrs[i as usize])
+                .cloned()
+                .collect(),
+            dir: std::env::temp_dir().join(format!(
+                "plaintcp-raft-{}-{}-{}",
+                name,
+                std::process::id(),
+                i
+            )),
+            compact_after,
+            child: None,
+        })
+        .collect();
+    for x in nodes.iter_mut() {
+        let _ = std::fs::remove_dir_all(&x.dir);
+        x.start();
+    }
+    nodes
+}
+
+/// Polls until `f` holds, panicking with `what` once the cluster had time
+/// enough to settle.
+fn wait_for<T>(what: &str, mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + SETTLE;
+    loop {
+        if let Some(x) = f() {
+            return x;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Index of the only leader among the running nodes.
+fn leader(nodes: &[Node]) -> usize {
+    wait_for("a leader", || {
+        let leaders: Vec<usize> = (0..nodes.len())
+            .filter(|i| nodes[*i].is_running())
+            .filter(|i| nodes[*i].role().as_deref() == Some("leader"))
+            .collect();
+        match leaders[..] {
+            [x] => Some(x),
+            _ => None,
+        }
+    })
+}
+
+#[test]
+fn elects_one_leader() {
+    let nodes = cluster("election", 17100, 10_000);
+    let i = leader(&nodes);
+    let term = nodes[i].stat("raft_term");
+    let addr = format!("127.0.0.1:{}", nodes[i].port);
+    wait_for("every node to follow the leader", || {
+        nodes
+            .iter()
+            .all(|x| {
+                let info = x.info();
+                info.get("raft_leader") == Some(&addr)
+                    && info.get("raft_term") == Some(&term.to_string())
+            })
+            .then_some(())
+    });
+}
+
+#[test]
+fn commits_writes_on_every_node() {
+    let nodes = cluster("commit", 17200, 10_000);
+    let i = leader(&nodes);
+    assert_eq!(nodes[i].call(&["SET", "key", "value"]).unwrap(), "+OK");
+
+    let committed = nodes[i].stat("raft_commit");
+    wait_for("every node to apply the write", || {
+        nodes
+            .iter()
+            .all(|x| x.stat("raft_applied") >= committed)
+            .then_some(())
+    });
+    let follower = &nodes[(i + 1) % 3];
+    assert!(follower
+        .call(&["GET", "key"])
+        .unwrap()
+        .starts_with("-NOTLEADER"));
+    assert_eq!(nodes[i].call(&["GET", "key"]).unwrap(), "value");
+}
+
+#[test]
+fn fails_over_to_a_new_leader() {
+    let mut nodes = cluster("failover", 17300, 10_000);
+    let old = leader(&nodes);
+    assert_eq!(nodes[old].call(&["SET", "key", "value"]).unwrap(), "+OK");
+    let term = nodes[old].stat("raft_term");
+
+    nodes[old].stop();
+    let new = leader(&nodes);
+    assert_ne!(new, old);
+    assert!(nodes[new].stat("raft_term") > term);
+    assert_eq!(nodes[new].call(&["GET", "key"]).unwrap(), "value");
+    assert_eq!(nodes[new].call(&["SET", "other", "value"]).unwrap(), "+OK");
+
+    // The old leader comes back as a follower and learns the new write.
+    nodes[old].start();
+    let committed = nodes[new].stat("raft_commit");
+    wait_for("the old leader to catch up", || {
+        (nodes[old].role().as_deref() == Some("follower")
+            && nodes[old].stat("raft_applied") >= committed)
+            .then_some(())
+    });
+}
+
+#[test]
+fn catches_up_from_a_snapshot() {
+    let mut nodes = cluster("snapshot", 17400, 10);
+    let i = leader(&nodes);
+    let lagging = (i + 1) % 3;
+    nodes[lagging].stop();
+    for n in 0..50 {
+        let key = format!("key{}", n);
+        assert_eq!(nodes[i].call(&["SET", &key, "value"]).unwrap(), "+OK");
+    }
+    assert!(nodes[i].stat("raft_snapshot_index") >= 40);
+
+    // Starting over empty, the entries it needs are compacted away.
+    let _ = std::fs::remove_dir_all(&nodes[lagging].dir);
+    nodes[lagging].start();
+    let committed = nodes[i].stat("raft_commit");
+    wait_for("the lagging node to install the snapshot", || {
+        (nodes[lagging].stat("raft_snapshot_index") >= 40
+            && nodes[lagging].stat("raft_applied") >= committed)
+            .then_some(())
+    });
+
+    // Once the leader is gone, what it compacted is still there.
+    nodes[i].stop();
+    let new = leader(&nodes);
+    for n in [0, 25, 49] {
+        let key = format!("key{}", n);
+        assert_eq!(nodes[new].call(&["GET", &key]).unwrap(), "value");
+    }
+}
+
+#[test]
+fn stops_serving_reads_without_a_majority() {
+    let nodes = cluster("partition", 17500, 10_000);
+    let i = leader(&nodes);
+    assert_eq!(nodes[i].call(&["SET", "key", "value"]).unwrap(), "+OK");
+
+    // Cut off from both followers, the leader cannot tell whether a newer one
+    // took over and wrote the key since, so it must not answer from its cache.
+    let followers = [(i + 1) % 3, (i + 2) % 3];
+    for x in followers {
+        nodes[x].signal("-STOP");
+    }
+    let res = nodes[i].call(&["GET", "key"]).unwrap();
+    for x in followers {
+        nodes[x].signal("-CONT");
+    }
+    assert!(res.starts_with("-TIMEOUT"), "answered {}", res);
+
+    // The followers may hold an election on waking, deposing the leader the
+    // read went to.
+    wait_for("reads to be served again", || {
+        let i = leader(&nodes);
+        (nodes[i].call(&["GET", "key"])? == "value").then_some(())
+    });
+}
+
+#[test]
+fn counts_on_from_every_committed_update_after_a_failover() {
+    let mut nodes = cluster("counter", 17600, 10_000);
+    let old = leader(&nodes);
+    assert_eq!(
+        nodes[old].call(&["COUNTER.INCRBY", "hits", "5"]).unwrap(),
+        ":5"
+    );
+
+    // The new leader may learn the increment was committed only after it
+    // took over, and must not build on a count without it.
+    nodes[old].stop();
+    let new = leader(&nodes);
+    assert_eq!(
+        nodes[new].call(&["COUNTER.INCRBY", "hits", "2"]).unwrap(),
+        ":7"
+    );
+    assert_eq!(nodes[new].call(&["COUNTER.GET", "hits"]).unwrap(), ":7");
+}